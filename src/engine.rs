@@ -1,3 +1,4 @@
 //! 2.5D graphics engine core components.
 
+pub mod rng;
 pub mod tile;
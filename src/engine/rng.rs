@@ -0,0 +1,48 @@
+//! Small, seedable pseudo-random number generator.
+
+use std::ops::Range;
+
+/// Deterministic pseudo-random number generator
+/// based on [SplitMix64](https://prng.di.unimi.it/splitmix64.c).
+///
+/// Not suitable for cryptography; intended for
+/// reproducible procedural content.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Returns a new generator seeded with `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Returns the next random `u64`.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a random `f32` in `[0.0, 1.0)`.
+    pub fn next_f32(&mut self) -> f32 {
+        // Use the upper 24 bits, which fit exactly in an f32's mantissa.
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Returns a random integer in `range`.
+    ///
+    /// Panics if `range` is empty.
+    pub fn range(&mut self, range: Range<usize>) -> usize {
+        assert!(!range.is_empty(), "empty random range");
+        range.start + (self.next_u64() % (range.end - range.start) as u64) as usize
+    }
+
+    /// Returns true with probability `p`.
+    pub fn chance(&mut self, p: f32) -> bool {
+        self.next_f32() < p
+    }
+}
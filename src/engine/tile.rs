@@ -10,7 +10,12 @@ use miniquad::MipmapFilterMode;
 use palette::{Srgb, WithAlpha};
 
 pub mod builder;
+pub mod colormap;
+pub mod regions;
+pub mod serialize;
 pub use builder::{ColorMapper, TileLoadResult};
+pub use colormap::{Palette, PaletteColorMapper, PaletteIssue, PaletteMatch, TileTemplate};
+pub use serialize::{TileMapData, TileMapFormatError};
 
 /// Type used for in-memory colors across the crate.
 pub type Color = palette::rgb::Rgba<Srgb, u8>;
@@ -80,6 +85,18 @@ impl TileTexture {
         Self { texture }
     }
 
+    /// Returns a texture referring to the raw OpenGL texture `id`,
+    /// so tiles can be compared without a graphics context.
+    ///
+    /// The texture can't be drawn.
+    #[cfg(test)]
+    pub fn placeholder(id: u32) -> Self {
+        let texture_id = miniquad::TextureId::from_raw_id(miniquad::RawId::OpenGl(id));
+        Self {
+            texture: Texture2D::from_miniquad_texture(texture_id),
+        }
+    }
+
     /// Draws the texture.
     pub fn draw(&self, x: f32, y: f32, size: Vec2, blend_color: Color) {
         let draw_params = DrawTextureParams {
@@ -167,8 +184,11 @@ pub struct TileMap {
 
 impl TileMap {
     /// Returns a new map with `width` x `height` tiles.
+    ///
+    /// The viewport starts out unpanned; position it before drawing,
+    /// e.g. by setting [`Self::viewport_offset`] each frame.
     pub fn new(width: usize, height: usize, color_bg: Color, color_default: Color) -> Self {
-        let mut map = Self {
+        Self {
             width,
             height,
             tiles_per_layer: width * height,
@@ -178,12 +198,17 @@ impl TileMap {
             layers: Default::default(),
             color_bg,
             color_default,
-        };
+        }
+    }
 
-        let view_size = map.calculate_view_size();
-        map.viewport_offset.y -= (view_size.y / height as f32) * 3.0;
+    /// Returns the maximum grid X-value, in units.
+    pub fn width(&self) -> usize {
+        self.width
+    }
 
-        map
+    /// Returns the maximum grid Y-value, in units.
+    pub fn height(&self) -> usize {
+        self.height
     }
 
     /// Updates all tile states.
@@ -466,6 +491,14 @@ impl TileMap {
         point + self.viewport_offset
     }
 
+    /// Converts a planar grid offset to an offset within an
+    /// axonometric projection, measured in tile widths.
+    ///
+    /// Unlike [`Self::grid_to_view`], this doesn't depend on the view.
+    pub fn grid_offset_to_view(&self, offset: Vec2) -> Vec2 {
+        glam::mat2(I_HAT, J_HAT).mul_vec2(offset)
+    }
+
     /// Converts a view point (in physical pixels) within an
     /// axonometric projection to a planar grid point.
     pub fn view_to_grid(&self, x: f32, y: f32, layer: i8) -> Vec2 {
@@ -23,13 +23,21 @@ pub trait ColorMapper {
     fn map_pixel(&mut self, x: u32, y: u32, color: Rgba<u8>) -> TileLoadResult;
 }
 
+/// Allows mappers to be borrowed during loading, so that
+/// any state they collect can be inspected afterwards.
+impl<M: ColorMapper + ?Sized> ColorMapper for &mut M {
+    fn map_pixel(&mut self, x: u32, y: u32, color: Rgba<u8>) -> TileLoadResult {
+        (**self).map_pixel(x, y, color)
+    }
+}
+
 impl TileMap {
     /// Loads tiles from a bitmap using a custom color mapper.
     ///
     /// This is the generic version that delegates color interpretation
     /// to the provided mapper, making the engine agnostic about color meanings.
     ///
-    /// Returns the first spawn point found, if any.
+    /// Returns the last spawn point found, if any.
     pub fn load_from_bitmap<M: ColorMapper>(
         &mut self,
         bitmap: &DynamicImage,
@@ -39,20 +47,41 @@ impl TileMap {
         let mut spawn_point = None;
 
         for (x, y, color) in bitmap.pixels() {
-            match color_mapper.map_pixel(x, y, color) {
-                TileLoadResult::Tile(tile) => {
-                    self.set_tile(x as usize, y as usize, layer, tile);
-                }
-                TileLoadResult::TileWithSpawn(tile, spawn_x, spawn_y) => {
-                    self.set_tile(x as usize, y as usize, layer, tile);
-                    spawn_point = Some((spawn_x, spawn_y));
-                }
-                TileLoadResult::Skip => {
-                    // Don't create a tile for this pixel
-                }
+            if let Some(spawn) = self.load_pixel(x, y, layer, color, &mut color_mapper) {
+                spawn_point = Some(spawn);
             }
         }
 
         spawn_point
     }
+
+    /// Loads the tile at `x, y` in `layer` from a single
+    /// bitmap pixel using a custom color mapper.
+    ///
+    /// Pixels which the mapper skips leave the existing tile in place.
+    ///
+    /// Returns the spawn point marked by the pixel, if any.
+    pub fn load_pixel<M: ColorMapper>(
+        &mut self,
+        x: u32,
+        y: u32,
+        layer: i8,
+        color: Rgba<u8>,
+        color_mapper: &mut M,
+    ) -> Option<(f32, f32)> {
+        match color_mapper.map_pixel(x, y, color) {
+            TileLoadResult::Tile(tile) => {
+                self.set_tile(x as usize, y as usize, layer, tile);
+                None
+            }
+            TileLoadResult::TileWithSpawn(tile, spawn_x, spawn_y) => {
+                self.set_tile(x as usize, y as usize, layer, tile);
+                Some((spawn_x, spawn_y))
+            }
+            TileLoadResult::Skip => {
+                // Don't create a tile for this pixel
+                None
+            }
+        }
+    }
 }
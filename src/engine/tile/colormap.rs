@@ -0,0 +1,573 @@
+//! Data-driven color mapping from palette tables.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use image::Rgba;
+
+use super::{Color, ColorMapper, Tile, TileLoadResult, TileTexture};
+
+/// Template for tiles created by a [`PaletteColorMapper`].
+#[derive(Clone)]
+pub struct TileTemplate {
+    /// Texture of the tile, or `None` if the tile is empty.
+    pub texture: Option<TileTexture>,
+
+    /// Vertical offset of the tile relative
+    /// to its height.
+    pub height_offset: Option<f32>,
+
+    /// Color to blend the tile's texture
+    /// with during drawing.
+    pub blend_color: Option<Color>,
+}
+
+impl TileTemplate {
+    /// Returns a new filled tile template with `texture`.
+    pub fn filled(texture: TileTexture) -> Self {
+        Self {
+            texture: Some(texture),
+            height_offset: None,
+            blend_color: None,
+        }
+    }
+
+    /// Returns a new empty tile template.
+    pub fn empty() -> Self {
+        Self {
+            texture: None,
+            height_offset: None,
+            blend_color: None,
+        }
+    }
+
+    /// Sets the template's blend color.
+    pub fn with_blend_color(mut self, blend_color: Color) -> Self {
+        self.blend_color = Some(blend_color);
+        self
+    }
+
+    /// Creates a new tile from the template.
+    pub fn instantiate(&self) -> Tile {
+        match &self.texture {
+            Some(texture) => Tile::Filled {
+                texture: texture.clone(),
+                height_offset: self.height_offset,
+                blend_color: self.blend_color,
+            },
+            None => Tile::Empty,
+        }
+    }
+}
+
+/// A single color in a [`Palette`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct PaletteEntry {
+    /// Color of pixels matching this entry.
+    pub color: Color,
+
+    /// Name of the [`TileTemplate`] to create for matching pixels.
+    pub template: String,
+
+    /// True if matching pixels mark a spawn point.
+    pub spawn: bool,
+}
+
+/// Table of colors and the tile templates they map to.
+///
+/// Palettes are typically loaded from a config file with [`Palette::parse`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Palette {
+    /// Colors in the palette.
+    pub entries: Vec<PaletteEntry>,
+
+    /// Template used for pixels matching no entry, if any.
+    pub fallback: Option<String>,
+
+    /// Maximum euclidean RGBA distance between a pixel and
+    /// an entry for the pixel to match the entry.
+    ///
+    /// A tolerance of `0` only matches exact colors.
+    pub tolerance: f32,
+}
+
+impl Palette {
+    /// Parses a palette from its text representation.
+    ///
+    /// Each non-empty line which isn't a `#` comment is one of:
+    ///
+    /// - `tolerance <distance>`: sets [`Self::tolerance`].
+    /// - `<color> <template> [spawn]`: adds an entry, where `<color>`
+    ///   is an `#rrggbb` or `#rrggbbaa` hex code. Entries flagged with
+    ///   `spawn` mark spawn points.
+    /// - `* <template>`: sets [`Self::fallback`].
+    ///
+    /// For example:
+    ///
+    /// ```text
+    /// tolerance 8
+    /// #131510 wall
+    /// #ef9275 floor spawn
+    /// * floor
+    /// ```
+    pub fn parse(source: &str) -> Result<Self, PaletteError> {
+        let mut palette = Self::default();
+
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            let mut words = line.split_whitespace();
+
+            let Some(key) = words.next() else {
+                continue;
+            };
+
+            // Skip comments, taking care not to confuse them with colors.
+            if key.starts_with('#') && parse_hex_color(key).is_none() {
+                continue;
+            }
+
+            let value = words
+                .next()
+                .ok_or(PaletteError::MissingValue { line: line_number })?;
+            let flag = words.next();
+
+            if let Some(extra) = words.next() {
+                return Err(PaletteError::UnexpectedWord {
+                    line: line_number,
+                    word: extra.to_string(),
+                });
+            }
+
+            // Only entries may be flagged.
+            let is_entry = key != "tolerance" && key != "*";
+            if let Some(word) = flag.filter(|_| !is_entry) {
+                return Err(PaletteError::UnexpectedWord {
+                    line: line_number,
+                    word: word.to_string(),
+                });
+            }
+
+            match key {
+                "tolerance" => {
+                    palette.tolerance = value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|t| *t >= 0.0)
+                        .ok_or_else(|| PaletteError::InvalidTolerance {
+                            line: line_number,
+                            value: value.to_string(),
+                        })?;
+                }
+
+                "*" => {
+                    palette.fallback = Some(value.to_string());
+                }
+
+                _ => {
+                    let color = parse_hex_color(key).ok_or_else(|| PaletteError::InvalidColor {
+                        line: line_number,
+                        value: key.to_string(),
+                    })?;
+
+                    let spawn = match flag {
+                        None => false,
+                        Some("spawn") => true,
+                        Some(word) => {
+                            return Err(PaletteError::UnexpectedWord {
+                                line: line_number,
+                                word: word.to_string(),
+                            });
+                        }
+                    };
+
+                    palette.entries.push(PaletteEntry {
+                        color,
+                        template: value.to_string(),
+                        spawn,
+                    });
+                }
+            }
+        }
+
+        Ok(palette)
+    }
+
+    /// Finds the entry matching `color`.
+    pub fn match_color(&self, color: Rgba<u8>) -> PaletteMatch {
+        // Collect all entries within tolerance, nearest first.
+        let mut candidates: Vec<(usize, f32)> = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (i, color_distance(entry.color, color)))
+            .filter(|(_, distance)| *distance <= self.tolerance)
+            .collect();
+        candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        match candidates.as_slice() {
+            [] => PaletteMatch::Unknown,
+
+            // Exact matches are never ambiguous.
+            [(nearest, distance), ..] if *distance == 0.0 => PaletteMatch::Entry(*nearest),
+
+            [(nearest, _)] => PaletteMatch::Entry(*nearest),
+
+            [(nearest, _), ..] => PaletteMatch::Ambiguous {
+                nearest: *nearest,
+                candidates: candidates.iter().map(|(i, _)| *i).collect(),
+            },
+        }
+    }
+}
+
+/// Result of matching a pixel's color against a [`Palette`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum PaletteMatch {
+    /// The color matched the entry at this index.
+    Entry(usize),
+
+    /// The color was within tolerance of several entries.
+    Ambiguous {
+        /// Index of the nearest entry.
+        nearest: usize,
+
+        /// Indices of all entries within tolerance, nearest first.
+        candidates: Vec<usize>,
+    },
+
+    /// The color wasn't within tolerance of any entry.
+    Unknown,
+}
+
+/// A pixel which didn't cleanly match a [`Palette`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum PaletteIssue {
+    /// The pixel was within tolerance of several entries,
+    /// and was mapped to the nearest one.
+    Ambiguous {
+        x: u32,
+        y: u32,
+        color: Rgba<u8>,
+        candidates: Vec<usize>,
+    },
+
+    /// The pixel wasn't within tolerance of any entry, and
+    /// was mapped to the palette's fallback (if any).
+    Unknown { x: u32, y: u32, color: Rgba<u8> },
+}
+
+impl fmt::Display for PaletteIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaletteIssue::Ambiguous {
+                x,
+                y,
+                color,
+                candidates,
+            } => write!(
+                f,
+                "pixel [{x}, {y}] color {} is ambiguous between entries {candidates:?}",
+                format_hex_color(*color),
+            ),
+            PaletteIssue::Unknown { x, y, color } => write!(
+                f,
+                "pixel [{x}, {y}] color {} matches no entry",
+                format_hex_color(*color),
+            ),
+        }
+    }
+}
+
+/// Color mapper that maps pixels to tile templates via a [`Palette`].
+///
+/// Pixels which don't cleanly match the palette are recorded
+/// in [`Self::issues`] as they're mapped.
+pub struct PaletteColorMapper {
+    palette: Palette,
+    templates: BTreeMap<String, TileTemplate>,
+
+    /// Issues found while mapping pixels.
+    pub issues: Vec<PaletteIssue>,
+}
+
+impl PaletteColorMapper {
+    /// Returns a new mapper for `palette`, creating tiles
+    /// from the named `templates`.
+    ///
+    /// Fails if the palette references a template
+    /// which isn't in `templates`.
+    pub fn new(
+        palette: Palette,
+        templates: BTreeMap<String, TileTemplate>,
+    ) -> Result<Self, PaletteError> {
+        let referenced = palette
+            .entries
+            .iter()
+            .map(|e| &e.template)
+            .chain(palette.fallback.iter());
+        for template in referenced {
+            if !templates.contains_key(template) {
+                return Err(PaletteError::UnknownTemplate(template.clone()));
+            }
+        }
+
+        Ok(Self {
+            palette,
+            templates,
+            issues: vec![],
+        })
+    }
+
+    /// Returns the mapper's palette.
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// Returns the tile load result for the palette entry at `index`.
+    fn load_entry(&self, x: u32, y: u32, index: usize) -> TileLoadResult {
+        let entry = &self.palette.entries[index];
+        let tile = self.templates[&entry.template].instantiate();
+
+        if entry.spawn {
+            TileLoadResult::TileWithSpawn(tile, x as f32, y as f32)
+        } else {
+            TileLoadResult::Tile(tile)
+        }
+    }
+}
+
+impl ColorMapper for PaletteColorMapper {
+    fn map_pixel(&mut self, x: u32, y: u32, color: Rgba<u8>) -> TileLoadResult {
+        match self.palette.match_color(color) {
+            PaletteMatch::Entry(index) => self.load_entry(x, y, index),
+
+            PaletteMatch::Ambiguous {
+                nearest,
+                candidates,
+            } => {
+                self.issues.push(PaletteIssue::Ambiguous {
+                    x,
+                    y,
+                    color,
+                    candidates,
+                });
+                self.load_entry(x, y, nearest)
+            }
+
+            PaletteMatch::Unknown => {
+                self.issues.push(PaletteIssue::Unknown { x, y, color });
+                match &self.palette.fallback {
+                    Some(template) => TileLoadResult::Tile(self.templates[template].instantiate()),
+                    None => TileLoadResult::Skip,
+                }
+            }
+        }
+    }
+}
+
+/// Errors encountered while loading a [`Palette`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum PaletteError {
+    /// A line is missing its value.
+    MissingValue { line: usize },
+
+    /// A line contains an unexpected word.
+    UnexpectedWord { line: usize, word: String },
+
+    /// A color isn't a valid `#rrggbb` or `#rrggbbaa` hex code.
+    InvalidColor { line: usize, value: String },
+
+    /// A tolerance isn't a non-negative number.
+    InvalidTolerance { line: usize, value: String },
+
+    /// The palette references a template which doesn't exist.
+    UnknownTemplate(String),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaletteError::MissingValue { line } => write!(f, "line {line}: missing value"),
+            PaletteError::UnexpectedWord { line, word } => {
+                write!(f, "line {line}: unexpected word `{word}`")
+            }
+            PaletteError::InvalidColor { line, value } => {
+                write!(f, "line {line}: invalid color `{value}`")
+            }
+            PaletteError::InvalidTolerance { line, value } => {
+                write!(f, "line {line}: invalid tolerance `{value}`")
+            }
+            PaletteError::UnknownTemplate(template) => {
+                write!(f, "unknown tile template `{template}`")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+/// Parses an `#rrggbb` or `#rrggbbaa` hex code into a color.
+///
+/// Colors without an alpha channel are opaque.
+fn parse_hex_color(value: &str) -> Option<Color> {
+    let hex = value.strip_prefix('#')?;
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+
+    match hex.len() {
+        6 => Some(Color::new(channel(0)?, channel(2)?, channel(4)?, 255)),
+        8 => Some(Color::new(
+            channel(0)?,
+            channel(2)?,
+            channel(4)?,
+            channel(6)?,
+        )),
+        _ => None,
+    }
+}
+
+/// Formats a pixel's color as an `#rrggbbaa` hex code.
+fn format_hex_color(color: Rgba<u8>) -> String {
+    let [r, g, b, a] = color.0;
+    format!("#{r:02x}{g:02x}{b:02x}{a:02x}")
+}
+
+/// Returns the euclidean distance between two colors' RGBA channels.
+fn color_distance(a: Color, b: Rgba<u8>) -> f32 {
+    let [r, g, b, alpha] = b.0;
+    let dr = a.red as f32 - r as f32;
+    let dg = a.green as f32 - g as f32;
+    let db = a.blue as f32 - b as f32;
+    let da = a.alpha as f32 - alpha as f32;
+    (dr * dr + dg * dg + db * db + da * da).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Palette with two nearby colors and a fallback.
+    const PALETTE: &str = "\
+# Walls and floors.
+tolerance 8
+#101010 wall
+#141414 floor spawn
+#ff000080 glass
+* floor
+";
+
+    #[test]
+    fn parses_every_line_kind() {
+        let palette = Palette::parse(PALETTE).unwrap();
+
+        assert_eq!(palette.tolerance, 8.0);
+        assert_eq!(palette.fallback.as_deref(), Some("floor"));
+        assert_eq!(
+            palette.entries,
+            vec![
+                PaletteEntry {
+                    color: Color::new(0x10, 0x10, 0x10, 255),
+                    template: "wall".to_string(),
+                    spawn: false,
+                },
+                PaletteEntry {
+                    color: Color::new(0x14, 0x14, 0x14, 255),
+                    template: "floor".to_string(),
+                    spawn: true,
+                },
+                PaletteEntry {
+                    color: Color::new(0xff, 0x00, 0x00, 0x80),
+                    template: "glass".to_string(),
+                    spawn: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        let error = |source| Palette::parse(source).unwrap_err();
+
+        assert_eq!(error("\n#101010"), PaletteError::MissingValue { line: 2 });
+        assert_eq!(
+            error("#101010 wall solid"),
+            PaletteError::UnexpectedWord {
+                line: 1,
+                word: "solid".to_string(),
+            }
+        );
+        assert_eq!(
+            error("* floor spawn"),
+            PaletteError::UnexpectedWord {
+                line: 1,
+                word: "spawn".to_string(),
+            }
+        );
+        assert_eq!(
+            error("tolerance 8 exact"),
+            PaletteError::UnexpectedWord {
+                line: 1,
+                word: "exact".to_string(),
+            }
+        );
+        assert_eq!(
+            error("tolerance -1"),
+            PaletteError::InvalidTolerance {
+                line: 1,
+                value: "-1".to_string(),
+            }
+        );
+        assert_eq!(
+            error("red wall"),
+            PaletteError::InvalidColor {
+                line: 1,
+                value: "red".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn matches_colors_within_tolerance() {
+        let palette = Palette::parse(PALETTE).unwrap();
+
+        // Exact matches win even when another entry is within tolerance.
+        assert_eq!(
+            palette.match_color(Rgba([0x10, 0x10, 0x10, 255])),
+            PaletteMatch::Entry(0)
+        );
+
+        // Near only one entry.
+        assert_eq!(
+            palette.match_color(Rgba([0xff, 0x02, 0x00, 0x80])),
+            PaletteMatch::Entry(2)
+        );
+
+        // Near both entries, but nearer the second.
+        assert_eq!(
+            palette.match_color(Rgba([0x13, 0x13, 0x13, 255])),
+            PaletteMatch::Ambiguous {
+                nearest: 1,
+                candidates: vec![1, 0],
+            }
+        );
+
+        // Alpha counts towards the distance.
+        assert_eq!(
+            palette.match_color(Rgba([0xff, 0x00, 0x00, 255])),
+            PaletteMatch::Unknown
+        );
+    }
+
+    #[test]
+    fn zero_tolerance_only_matches_exact_colors() {
+        let palette = Palette::parse("#101010 wall").unwrap();
+
+        assert_eq!(
+            palette.match_color(Rgba([0x10, 0x10, 0x10, 255])),
+            PaletteMatch::Entry(0)
+        );
+        assert_eq!(
+            palette.match_color(Rgba([0x10, 0x10, 0x11, 255])),
+            PaletteMatch::Unknown
+        );
+    }
+}
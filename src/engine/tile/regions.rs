@@ -0,0 +1,96 @@
+//! Connectivity queries over a [`TileMap`]'s tiles.
+
+use std::collections::{BTreeSet, VecDeque};
+
+use super::{TileMap, TileState};
+
+impl TileMap {
+    /// Returns the state of the tile at logical coordinate `x, y` in `layer`,
+    /// or `None` if the coordinate is outside the map or the layer doesn't exist.
+    pub fn tile_state(&self, x: usize, y: usize, layer: i8) -> Option<&TileState> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+
+        let layer = self.layers.get(&layer)?;
+        Some(&layer[y + self.height * x].1)
+    }
+
+    /// Returns all tiles in `layer` which are connected to the tile at `x, y`
+    /// by orthogonal steps through tiles for which `passable` is true.
+    ///
+    /// Returns an empty set if the tile at `x, y` isn't passable.
+    pub fn connected_tiles(
+        &self,
+        x: usize,
+        y: usize,
+        layer: i8,
+        passable: impl Fn(&TileState) -> bool,
+    ) -> BTreeSet<(usize, usize)> {
+        let mut connected = BTreeSet::new();
+
+        if !self.tile_state(x, y, layer).is_some_and(&passable) {
+            return connected;
+        }
+
+        // Breadth-first search from the starting tile.
+        let mut queue = VecDeque::from([(x, y)]);
+        connected.insert((x, y));
+        while let Some((x, y)) = queue.pop_front() {
+            for (nx, ny) in self.orthogonal_neighbors(x, y) {
+                if !connected.contains(&(nx, ny))
+                    && self.tile_state(nx, ny, layer).is_some_and(&passable)
+                {
+                    connected.insert((nx, ny));
+                    queue.push_back((nx, ny));
+                }
+            }
+        }
+
+        connected
+    }
+
+    /// Partitions all tiles in `layer` for which `include` is true
+    /// into orthogonally-connected regions.
+    ///
+    /// Regions are ordered by their first tile in index order.
+    pub fn connected_regions(
+        &self,
+        layer: i8,
+        include: impl Fn(&TileState) -> bool,
+    ) -> Vec<BTreeSet<(usize, usize)>> {
+        let mut regions: Vec<BTreeSet<(usize, usize)>> = vec![];
+        let mut visited = BTreeSet::new();
+
+        for x in 0..self.width {
+            for y in 0..self.height {
+                if visited.contains(&(x, y)) {
+                    continue;
+                }
+
+                let region = self.connected_tiles(x, y, layer, &include);
+                if region.is_empty() {
+                    continue;
+                }
+
+                visited.extend(region.iter().copied());
+                regions.push(region);
+            }
+        }
+
+        regions
+    }
+
+    /// Returns the orthogonal neighbors of `x, y` which are inside the map.
+    fn orthogonal_neighbors(&self, x: usize, y: usize) -> impl Iterator<Item = (usize, usize)> {
+        let (width, height) = (self.width, self.height);
+        [
+            x.checked_sub(1).map(|x| (x, y)),
+            (x + 1 < width).then_some((x + 1, y)),
+            y.checked_sub(1).map(|y| (x, y)),
+            (y + 1 < height).then_some((x, y + 1)),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
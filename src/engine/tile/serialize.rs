@@ -0,0 +1,707 @@
+//! Versioned serialization of [`TileMap`]s and their tile states.
+//!
+//! Maps are converted to and from a plain [`TileMapData`] representation,
+//! which can be encoded as either a compact binary format or as RON.
+//!
+//! Since [`TileTexture`]s live on the GPU, tiles refer to their textures
+//! by name, which are resolved against a table of named textures.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Color, Tile, TileMap, TileState, TileTexture};
+
+/// Magic bytes at the start of binary-encoded tile maps.
+const BINARY_MAGIC: &[u8; 4] = b"RTMP";
+
+/// Largest number of grid points per layer accepted when loading a map.
+pub const MAX_TILES_PER_LAYER: usize = 1 << 20;
+
+/// Serializable representation of a [`TileMap`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TileMapData {
+    /// Version of the format the data was written with.
+    pub version: u16,
+
+    /// Maximum grid X-value, in units.
+    pub width: usize,
+
+    /// Maximum grid Y-value, in units.
+    pub height: usize,
+
+    /// Background color for the map, as RGBA.
+    pub color_bg: [u8; 4],
+
+    /// Default color for tiles without a blend color, as RGBA.
+    pub color_default: [u8; 4],
+
+    /// Names of the textures used by tiles in the map.
+    ///
+    /// Tiles refer to textures by their index in this list.
+    pub textures: Vec<String>,
+
+    /// Tile layers, ordered by layer number.
+    pub layers: Vec<LayerData>,
+}
+
+/// Serializable representation of a single [`TileMap`] layer.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LayerData {
+    /// The layer's number.
+    pub layer: i8,
+
+    /// Dense list of the layer's tiles, in the same
+    /// order as they're stored in the [`TileMap`].
+    pub tiles: Vec<TileData>,
+}
+
+/// Serializable representation of a [`Tile`] and its [`TileState`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TileData {
+    /// A filled tile.
+    Filled {
+        /// Index of the tile's texture in [`TileMapData::textures`].
+        texture: usize,
+
+        /// Vertical offset of the tile relative
+        /// to its height.
+        height_offset: Option<f32>,
+
+        /// Color to blend the tile's texture
+        /// with during drawing, as RGBA.
+        blend_color: Option<[u8; 4]>,
+
+        /// Mutable state of the tile.
+        state: TileStateData,
+    },
+
+    /// An empty tile.
+    Empty,
+}
+
+/// Serializable representation of a [`TileState`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TileStateData {
+    pub height_offset: f32,
+    pub target_height_offset: f32,
+    pub original_blend_color: [u8; 4],
+    pub blend_color: [u8; 4],
+    pub target_blend_color: [u8; 4],
+}
+
+impl TileMapData {
+    /// The current version of the format.
+    pub const VERSION: u16 = 1;
+
+    /// Encodes the data in the binary format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = BinaryWriter::default();
+        writer.bytes(BINARY_MAGIC);
+        writer.u16(self.version);
+        writer.u32(self.width as u32);
+        writer.u32(self.height as u32);
+        writer.bytes(&self.color_bg);
+        writer.bytes(&self.color_default);
+
+        writer.u32(self.textures.len() as u32);
+        for texture in &self.textures {
+            writer.u32(texture.len() as u32);
+            writer.bytes(texture.as_bytes());
+        }
+
+        writer.u32(self.layers.len() as u32);
+        for layer in &self.layers {
+            writer.bytes(&layer.layer.to_le_bytes());
+            writer.u32(layer.tiles.len() as u32);
+            for tile in &layer.tiles {
+                match tile {
+                    TileData::Empty => writer.bytes(&[0]),
+                    TileData::Filled {
+                        texture,
+                        height_offset,
+                        blend_color,
+                        state,
+                    } => {
+                        writer.bytes(&[1]);
+                        writer.u32(*texture as u32);
+
+                        // Presence flags for optional fields.
+                        let flags =
+                            height_offset.is_some() as u8 | (blend_color.is_some() as u8) << 1;
+                        writer.bytes(&[flags]);
+                        if let Some(height_offset) = height_offset {
+                            writer.f32(*height_offset);
+                        }
+                        if let Some(blend_color) = blend_color {
+                            writer.bytes(blend_color);
+                        }
+
+                        writer.f32(state.height_offset);
+                        writer.f32(state.target_height_offset);
+                        writer.bytes(&state.original_blend_color);
+                        writer.bytes(&state.blend_color);
+                        writer.bytes(&state.target_blend_color);
+                    }
+                }
+            }
+        }
+
+        writer.buffer
+    }
+
+    /// Decodes data from the binary format.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TileMapFormatError> {
+        let mut reader = BinaryReader { bytes };
+
+        if reader.take::<4>()? != *BINARY_MAGIC {
+            return Err(TileMapFormatError::InvalidMagic);
+        }
+
+        let version = reader.u16()?;
+        check_version(version)?;
+
+        let width = reader.u32()? as usize;
+        let height = reader.u32()? as usize;
+        let tiles_per_layer = check_size(width, height)?;
+        let color_bg = reader.take::<4>()?;
+        let color_default = reader.take::<4>()?;
+
+        let texture_count = reader.u32()?;
+        let mut textures = vec![];
+        for _ in 0..texture_count {
+            let len = reader.u32()? as usize;
+            let name = String::from_utf8(reader.slice(len)?.to_vec())
+                .map_err(|_| TileMapFormatError::InvalidTextureName)?;
+            textures.push(name);
+        }
+
+        let layer_count = reader.u32()?;
+        let mut layers = vec![];
+        for _ in 0..layer_count {
+            let layer = i8::from_le_bytes(reader.take::<1>()?);
+            let tile_count = reader.u32()? as usize;
+            if tile_count != tiles_per_layer {
+                return Err(TileMapFormatError::LayerSizeMismatch {
+                    layer,
+                    expected: tiles_per_layer,
+                    actual: tile_count,
+                });
+            }
+
+            // Grow the layer as tiles are read, so that its size is
+            // bounded by the data rather than by the claimed count.
+            let mut tiles = vec![];
+            for _ in 0..tile_count {
+                let tile = match reader.take::<1>()? {
+                    [0] => TileData::Empty,
+                    [1] => {
+                        let texture = reader.u32()? as usize;
+                        let [flags] = reader.take::<1>()?;
+                        let height_offset = match flags & 0b01 {
+                            0 => None,
+                            _ => Some(reader.f32()?),
+                        };
+                        let blend_color = match flags & 0b10 {
+                            0 => None,
+                            _ => Some(reader.take::<4>()?),
+                        };
+
+                        TileData::Filled {
+                            texture,
+                            height_offset,
+                            blend_color,
+                            state: TileStateData {
+                                height_offset: reader.f32()?,
+                                target_height_offset: reader.f32()?,
+                                original_blend_color: reader.take::<4>()?,
+                                blend_color: reader.take::<4>()?,
+                                target_blend_color: reader.take::<4>()?,
+                            },
+                        }
+                    }
+                    [tag] => return Err(TileMapFormatError::InvalidTileTag(tag)),
+                };
+                tiles.push(tile);
+            }
+            layers.push(LayerData { layer, tiles });
+        }
+
+        Ok(Self {
+            version,
+            width,
+            height,
+            color_bg,
+            color_default,
+            textures,
+            layers,
+        })
+    }
+
+    /// Encodes the data as RON.
+    pub fn to_ron(&self) -> Result<String, TileMapFormatError> {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|e| TileMapFormatError::Ron(e.to_string()))
+    }
+
+    /// Decodes data from RON.
+    pub fn from_ron(source: &str) -> Result<Self, TileMapFormatError> {
+        let data: Self =
+            ron::from_str(source).map_err(|e| TileMapFormatError::Ron(e.to_string()))?;
+        check_version(data.version)?;
+        Ok(data)
+    }
+}
+
+impl TileMap {
+    /// Converts the map into its serializable representation.
+    ///
+    /// `textures` names every texture used by the map's tiles.
+    pub fn to_data(
+        &self,
+        textures: &BTreeMap<String, TileTexture>,
+    ) -> Result<TileMapData, TileMapFormatError> {
+        let mut texture_names: Vec<String> = vec![];
+
+        let mut layers = vec![];
+        for (layer, tiles) in &self.layers {
+            let mut layer_tiles = Vec::with_capacity(tiles.len());
+            for (tile, state) in tiles {
+                let tile = match tile {
+                    Tile::Empty => TileData::Empty,
+                    Tile::Filled {
+                        texture,
+                        height_offset,
+                        blend_color,
+                    } => {
+                        // Look up the texture's name, adding it to
+                        // the map's texture list on first use.
+                        let name = textures
+                            .iter()
+                            .find(|(_, t)| *t == texture)
+                            .map(|(name, _)| name)
+                            .ok_or(TileMapFormatError::UnnamedTexture)?;
+                        let texture = match texture_names.iter().position(|n| n == name) {
+                            Some(index) => index,
+                            None => {
+                                texture_names.push(name.clone());
+                                texture_names.len() - 1
+                            }
+                        };
+
+                        TileData::Filled {
+                            texture,
+                            height_offset: *height_offset,
+                            blend_color: blend_color.map(Into::into),
+                            state: TileStateData {
+                                height_offset: state.height_offset,
+                                target_height_offset: state.target_height_offset,
+                                original_blend_color: state.original_blend_color.into(),
+                                blend_color: state.blend_color.into(),
+                                target_blend_color: state.target_blend_color.into(),
+                            },
+                        }
+                    }
+                };
+                layer_tiles.push(tile);
+            }
+
+            layers.push(LayerData {
+                layer: *layer,
+                tiles: layer_tiles,
+            });
+        }
+
+        Ok(TileMapData {
+            version: TileMapData::VERSION,
+            width: self.width,
+            height: self.height,
+            color_bg: self.color_bg.into(),
+            color_default: self.color_default.into(),
+            textures: texture_names,
+            layers,
+        })
+    }
+
+    /// Creates a map from its serializable representation.
+    ///
+    /// `textures` must contain every texture named by the data.
+    pub fn from_data(
+        data: &TileMapData,
+        textures: &BTreeMap<String, TileTexture>,
+    ) -> Result<Self, TileMapFormatError> {
+        check_version(data.version)?;
+        check_size(data.width, data.height)?;
+
+        // Resolve texture names up front.
+        let texture_table = data
+            .textures
+            .iter()
+            .map(|name| {
+                textures
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| TileMapFormatError::UnknownTexture(name.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut map = TileMap::new(
+            data.width,
+            data.height,
+            as_color(data.color_bg),
+            as_color(data.color_default),
+        );
+
+        for layer in &data.layers {
+            if map.layers.contains_key(&layer.layer) {
+                return Err(TileMapFormatError::DuplicateLayer(layer.layer));
+            }
+
+            if layer.tiles.len() != map.tiles_per_layer {
+                return Err(TileMapFormatError::LayerSizeMismatch {
+                    layer: layer.layer,
+                    expected: map.tiles_per_layer,
+                    actual: layer.tiles.len(),
+                });
+            }
+
+            let mut tiles = Vec::with_capacity(map.tiles_per_layer);
+            for tile in &layer.tiles {
+                let tile = match tile {
+                    TileData::Empty => (Tile::Empty, TileState::default()),
+                    TileData::Filled {
+                        texture,
+                        height_offset,
+                        blend_color,
+                        state,
+                    } => {
+                        let texture = texture_table
+                            .get(*texture)
+                            .ok_or(TileMapFormatError::InvalidTextureIndex(*texture))?;
+
+                        (
+                            Tile::Filled {
+                                texture: texture.clone(),
+                                height_offset: *height_offset,
+                                blend_color: blend_color.map(as_color),
+                            },
+                            TileState {
+                                texture: Some(texture.clone()),
+                                height_offset: state.height_offset,
+                                target_height_offset: state.target_height_offset,
+                                original_blend_color: as_color(state.original_blend_color),
+                                blend_color: as_color(state.blend_color),
+                                target_blend_color: as_color(state.target_blend_color),
+                            },
+                        )
+                    }
+                };
+                tiles.push(tile);
+            }
+
+            map.layers.insert(layer.layer, tiles);
+        }
+
+        Ok(map)
+    }
+}
+
+/// Errors encountered while serializing or deserializing a [`TileMap`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum TileMapFormatError {
+    /// Binary data doesn't start with the expected magic bytes.
+    InvalidMagic,
+
+    /// Data was written with a format version which doesn't exist,
+    /// or is newer than this one.
+    UnsupportedVersion(u16),
+
+    /// Binary data ended unexpectedly.
+    UnexpectedEof,
+
+    /// Binary data contains an unknown tile tag.
+    InvalidTileTag(u8),
+
+    /// Binary data contains a texture name which isn't UTF-8.
+    InvalidTextureName,
+
+    /// A tile refers to a texture index that doesn't exist.
+    InvalidTextureIndex(usize),
+
+    /// A tile uses a texture which wasn't given a name.
+    UnnamedTexture,
+
+    /// Data names a texture which wasn't provided.
+    UnknownTexture(String),
+
+    /// The map has more than [`MAX_TILES_PER_LAYER`] grid points.
+    TooLarge { width: usize, height: usize },
+
+    /// The same layer appears more than once.
+    DuplicateLayer(i8),
+
+    /// A layer doesn't contain one tile for every grid point.
+    LayerSizeMismatch {
+        layer: i8,
+        expected: usize,
+        actual: usize,
+    },
+
+    /// RON encoding or decoding failed.
+    Ron(String),
+}
+
+impl fmt::Display for TileMapFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TileMapFormatError::InvalidMagic => write!(f, "not a tile map"),
+            TileMapFormatError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported tile map version {version} (expected 1 to {})",
+                TileMapData::VERSION
+            ),
+            TileMapFormatError::UnexpectedEof => write!(f, "unexpected end of data"),
+            TileMapFormatError::InvalidTileTag(tag) => write!(f, "invalid tile tag {tag}"),
+            TileMapFormatError::InvalidTextureName => write!(f, "texture name isn't UTF-8"),
+            TileMapFormatError::InvalidTextureIndex(index) => {
+                write!(f, "invalid texture index {index}")
+            }
+            TileMapFormatError::UnnamedTexture => write!(f, "tile uses an unnamed texture"),
+            TileMapFormatError::UnknownTexture(name) => write!(f, "unknown texture `{name}`"),
+            TileMapFormatError::TooLarge { width, height } => write!(
+                f,
+                "map is too large ({width}x{height}, expected at most {MAX_TILES_PER_LAYER} tiles)"
+            ),
+            TileMapFormatError::DuplicateLayer(layer) => write!(f, "layer {layer} appears twice"),
+            TileMapFormatError::LayerSizeMismatch {
+                layer,
+                expected,
+                actual,
+            } => write!(f, "layer {layer} has {actual} tiles (expected {expected})"),
+            TileMapFormatError::Ron(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for TileMapFormatError {}
+
+/// Fails if `version` is `0` or newer than [`TileMapData::VERSION`].
+fn check_version(version: u16) -> Result<(), TileMapFormatError> {
+    if version == 0 || version > TileMapData::VERSION {
+        return Err(TileMapFormatError::UnsupportedVersion(version));
+    }
+
+    Ok(())
+}
+
+/// Returns the number of grid points in a `width` x `height` map.
+///
+/// Fails if there are more than [`MAX_TILES_PER_LAYER`], so that sizes
+/// which would overflow or exhaust memory are rejected before allocating.
+fn check_size(width: usize, height: usize) -> Result<usize, TileMapFormatError> {
+    width
+        .checked_mul(height)
+        .filter(|tiles| *tiles <= MAX_TILES_PER_LAYER)
+        .ok_or(TileMapFormatError::TooLarge { width, height })
+}
+
+/// Converts RGBA channels into a [`Color`].
+fn as_color([red, green, blue, alpha]: [u8; 4]) -> Color {
+    Color::new(red, green, blue, alpha)
+}
+
+/// Little-endian writer for the binary format.
+#[derive(Default)]
+struct BinaryWriter {
+    buffer: Vec<u8>,
+}
+
+impl BinaryWriter {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.bytes(&value.to_le_bytes());
+    }
+}
+
+/// Little-endian reader for the binary format.
+struct BinaryReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> BinaryReader<'a> {
+    fn slice(&mut self, len: usize) -> Result<&'a [u8], TileMapFormatError> {
+        if self.bytes.len() < len {
+            return Err(TileMapFormatError::UnexpectedEof);
+        }
+
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], TileMapFormatError> {
+        Ok(self.slice(N)?.try_into().unwrap())
+    }
+
+    fn u16(&mut self) -> Result<u16, TileMapFormatError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, TileMapFormatError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, TileMapFormatError> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns named placeholder textures for test maps.
+    fn textures() -> BTreeMap<String, TileTexture> {
+        BTreeMap::from([
+            ("wall".to_string(), TileTexture::placeholder(1)),
+            ("floor".to_string(), TileTexture::placeholder(2)),
+        ])
+    }
+
+    /// Returns a 3x2 map with walls, floors and empty tiles on two layers.
+    fn map() -> TileMap {
+        let textures = textures();
+        let mut map = TileMap::new(
+            3,
+            2,
+            Color::new(1, 2, 3, 255),
+            Color::new(255, 255, 255, 255),
+        );
+        map.set_tile(
+            0,
+            0,
+            0,
+            Tile::Filled {
+                texture: textures["wall"].clone(),
+                height_offset: Some(0.5),
+                blend_color: None,
+            },
+        );
+        map.set_tile(
+            2,
+            1,
+            0,
+            Tile::Filled {
+                texture: textures["floor"].clone(),
+                height_offset: None,
+                blend_color: Some(Color::new(10, 20, 30, 40)),
+            },
+        );
+        map.set_tile(
+            1,
+            0,
+            -1,
+            Tile::Filled {
+                texture: textures["floor"].clone(),
+                height_offset: None,
+                blend_color: None,
+            },
+        );
+        map.get_tile_state(2, 1, 0).unwrap().target_height_offset = -3.0;
+        map
+    }
+
+    #[test]
+    fn binary_round_trip_preserves_map() {
+        let data = map().to_data(&textures()).unwrap();
+        assert_eq!(data.version, TileMapData::VERSION);
+        assert_eq!(data.textures, ["floor", "wall"]);
+        assert_eq!(data.layers.len(), 2);
+
+        let decoded = TileMapData::from_bytes(&data.to_bytes()).unwrap();
+        assert_eq!(decoded, data);
+
+        let map = TileMap::from_data(&decoded, &textures()).unwrap();
+        assert_eq!(map.to_data(&textures()).unwrap(), data);
+    }
+
+    #[test]
+    fn ron_round_trip_preserves_map() {
+        let data = map().to_data(&textures()).unwrap();
+
+        let decoded = TileMapData::from_ron(&data.to_ron().unwrap()).unwrap();
+        assert_eq!(decoded, data);
+
+        let map = TileMap::from_data(&decoded, &textures()).unwrap();
+        assert_eq!(map.to_data(&textures()).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_unwritten_versions() {
+        let mut data = map().to_data(&textures()).unwrap();
+
+        for version in [0, TileMapData::VERSION + 1] {
+            data.version = version;
+            let expected = Err(TileMapFormatError::UnsupportedVersion(version));
+            assert_eq!(TileMapData::from_bytes(&data.to_bytes()), expected);
+            assert_eq!(
+                TileMapData::from_ron(&data.to_ron().unwrap()),
+                expected.clone()
+            );
+            assert_eq!(TileMap::from_data(&data, &textures()).err(), expected.err());
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_layers() {
+        let mut data = map().to_data(&textures()).unwrap();
+        data.layers.push(data.layers[0].clone());
+
+        assert_eq!(
+            TileMap::from_data(&data, &textures()).err(),
+            Some(TileMapFormatError::DuplicateLayer(-1))
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_maps_before_reading_tiles() {
+        let mut data = map().to_data(&textures()).unwrap();
+        data.width = u32::MAX as usize;
+        data.height = u32::MAX as usize;
+
+        // The layers are far too short for the claimed size,
+        // but the size itself is rejected first.
+        let too_large = TileMapFormatError::TooLarge {
+            width: data.width,
+            height: data.height,
+        };
+        assert_eq!(TileMapData::from_bytes(&data.to_bytes()), Err(too_large));
+    }
+
+    #[test]
+    fn rejects_layers_with_the_wrong_tile_count() {
+        let mut data = map().to_data(&textures()).unwrap();
+        data.layers[1].tiles.pop();
+
+        assert_eq!(
+            TileMapData::from_bytes(&data.to_bytes()),
+            Err(TileMapFormatError::LayerSizeMismatch {
+                layer: 0,
+                expected: 6,
+                actual: 5,
+            })
+        );
+    }
+}
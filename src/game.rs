@@ -1,100 +1,148 @@
-use image::imageops::FilterType;
+use std::rc::Rc;
 
 use crate::{
     engine::tile::as_macroquad_color,
     game::{
-        audio::{Piece, Track},
+        audio::{
+            Piece,
+            arrangement::{Arranger, MusicEvent},
+            backend::{AudioBackend, MacroquadBackend, NullBackend, PlayParams},
+            events::Subscription,
+            mixer::{Bus, Mixer},
+            pattern::Composition,
+            spatial::{Emitter, SpatialSound},
+        },
+        editor::Editor,
         entity::Player,
+        generator::{LevelGenerator, Method},
+        jukebox::{Jukebox, Record, RecordCollection, RecordsError},
         transition::{TransitionOverlay, TransitionState},
     },
 };
 
 pub mod audio;
+pub mod editor;
 pub mod entity;
 pub mod fog;
+pub mod generator;
+pub mod jukebox;
 pub mod map;
 pub mod transition;
 
 pub const IMAGE_SPLASH: &[u8] = include_bytes!("../assets/splash.png");
 pub const IMAGE_LOADING: &[u8] = include_bytes!("../assets/loading.png");
 
+/// Number of seeds tried for each generated level before
+/// giving up and replaying the first level instead.
+const MAX_GENERATION_ATTEMPTS: usize = 16;
+
 /// Main game loop entrypoint.
 pub async fn game_loop() {
     // Clear the screen to the map background color.
     macroquad::prelude::clear_background(as_macroquad_color(map::BACKGROUND));
     macroquad::prelude::next_frame().await;
 
-    // Start the background texture.
-    let bg_track = macroquad::audio::load_sound_from_bytes(audio::FOLEY_VINYL_TEXTURE)
-        .await
-        .unwrap();
-    macroquad::audio::play_sound(
-        &bg_track,
-        macroquad::audio::PlaySoundParams {
-            looped: true,
+    // Choose an audio backend, playing nothing if audio is disabled.
+    let mut audio_backend: Rc<dyn AudioBackend> =
+        if std::env::args().any(|arg| arg == audio::NO_AUDIO_ARG) {
+            Rc::new(NullBackend::default())
+        } else {
+            Rc::new(MacroquadBackend::default())
+        };
+
+    // Start the background texture, falling back to
+    // silence if the backend can't load sounds.
+    let bg_track = match audio_backend.load(audio::FOLEY_VINYL_TEXTURE).await {
+        Ok(sound) => sound,
+        Err(e) => {
+            eprintln!("{e}; continuing without audio");
+            audio_backend = Rc::new(NullBackend::default());
+            audio_backend
+                .load(audio::FOLEY_VINYL_TEXTURE)
+                .await
+                .unwrap()
+        }
+    };
+
+    // Route everything through a mixer, so each kind of sound has its own volume.
+    let mixer = Mixer::new(audio_backend.clone());
+    let music_bus = mixer.bus(Bus::Music);
+    let ambience_bus = mixer.bus(Bus::Ambience);
+    let sfx_bus = mixer.bus(Bus::Sfx);
+
+    // Add effects to each bus before anything's loaded through it.
+    mixer.set_effects(Bus::Music, audio::music_effects());
+    mixer.set_send(Bus::Music, audio::music_muffle_effects());
+    mixer.set_effects(Bus::Sfx, audio::sfx_effects());
+
+    ambience_bus.play(
+        bg_track,
+        PlayParams {
+            time: macroquad::time::get_time(),
             volume: 1.0,
+            looped: true,
         },
     );
 
-    // Configure audio tracks.
-    #[rustfmt::skip]
-    let track_1 = Track::new(audio::SAMPLE_BASELINE, [
-        1,0,0,0, 1,0,0,1, 1,0,0,0, 1,0,0,0, 
-        1,0,0,0, 1,0,0,1, 1,0,0,0, 1,0,0,0,
-    ]).await;
-    #[rustfmt::skip]
-    let track_2_lo = Track::new(audio::SAMPLE_1_LO, [
-        0,0,0,0, 1,0,0,0, 0,0,0,0, 0,0,0,0, 
-        0,0,0,0, 0,0,0,0, 0,0,0,0, 0,0,0,0,
-    ]).await;
-    #[rustfmt::skip]
-    let track_2_hi = Track::new(audio::SAMPLE_1_HI, [
-        0,0,0,0, 0,0,0,0, 0,0,0,0, 0,0,0,0, 
-        0,0,0,0, 1,0,0,0, 0,0,0,0, 0,0,0,0,
-    ]).await;
-    #[rustfmt::skip]
-    let track_3_lo = Track::new(audio::SAMPLE_2_LO, [
-        0,0,0,0, 0,0,0,0, 0,0,0,0, 0,0,0,0, 
-        0,0,0,0, 0,0,0,0, 0,0,1,0, 0,0,0,0,
-    ]).await;
-    #[rustfmt::skip]
-    let track_3_hi = Track::new(audio::SAMPLE_2_HI, [
-        0,0,0,0, 0,0,0,0, 0,0,1,0, 0,0,0,0, 
-        0,0,0,0, 0,0,0,0, 0,0,0,0, 0,0,0,0,
-    ]).await;
-    #[rustfmt::skip]
-    let track_4_lo = Track::new(audio::SAMPLE_3_LO, [
-        1,0,0,0, 0,0,0,0, 0,0,0,0, 0,0,0,0, 
-        0,0,0,0, 0,0,0,0, 0,0,0,0, 0,0,0,0,
-    ]).await;
-    #[rustfmt::skip]
-    let track_4_hi = Track::new(audio::SAMPLE_3_HI, [
-        0,0,0,0, 0,0,0,0, 0,0,0,0, 0,0,0,0,
-        1,0,0,0, 0,0,0,0, 0,0,0,0, 0,0,0,0,
-    ]).await;
-
-    // Compose a piece.
-    let mut audio_piece = Piece::new(track_1, audio::TEMPO_BPM)
-        .with(track_2_lo)
-        .with(track_2_hi)
-        .with(track_3_lo)
-        .with(track_3_hi)
-        .with(track_4_lo)
-        .with(track_4_hi);
-
-    // Which track should unmute next?
-    let mut next_track = 0;
-
-    // Load tile maps.
-    let mut tilemaps = vec![];
-    for &map_bytes in map::TILEMAPS {
-        let map_image = image::load_from_memory(map_bytes)
-            .unwrap()
-            .rotate270()
-            .resize_exact(map::WIDTH as u32, map::HEIGHT as u32, FilterType::Nearest);
-        tilemaps.push(map_image);
+    // Generate the sound effects.
+    let objective_sound = sfx_bus
+        .load(&audio::objective_sound().to_wav())
+        .await
+        .unwrap();
+
+    // Load a hum for each objective which can hum at once.
+    let objective_hum = audio::objective_hum().to_wav();
+    let mut hum_sounds = vec![];
+    for _ in 0..audio::OBJECTIVE_HUMS {
+        let sound = SpatialSound::load(ambience_bus.as_ref(), &objective_hum).await;
+        hum_sounds.push(sound.unwrap());
     }
 
+    // Returns a humming emitter at each of `map`'s objectives,
+    // as heard from `listener`, so players can find them by ear.
+    let objective_hums = |map: &map::GameMap, listener: glam::Vec2| -> Vec<Emitter> {
+        map.objective_positions()
+            .into_iter()
+            .zip(&hum_sounds)
+            .map(|(position, &sound)| {
+                let mut hum = Emitter::new(ambience_bus.clone(), sound, position);
+                hum.start(&map.map, listener, &map.wall_texture);
+                hum
+            })
+            .collect()
+    };
+
+    // Compose each distinct level piece, with an arranger to follow it,
+    // keeping the source of each for the records collected from it.
+    let mut music: Vec<(Piece, Arranger)> = vec![];
+    let mut music_sources: Vec<String> = vec![];
+    let mut music_notes: Vec<(String, Vec<String>)> = vec![];
+    let mut level_music: Vec<usize> = vec![];
+    for (i, &source) in audio::LEVEL_PIECES.iter().enumerate() {
+        if let Some(earlier) = audio::LEVEL_PIECES[..i].iter().position(|&s| s == source) {
+            level_music.push(level_music[earlier]);
+            continue;
+        }
+
+        let composition = Composition::parse(source).unwrap();
+        let piece = composition.into_piece(music_bus.clone()).await.unwrap();
+        music.push((piece, Arranger::new(composition.arrangement)));
+        music_sources.push(source.to_string());
+        music_notes.push((composition.title, composition.notes));
+        level_music.push(music.len() - 1);
+    }
+
+    // Pulse the fog when the pianos and pulses play.
+    let medium_pulses = Subscription::new().with_tracks(["piano_lo", "piano_hi"]);
+    let large_pulses = Subscription::new().with_tracks(["pulse_lo", "pulse_hi"]);
+    for (piece, _) in &mut music {
+        piece.subscribe(&medium_pulses);
+        piece.subscribe(&large_pulses);
+    }
+
+    // Returns the index in `music` of the piece for the level at `index`.
+    let music_for_level = |index: usize| level_music[index % level_music.len()];
+
     // Configure player sprites and state.
     let mut player = Player::new();
     let mut player_pulses: Vec<fog::Pulse> = vec![];
@@ -106,34 +154,171 @@ pub async fn game_loop() {
     let mut map_transition = TransitionOverlay::new(0.0, 2.0, 0.75).with_image(IMAGE_SPLASH);
     let mut map_transition_state = map_transition.update(0.0);
 
+    // Load tile maps, skipping any which aren't playable, along with
+    // the music for each. Each map is kept with its level number, its
+    // index in `TILEMAPS`, so skipped maps don't renumber the rest.
+    let mut tilemaps = vec![];
+    let mut tilemap_music = vec![];
+    for (i, asset) in map::TILEMAPS.iter().enumerate() {
+        let level = map::Level::decode(asset);
+
+        let spawn_point = map.load_map(&level);
+        for issue in &map.palette_issues {
+            eprintln!("Map {i}: {issue}");
+        }
+        let issues = map.validate(spawn_point);
+        for issue in &issues {
+            eprintln!("Map {i}: {issue}");
+        }
+
+        if !issues.iter().any(map::LevelIssue::is_fatal) {
+            tilemaps.push((i, level));
+            tilemap_music.push(music_for_level(i));
+        }
+    }
+    assert!(!tilemaps.is_empty(), "no playable maps");
+
     // Load the first map.
-    let spawn_point = map.load_map(&tilemaps[0]);
+    let spawn_point = map.load_map(&tilemaps[0].1).unwrap();
     player.position = spawn_point;
-    let mut next_map_index = 1;
+    let mut map_index = 0;
+    let (audio_piece, arranger) = &mut music[tilemap_music[0]];
+    arranger.handle(MusicEvent::Start, audio_piece);
+    let mut hums = objective_hums(&map, player.position);
+
+    // Objectives reached in the current level, which the music is clearest near.
+    let mut cleared_objectives: Vec<glam::Vec2> = vec![];
+
+    // Level editor, if it's open.
+    let mut editor: Option<Editor> = None;
+
+    // Records collected from completed levels, the last error loading
+    // or saving them, and the jukebox, if it's open.
+    #[cfg(not(target_arch = "wasm32"))]
+    let (mut records, mut records_error) = match RecordCollection::load() {
+        Ok(records) => (records, None),
+        Err(e) => (RecordCollection::default(), Some(e)),
+    };
+    #[cfg(target_arch = "wasm32")]
+    let (mut records, records_error) = (RecordCollection::default(), None::<RecordsError>);
+    let mut jukebox: Option<Jukebox> = None;
 
     loop {
         let frame_time = macroquad::prelude::get_frame_time();
 
+        // Start any sounds held until this frame.
+        audio_backend.update();
+
+        // Toggle the mute.
+        if macroquad::prelude::is_key_pressed(miniquad::KeyCode::M) {
+            mixer.set_muted(Bus::Master, !mixer.is_muted(Bus::Master));
+        }
+
+        // Toggle the jukebox, unless the level editor is open.
+        if macroquad::prelude::is_key_pressed(miniquad::KeyCode::J) && editor.is_none() {
+            match jukebox.take() {
+                None => {
+                    hums.clear();
+                    music[tilemap_music[map_index]].0.silence();
+                    mixer.set_wet(Bus::Music, 0.0);
+                    jukebox = Some(Jukebox::new());
+                }
+
+                // Pick the level's music back up where it left off.
+                Some(mut closing) => {
+                    closing.stop(&mut music);
+
+                    let (audio_piece, arranger) = &mut music[tilemap_music[map_index]];
+                    arranger.restore(audio_piece);
+                    hums = objective_hums(&map, player.position);
+                }
+            }
+        }
+
+        // Play records instead of the level while the jukebox is open.
+        if let Some(jukebox) = &mut jukebox {
+            mixer.update(frame_time);
+            if let Some(record) = jukebox.update(frame_time, &records, &mut music) {
+                let backend = music_bus.clone();
+                jukebox
+                    .play(record, &mut music, &mut music_sources, backend)
+                    .await;
+            }
+
+            // The fog isn't drawn, so don't keep any steps to pulse to.
+            medium_pulses.clear();
+            large_pulses.clear();
+
+            macroquad::prelude::clear_background(as_macroquad_color(map::BACKGROUND));
+            jukebox.draw(&records, records_error.as_ref(), &music);
+
+            macroquad::prelude::next_frame().await;
+            continue;
+        }
+
+        // Toggle the level editor.
+        if macroquad::prelude::is_key_pressed(miniquad::KeyCode::Tab) {
+            match editor.take() {
+                None => {
+                    hums.clear();
+                    editor = Some(Editor::new(
+                        &tilemaps[map_index].1,
+                        &mut map,
+                        player.position,
+                    ));
+                }
+
+                // Only close the editor once the level is playable,
+                // restarting the level with any edits.
+                Some(closing) => {
+                    if closing.issues.iter().any(map::LevelIssue::is_fatal) {
+                        editor = Some(closing);
+                    } else {
+                        tilemaps[map_index].1 = closing.level();
+                        player.position = map.load_map(&tilemaps[map_index].1).unwrap();
+                        player_pulses.clear();
+                        cleared_objectives.clear();
+
+                        let (audio_piece, arranger) = &mut music[tilemap_music[map_index]];
+                        arranger.handle(MusicEvent::Start, audio_piece);
+                        hums = objective_hums(&map, player.position);
+                    }
+                }
+            }
+        }
+
+        // Paint the level instead of playing it while the editor is open.
+        if let Some(editor) = &mut editor {
+            editor.update(frame_time, &mut map);
+
+            // Export the level.
+            #[cfg(not(target_arch = "wasm32"))]
+            if macroquad::prelude::is_key_pressed(miniquad::KeyCode::P) {
+                let path = format!("level-{}.png", tilemaps[map_index].0);
+                match std::fs::write(&path, editor.level().to_png()) {
+                    Ok(()) => eprintln!("Exported level to {path}"),
+                    Err(e) => eprintln!("Failed to export level to {path}: {e}"),
+                }
+            }
+
+            map.center_viewport_on(editor.camera);
+            map.reveal();
+            map.map.update(frame_time);
+            map.map.draw_tiles();
+            editor.draw(&map);
+
+            macroquad::prelude::next_frame().await;
+            continue;
+        }
+
+        // Follow the current level's music.
+        let (audio_piece, arranger) = &mut music[tilemap_music[map_index]];
+
         // Update player position.
         player.translate(frame_time, &mut map.map, &map_wall_texture);
 
-        // Center the map viewport on the player. //
-        let player_view_position =
-            map.map
-                .grid_to_view(player.position.x, player.position.y, map::FOREGROUND_LAYER);
-        // Subtract viewport offset from the view position, since the view position
-        // includes the viewport offset.
-        let player_view_position = player_view_position - map.map.viewport_offset;
-        map.map.viewport_offset.x = -player_view_position.x;
-        map.map.viewport_offset.y = -player_view_position.y;
-        // Shift the viewport offset to be centered on the player.
-        let view_size = map.map.calculate_view_size();
-        map.map.viewport_offset.x += view_size.x / 2.0;
-        map.map.viewport_offset.y += view_size.y / 2.0;
-        // Shift the viewport offset to adjust for the player sprite width.
-        let tile_size = map.map.calculate_tile_size();
-        map.map.viewport_offset.x -= tile_size.x / 2.0;
-        map.map.viewport_offset.y -= tile_size.y / 2.0;
+        // Center the map viewport on the player.
+        map.center_viewport_on(player.position);
 
         // If the player is on an objective tile, fill all adjacent tiles
         // to clear the objectives.
@@ -152,40 +337,77 @@ pub async fn game_loop() {
                 map::ACCENT_2,
             );
 
-            // Play a new track.
-            match next_track {
-                0 => audio_piece.set_track_volume(0, 1.0),
-                1 => {
-                    audio_piece.set_track_volume(1, 1.0);
-                    audio_piece.set_track_volume(2, 1.0);
-                }
-                2 => {
-                    audio_piece.set_track_volume(3, 1.0);
-                    audio_piece.set_track_volume(4, 1.0);
-                }
-                3 => {
-                    audio_piece.set_track_volume(5, 1.0);
-                    audio_piece.set_track_volume(6, 1.0);
+            cleared_objectives.push(player.position);
+
+            // Silence the reached objectives.
+            hums.retain(|hum| {
+                map.map.tile_has_original_color(
+                    hum.position.x as usize,
+                    hum.position.y as usize,
+                    map::FOREGROUND_LAYER,
+                    map::ACCENT_1,
+                )
+            });
+
+            mixer.duck(audio::OBJECTIVE_DUCK, audio::objective_sound().duration());
+            sfx_bus.play(
+                objective_sound,
+                PlayParams {
+                    time: macroquad::time::get_time(),
+                    volume: 1.0,
+                    looped: false,
+                },
+            );
+
+            // Unlock new tracks, and move on to the outro once the level is clear.
+            arranger.handle(MusicEvent::Objective, audio_piece);
+            if map.objectives_remaining == 0 {
+                arranger.handle(MusicEvent::Clear, audio_piece);
+
+                // Collect the level's record, with every part unlocked along the way.
+                let (title, notes) = &music_notes[tilemap_music[map_index]];
+                records.collect(Record {
+                    level: tilemaps[map_index].0,
+                    title: title.clone(),
+                    notes: notes.clone(),
+                    source: music_sources[tilemap_music[map_index]].clone(),
+                    unlocked: arranger.unlocked(),
+                });
+
+                // Don't overwrite records which couldn't be loaded.
+                #[cfg(not(target_arch = "wasm32"))]
+                if !matches!(records_error, Some(RecordsError::Load(_))) {
+                    records_error = records.save().err();
                 }
-                _ => {}
             }
-            next_track += 1;
         }
 
+        // Muffle the music away from the objectives reached so far.
+        let nearest_cleared = cleared_objectives
+            .iter()
+            .map(|position| position.distance(player.position))
+            .min_by(f32::total_cmp);
+        mixer.set_wet(Bus::Music, audio::music_muffle(nearest_cleared));
+
         // Update audio tracks.
-        let played_tracks = audio_piece.update(frame_time);
+        mixer.update(frame_time);
+        arranger.update(audio_piece);
+        audio_piece.update();
+        for hum in &mut hums {
+            hum.update(&map.map, player.position, &map_wall_texture);
+        }
 
         // Emit pulses from the player position when tracks play. //
-        if played_tracks[1] || played_tracks[2] {
+        if !medium_pulses.drain().is_empty() {
             player_pulses.push(fog::Pulse::new(
                 glam::Vec2::new(player.position.x + 0.5, player.position.y + 0.5),
-                fog::MEDIUM_MAX_PULSE_RADIUS as f32,
+                fog::max_pulse_radius(&map.map, fog::MEDIUM_PULSE) as f32,
             ));
         }
-        if played_tracks[5] || played_tracks[6] {
+        if !large_pulses.drain().is_empty() {
             player_pulses.push(fog::Pulse::new(
                 glam::Vec2::new(player.position.x + 0.5, player.position.y + 0.5),
-                fog::LARGE_MAX_PULSE_RADIUS as f32,
+                fog::max_pulse_radius(&map.map, fog::LARGE_PULSE) as f32,
             ));
         }
 
@@ -194,8 +416,8 @@ pub async fn game_loop() {
         player_pulses.retain_mut(|pulse| pulse.update(time, &mut map.map, &map_wall_texture));
 
         // Apply fog of war to the entire map. //
-        for x in 0..map::WIDTH {
-            for y in 0..map::HEIGHT {
+        for x in 0..map.map.width() {
+            for y in 0..map.map.height() {
                 if let Some(tile_state) = map.map.get_tile_state(x, y, map::FOREGROUND_LAYER) {
                     // Skip wall tiles.
                     if tile_state.texture.as_ref() == Some(&map_wall_texture) {
@@ -252,12 +474,6 @@ pub async fn game_loop() {
 
         // Load the next map if all objectives are cleared.
         if map.objectives_remaining == 0 {
-            // Stop all tracks.
-            for i in 0..audio_piece.track_count() {
-                audio_piece.set_track_volume(i, 0.0);
-            }
-            next_track = 0;
-
             // Clear all pulses.
             player_pulses.clear();
 
@@ -270,9 +486,50 @@ pub async fn game_loop() {
 
                 // Load the next map once the transition is holding.
                 TransitionState::Hold => {
-                    let spawn_point = map.load_map(&tilemaps[next_map_index]);
+                    // Once every level has been played, keep
+                    // going with freshly generated levels.
+                    if map_index + 1 == tilemaps.len() {
+                        let methods = [Method::Caves, Method::Rooms, Method::DrunkardsWalk];
+                        let first_level = (tilemaps[map_index].0 + 1).max(map::TILEMAPS.len());
+
+                        // Move on to the next seed until a level is playable.
+                        let playable = (first_level..first_level + MAX_GENERATION_ATTEMPTS)
+                            .find_map(|level| {
+                                let seed = level as u64;
+                                let generator =
+                                    LevelGenerator::new(seed, methods[seed as usize % 3]);
+                                let generated = generator.generate_level();
+
+                                let spawn_point = map.load_map(&generated);
+                                let issues = map.validate(spawn_point);
+                                if !issues.iter().any(map::LevelIssue::is_fatal) {
+                                    return Some((level, generated));
+                                }
+
+                                for issue in &issues {
+                                    eprintln!("Generated map {level}: {issue}");
+                                }
+                                None
+                            });
+
+                        // Replay the first level if no seed was playable.
+                        let (level, generated) = playable.unwrap_or_else(|| tilemaps[0].clone());
+                        tilemaps.push((level, generated));
+                        tilemap_music.push(music_for_level(level));
+                    }
+
+                    map_index += 1;
+                    let spawn_point = map.load_map(&tilemaps[map_index].1).unwrap();
                     player.position = spawn_point;
-                    next_map_index = (next_map_index + 1) % tilemaps.len();
+                    cleared_objectives.clear();
+
+                    // Start the new level's music from the top.
+                    let (audio_piece, arranger) = &mut music[tilemap_music[map_index]];
+                    arranger.handle(MusicEvent::Start, audio_piece);
+
+                    // Stop any old hums before their sounds are reused.
+                    hums.clear();
+                    hums = objective_hums(&map, player.position);
                 }
                 _ => {}
             }
@@ -280,20 +537,50 @@ pub async fn game_loop() {
 
         // Draw controls
         let screen_height = macroquad::prelude::screen_height();
+        if let Some(e) = &records_error {
+            macroquad::prelude::draw_text(
+                &format!("Records won't be kept: {e}"),
+                10.,
+                screen_height - 120.,
+                20.,
+                as_macroquad_color(map::ACCENT_1),
+            );
+        }
         macroquad::prelude::draw_text(
             "[mouse | touch]",
             10.,
-            screen_height - 60.,
+            screen_height - 100.,
             20.,
             macroquad::prelude::GRAY,
         );
         macroquad::prelude::draw_text(
             "[w a s d]: move",
             10.,
+            screen_height - 80.,
+            20.,
+            macroquad::prelude::GRAY,
+        );
+        macroquad::prelude::draw_text(
+            "[tab]: edit level",
+            10.,
+            screen_height - 60.,
+            20.,
+            macroquad::prelude::GRAY,
+        );
+        macroquad::prelude::draw_text(
+            "[j]: jukebox",
+            10.,
             screen_height - 40.,
             20.,
             macroquad::prelude::GRAY,
         );
+        macroquad::prelude::draw_text(
+            "[m]: mute",
+            10.,
+            screen_height - 20.,
+            20.,
+            macroquad::prelude::GRAY,
+        );
         // macroquad::prelude::draw_text(
         //     "[e]: debug info",
         //     10.,
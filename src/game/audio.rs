@@ -1,3 +1,29 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::engine::rng::Rng;
+
+pub mod arrangement;
+pub mod backend;
+pub mod effects;
+pub mod events;
+pub mod midi;
+pub mod mixdown;
+pub mod mixer;
+pub mod pattern;
+pub mod scheduler;
+pub mod spatial;
+pub mod synth;
+pub mod wav;
+
+use backend::{AudioBackend, PlayParams, SoundId};
+use effects::{Effect, EffectChain};
+use events::{StepEvent, Subscription};
+use scheduler::{AudioClock, GameClock, Scheduler, TempoRamp};
+use synth::{Synth, Waveform};
+
 // Foley samples.
 pub const FOLEY_VINYL_TEXTURE: &[u8] =
     include_bytes!("../../assets/Clark Audio - Texture Crackle Vinyl.wav");
@@ -13,42 +39,178 @@ pub const SAMPLE_2_HI: &[u8] = include_bytes!("../../assets/Helton Yan - Major L
 pub const SAMPLE_3_LO: &[u8] = include_bytes!("../../assets/Helton Yan - Pulse Low.wav");
 pub const SAMPLE_3_HI: &[u8] = include_bytes!("../../assets/Helton Yan - Pulse High.wav");
 
-/// The tempo of the game tracks, in beats per minute.
+/// Embedded track samples, by the names used in compositions.
+pub const SAMPLES: &[(&str, &[u8])] = &[
+    ("decay4", SAMPLE_BASELINE),
+    ("distant-piano-lo", SAMPLE_1_LO),
+    ("distant-piano-hi", SAMPLE_1_HI),
+    ("lone-beep-lo", SAMPLE_2_LO),
+    ("lone-beep-hi", SAMPLE_2_HI),
+    ("pulse-lo", SAMPLE_3_LO),
+    ("pulse-hi", SAMPLE_3_HI),
+];
+
+/// Command-line argument which runs the game without audio.
+pub const NO_AUDIO_ARG: &str = "--no-audio";
+
+/// The music for the first levels, as a [pattern] composition.
+pub const PIECE: &str = include_str!("../../assets/piece.txt");
+
+/// The music for the later levels, as a [pattern] composition.
+pub const PIECE_NIGHT: &str = include_str!("../../assets/piece-night.txt");
+
+/// The music for each of the hand-made [TILEMAPS](super::map::TILEMAPS),
+/// by index. Any other levels cycle through the same pieces.
+pub const LEVEL_PIECES: &[&str] = &[PIECE, PIECE, PIECE, PIECE_NIGHT, PIECE_NIGHT];
+
+/// Returns the sound effect played when an objective is cleared.
+pub fn objective_sound() -> Synth {
+    Synth::new(Waveform::Square, 660.0)
+        .with_sweep(1.5)
+        .with_envelope(0.005, 0.05, 0.3, 0.05, 0.15)
+        .with_lowpass(3000.0)
+        .with_volume(0.25)
+}
+
+/// Level the music and ambience duck to while the objective sound plays.
+pub const OBJECTIVE_DUCK: f32 = 0.4;
+
+/// Returns the loop hummed by objectives which haven't been reached.
+///
+/// The loop holds a whole number of cycles, so it repeats seamlessly.
+pub fn objective_hum() -> Synth {
+    Synth::new(Waveform::Sine, 220.0)
+        .with_envelope(0.0, 0.0, 1.0, 1.0, 0.0)
+        .with_volume(0.15)
+}
+
+/// The most objectives which hum at once in a level.
+pub const OBJECTIVE_HUMS: usize = 8;
+
+/// Returns the effects on the music bus, wavering like a worn record.
+pub fn music_effects() -> EffectChain {
+    EffectChain::new().with(Effect::WowFlutter {
+        wow: 0.0015,
+        rate: 0.4,
+        flutter: 0.0002,
+    })
+}
+
+/// Returns the effects the music is sent through to muffle it,
+/// as if it were playing in another room.
+pub fn music_muffle_effects() -> EffectChain {
+    EffectChain::new()
+        .with(Effect::LowPass { cutoff: 500.0 })
+        .with(Effect::Reverb {
+            room: 0.7,
+            mix: 0.4,
+        })
+}
+
+/// Returns the effects on the sound effects bus, crushed to fit the music.
+pub fn sfx_effects() -> EffectChain {
+    EffectChain::new().with(Effect::Bitcrush {
+        bits: 10,
+        downsample: 2,
+    })
+}
+
+/// Distances from the nearest cleared objective, in tiles, over
+/// which the music goes from clear to as muffled as it gets.
+pub const MUFFLE_DISTANCE: Range<f32> = 3.0..15.0;
+
+/// The most the music is muffled, away from every cleared objective.
+pub const MAX_MUFFLE: f32 = 0.8;
+
+/// Returns how muffled the music is `distance` tiles
+/// from the nearest cleared objective, if there is one.
+pub fn music_muffle(distance: Option<f32>) -> f32 {
+    let Some(distance) = distance else {
+        return MAX_MUFFLE;
+    };
+
+    let t = (distance - MUFFLE_DISTANCE.start) / (MUFFLE_DISTANCE.end - MUFFLE_DISTANCE.start);
+    t.clamp(0.0, 1.0) * MAX_MUFFLE
+}
+
+/// The highest velocity of a [Step], at which it plays at full volume.
+pub const MAX_VELOCITY: u8 = 127;
+
+/// The tempo of the game tracks, in beats (quarter notes) per minute.
 ///
 /// Lo-Fi beats tend to be around 60-90 BPM.
-pub const TEMPO_BPM: f32 = 80.0;
+pub const TEMPO_BPM: f32 = 64.0;
+
+/// The time signature of the game tracks.
+pub const TIME_SIGNATURE: TimeSignature = TimeSignature::new(4, 4);
+
+/// The number of bars in each phrase.
+pub const BARS_PER_PHRASE: u32 = 4;
+
+/// The most a piece can swing its off-beat steps by, as a fraction of a step.
+pub const MAX_SWING: f32 = 0.75;
+
+/// The velocity of steps added to a track by its mutation.
+pub const MUTATION_VELOCITY: u8 = MAX_VELOCITY / 2;
+
+/// The time taken to fade in a track unlocked by an objective, in seconds.
+pub const UNLOCK_FADE_SECS: f32 = 0.5;
 
 /// A musical piece comprised of one or more [Track]s.
+///
+/// Tracks play against a shared clock measured in beats, but each
+/// track loops over its own steps at its own [Resolution], so tracks
+/// of different lengths drift against each other (polymeter).
+///
+/// Beats are measured against an [AudioClock] by a [Scheduler],
+/// so the schedule doesn't drift with the frame rate.
 pub struct Piece {
+    /// The backend playing the piece's sounds.
+    backend: Rc<dyn AudioBackend>,
+
     /// The tracks comprising the piece.
-    ///
-    /// The first track is considered the "baseline" track,
-    /// and is used to determine the interval timing (with
-    /// respect to the piece's tempo) between steps for all tracks.
     tracks: Vec<Track>,
 
-    /// The playback status of each track in the piece,
-    /// where `true` means the track played during the
-    /// most recent update, and `false` means it did not.
-    track_states: Vec<bool>,
+    /// The steps played during the most recent update.
+    events: Vec<StepEvent>,
+
+    /// Steps handed to the backend which aren't due yet, in the order they're due.
+    pending: VecDeque<StepEvent>,
+
+    /// Subscriptions receiving the steps the piece plays.
+    subscriptions: Vec<Subscription>,
 
-    /// The time interval between each beat-step, in seconds.
-    interval_secs: f32,
-    interval_accumulator: f32,
+    /// The tempo the piece started at, in beats per minute.
+    tempo_bpm: f32,
 
-    /// The index of the next beat-step to play.
-    interval_step: usize,
+    /// The time signature of the piece.
+    time_signature: TimeSignature,
+
+    /// Schedules the piece's triggers against its clock.
+    scheduler: Scheduler,
+
+    /// The fraction of a step by which off-beat steps are delayed.
+    swing: f32,
+
+    /// Seed for rolling step probabilities.
+    seed: u64,
 }
 
 impl Piece {
-    /// Creates a new piece with a baseline track and tempo.
-    pub fn new(baseline_track: Track, tempo_bpm: f32) -> Self {
+    /// Creates a new piece with a baseline track and tempo,
+    /// playing sounds loaded by `backend`.
+    pub fn new(backend: Rc<dyn AudioBackend>, baseline_track: Track, tempo_bpm: f32) -> Self {
         let mut piece = Self {
+            backend,
             tracks: vec![baseline_track],
-            track_states: vec![false],
-            interval_secs: 0.0,
-            interval_accumulator: 0.0,
-            interval_step: 0,
+            events: vec![],
+            pending: VecDeque::new(),
+            subscriptions: vec![],
+            tempo_bpm,
+            time_signature: TIME_SIGNATURE,
+            scheduler: Scheduler::new(GameClock, tempo_bpm),
+            swing: 0.0,
+            seed: 0,
         };
 
         piece.set_tempo(tempo_bpm);
@@ -56,118 +218,1439 @@ impl Piece {
         piece
     }
 
+    /// Sets the time signature of the piece.
+    pub fn with_time_signature(mut self, time_signature: TimeSignature) -> Self {
+        self.time_signature = time_signature;
+        self
+    }
+
     /// Adds a track to the piece.
     pub fn with(mut self, track: Track) -> Self {
-        assert_eq!(track.steps.len(), self.tracks[0].steps.len());
         self.tracks.push(track);
-        self.track_states.push(false);
         self
     }
 
-    /// Changes the tempo of the piece.
+    /// Seeds the random rolls of steps with a [Step::probability],
+    /// so that the same seed always plays the same steps.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Measures the piece's beats with `clock`, rather than the game's time.
+    pub fn with_clock(mut self, clock: impl AudioClock + 'static) -> Self {
+        self.scheduler = Scheduler::new(clock, self.tempo_bpm);
+        self
+    }
+
+    /// Changes the swing of the piece, delaying every off-beat step
+    /// by `swing` (from `0.0`, straight, to [MAX_SWING]) of a step.
+    ///
+    /// A swing of roughly `0.33` gives a triplet feel.
+    pub fn set_swing(&mut self, swing: f32) {
+        self.swing = swing.clamp(0.0, MAX_SWING);
+    }
+
+    /// Changes the tempo of the piece immediately,
+    /// cancelling any tempo change in progress.
     pub fn set_tempo(&mut self, tempo_bpm: f32) {
-        // Calculate the time interval between each beat-step (quarter note) such that
-        // we'll play `tempo_bpm` beats per minute. There are four quarter notes
-        // per measure, and 8 measures per phrase, for a total of 32 steps per phrase.
-        let beats_per_phrase = self.tracks[0].steps.iter().filter(|&&s| s != 0).count() as f32;
-        let phrases_per_minute = tempo_bpm / beats_per_phrase;
-        let seconds_per_phrase = 60.0 / phrases_per_minute;
-        let interval_secs = seconds_per_phrase / 32.0;
-
-        eprintln!(
-            "Piece: {} BPM, {} steps, {} phrases/min, {} secs/phrase, {} secs/step",
-            tempo_bpm, beats_per_phrase, phrases_per_minute, seconds_per_phrase, interval_secs,
-        );
+        self.tempo_bpm = tempo_bpm;
+        self.scheduler.set_tempo(tempo_bpm);
+    }
+
+    /// Changes the tempo of the piece linearly across a window of
+    /// `beats`, starting from the tempo at the start of the window.
+    ///
+    /// Replaces any tempo change already scheduled or in progress.
+    pub fn ramp_tempo(&mut self, tempo_bpm: f32, beats: Range<f64>) {
+        self.scheduler.ramp_tempo(tempo_bpm, beats);
+    }
+
+    /// Returns the current tempo of the piece, in beats per minute.
+    pub fn tempo(&self) -> f32 {
+        self.scheduler.tempo() as f32
+    }
+
+    /// Returns the time signature of the piece.
+    pub fn time_signature(&self) -> TimeSignature {
+        self.time_signature
+    }
+
+    /// Returns the first `boundary` at or after `beat`,
+    /// measured in the piece's time signature.
+    pub fn next_boundary(&self, boundary: Boundary, beat: f64) -> f64 {
+        boundary.next_after(beat, self.time_signature)
+    }
 
-        self.interval_secs = interval_secs;
+    /// Returns a description of the piece's current timing.
+    pub fn timing(&self) -> Timing {
+        let beat = self.beat();
+        Timing {
+            tempo_bpm: self.tempo(),
+            time_signature: self.time_signature,
+            beat,
+            bar: (beat / self.time_signature.bar_beats()) as u64,
+            ramp: self.scheduler.ramp(),
+        }
     }
 
-    /// Changes the volume of a given track in the piece.
+    /// Changes the volume of a given track in the piece immediately.
     pub fn set_track_volume(&mut self, track_index: usize, volume: f32) {
         if let Some(track) = self.tracks.get_mut(track_index) {
             track.volume = volume;
+            track.ramp = None;
+        }
+    }
+
+    /// Silences every track in the piece immediately.
+    pub fn silence(&mut self) {
+        for track_index in 0..self.tracks.len() {
+            self.set_track_volume(track_index, 0.0);
+        }
+    }
+
+    /// Changes the volume of a given track in the piece, fading
+    /// so that the new volume is reached at the change's boundary.
+    ///
+    /// The change lands on the first boundary at least a fade's
+    /// duration away, so a track faded in at the next bar plays
+    /// its downbeat at full volume.
+    pub fn change_track_volume(&mut self, track_index: usize, change: VolumeChange) {
+        let fade_beats = self.beats_in(change.fade_secs);
+        let end_beat = self.next_boundary(change.boundary, self.beat() + fade_beats);
+
+        self.ramp_track_volume(track_index, change.volume, end_beat - fade_beats..end_beat);
+    }
+
+    /// Changes the volume of a given track in the piece,
+    /// fading linearly across a window of `beats`.
+    ///
+    /// Replaces any change already in progress on the track,
+    /// starting from the volume it would have reached.
+    pub fn ramp_track_volume(&mut self, track_index: usize, volume: f32, beats: Range<f64>) {
+        if let Some(track) = self.tracks.get_mut(track_index) {
+            track.ramp = Some(VolumeRamp {
+                from: track.volume_at(beats.start),
+                to: volume,
+                start_beat: beats.start,
+                end_beat: beats.end,
+            });
         }
     }
 
+    /// Returns the number of beats lasting `secs` seconds at the piece's current tempo.
+    pub fn beats_in(&self, secs: f32) -> f64 {
+        secs as f64 * self.scheduler.tempo() / 60.0
+    }
+
     /// Returns the number of tracks in the piece.
     pub fn track_count(&self) -> usize {
         self.tracks.len()
     }
 
-    /// Updates the piece, playing sounds as needed.
+    /// Returns the number of voices playing on the track at `track_index`,
+    /// or `None` if the track plays any number of sounds at once.
+    pub fn active_voices(&self, track_index: usize) -> Option<usize> {
+        let track = self.tracks.get(track_index)?;
+        track.voices_at(self.scheduler.now())
+    }
+
+    /// Returns the name of the track at `track_index`.
+    pub fn track_name(&self, track_index: usize) -> Option<&str> {
+        self.tracks
+            .get(track_index)
+            .map(|track| track.name.as_str())
+    }
+
+    /// Returns the current beat of the piece,
+    /// including the fraction of the beat elapsed.
+    pub fn beat(&self) -> f64 {
+        self.scheduler.beat()
+    }
+
+    /// Returns the clock time, in seconds, at which `beat` plays.
+    pub fn time_at(&self, beat: f64) -> f64 {
+        self.scheduler.time_at(beat)
+    }
+
+    /// Returns the fraction of the current beat which has
+    /// elapsed, from `0.0` (on the beat) up to `1.0`.
+    pub fn beat_phase(&self) -> f32 {
+        self.scheduler.beat_phase()
+    }
+
+    /// Returns the fraction of the current bar which has
+    /// elapsed, from `0.0` (on the downbeat) up to `1.0`.
+    pub fn bar_phase(&self) -> f32 {
+        (self.beat() / self.time_signature.bar_beats()).fract() as f32
+    }
+
+    /// Sends every step the piece plays from now on to `subscription`.
+    pub fn subscribe(&mut self, subscription: &Subscription) {
+        self.subscriptions.push(subscription.clone());
+    }
+
+    /// Updates the piece, handing upcoming sounds to the backend.
+    ///
+    /// Sounds are handed over as soon as they're scheduled, up to the
+    /// scheduler's lookahead before they're due, with their exact time.
+    ///
+    /// Returns the steps which became due during this update,
+    /// which are also sent to the piece's subscriptions.
+    pub fn update(&mut self) -> &[StepEvent] {
+        self.events.clear();
+
+        // Settle any volume changes which have finished.
+        let beat = self.beat();
+        for track in &mut self.tracks {
+            if track.ramp.is_some_and(|ramp| beat >= ramp.end_beat) {
+                track.volume = track.volume_at(beat);
+                track.ramp = None;
+            }
+        }
+
+        if let Some(window) = self.scheduler.advance() {
+            self.schedule(window);
+        }
+
+        // Hand the new triggers to the backend, at their track's volume.
+        for trigger in self.scheduler.drain_queued() {
+            let track = &self.tracks[trigger.track];
+            let volume = track.volume_at(trigger.beat);
+            if volume <= 0.0 {
+                continue;
+            }
+
+            let volume = volume * trigger.velocity as f32 / MAX_VELOCITY as f32;
+            let pitch = trigger.pitch;
+            let track = &mut self.tracks[trigger.track];
+            let (sound, stolen) = track.start_voice(pitch, trigger.time, volume);
+            if let Some(stolen) = stolen {
+                self.backend.stop_at(stolen, trigger.time);
+            }
+            self.backend.play(
+                sound,
+                PlayParams {
+                    time: trigger.time,
+                    volume,
+                    looped: false,
+                },
+            );
+
+            let event = StepEvent {
+                track: trigger.track,
+                track_name: track.name.clone(),
+                step: trigger.step,
+                fill: trigger.fill,
+                bar: (trigger.beat / self.time_signature.bar_beats()) as u64,
+                beat: trigger.beat,
+                velocity: trigger.velocity,
+                pitch,
+                volume,
+                time: trigger.time,
+            };
+            self.pending.push_back(event);
+        }
+
+        // Report the steps which are now due.
+        let now = self.scheduler.now();
+        while let Some(event) = self.pending.pop_front_if(|event| event.time <= now) {
+            for subscription in &self.subscriptions {
+                subscription.push(&event);
+            }
+            self.events.push(event);
+        }
+
+        &self.events
+    }
+
+    /// Queues a trigger for every sound starting within `window`.
     ///
-    /// Returns a slice of booleans indicating which tracks
-    /// played during this update, where each index corresponds
-    /// to the track at the same index in [Self::tracks].
-    pub fn update(&mut self, delta_time: f32) -> &[bool] {
-        self.interval_accumulator += delta_time;
+    /// Muted tracks are scheduled too, since a
+    /// volume ramp may unmute them within the window.
+    fn schedule(&mut self, window: Range<f64>) {
+        let (start_beats, end_beats) = (window.start, window.end);
+
+        let mut triggers = vec![];
+        for (i, track) in self.tracks.iter().enumerate() {
+            if track.steps.is_empty() {
+                continue;
+            }
+
+            let steps_per_beat = track.resolution.steps_per_beat();
 
-        // Clear previous track states.
-        self.track_states.fill(false);
+            // Swing and repeats can delay a step's triggers by up to
+            // the swing plus a step, so also check every step early
+            // enough to still have triggers due during this update.
+            let look_back = MAX_SWING as f64 + 1.0;
+            let first_step = (start_beats * steps_per_beat - look_back).floor().max(0.0) as u64;
+            let end_step = (end_beats * steps_per_beat).ceil() as u64;
 
-        // Play any tracks that have a sound at the current step.
-        while self.interval_accumulator >= self.interval_secs {
-            for (i, track) in self.tracks.iter().enumerate() {
-                if track.volume > 0.0 && track.steps[self.interval_step] != 0 {
-                    macroquad::audio::play_sound(
-                        &track.sound,
-                        macroquad::audio::PlaySoundParams {
-                            looped: false,
-                            volume: track.volume,
-                        },
-                    );
+            for absolute_step in first_step..end_step {
+                let (step_index, fill, step) = self.step_at(i, absolute_step);
+                if step.velocity == 0 {
+                    continue;
+                }
+
+                // Delay off-beat steps by the swing.
+                let mut step_start = absolute_step as f64;
+                if absolute_step % 2 == 1 {
+                    step_start += self.swing as f64;
+                }
 
-                    self.track_states[i] = true;
+                // Repeats evenly subdivide the step.
+                let repeats = step.repeats.max(1);
+                let beats: Vec<f64> = (0..repeats)
+                    .map(|r| (step_start + r as f64 / repeats as f64) / steps_per_beat)
+                    .filter(|beat| *beat >= start_beats && *beat < end_beats)
+                    .collect();
+                if beats.is_empty() || !self.roll(i, absolute_step, step.probability) {
+                    continue;
                 }
+
+                triggers.extend(
+                    beats
+                        .into_iter()
+                        .map(|beat| (beat, i, step_index, fill, step.velocity, step.pitch)),
+                );
+            }
+        }
+
+        for (beat, track, step, fill, velocity, pitch) in triggers {
+            self.scheduler
+                .push(beat, track, step, fill, velocity, pitch);
+        }
+    }
+
+    /// Returns the step played at `absolute_step` by the track
+    /// at `track_index`, following the track's variations, along with
+    /// its index and whether it's from the track's fill.
+    ///
+    /// Bars due a fill play the fill. Otherwise the track plays its
+    /// composed step, which its mutation may flip between a rest and
+    /// a hit. Mutations are rolled once per phrase, so each phrase
+    /// repeats its own variation of the pattern.
+    fn step_at(&self, track_index: usize, absolute_step: u64) -> (usize, bool, Step) {
+        let track = &self.tracks[track_index];
+        let steps_per_bar = self.time_signature.bar_beats() * track.resolution.steps_per_beat();
+        let bar = (absolute_step as f64 / steps_per_bar).floor() as u64;
+
+        if let Some(fill) = &track.fill
+            && (bar + 1).is_multiple_of(fill.every_bars as u64)
+        {
+            let bar_start = (bar as f64 * steps_per_bar).ceil() as u64;
+            let fill_index = ((absolute_step - bar_start) % fill.steps.len() as u64) as usize;
+            return (fill_index, true, fill.steps[fill_index]);
+        }
+
+        let step_index = (absolute_step % track.steps.len() as u64) as usize;
+        let step = track.steps[step_index];
+        if track.mutation <= 0.0 {
+            return (step_index, false, step);
+        }
+
+        let phrase = bar / BARS_PER_PHRASE as u64;
+        let seed = !self.seed ^ (track_index as u64).rotate_left(32) ^ phrase.rotate_left(16);
+        let step = if !Rng::new(seed ^ step_index as u64).chance(track.mutation) {
+            step
+        } else if step.velocity == 0 {
+            Step {
+                velocity: MUTATION_VELOCITY,
+                ..step
             }
+        } else {
+            Step::REST.with_pitch(step.pitch)
+        };
+        (step_index, false, step)
+    }
+
+    /// Returns true if the step at `absolute_step` in the track
+    /// at `track_index` should play, given its `probability`.
+    ///
+    /// Rolls are derived from the piece's seed and the step's
+    /// position, so every trigger of a repeated step shares one roll.
+    fn roll(&self, track_index: usize, absolute_step: u64, probability: f32) -> bool {
+        if probability >= 1.0 {
+            return true;
+        }
+
+        let seed = self.seed ^ (track_index as u64).rotate_left(32) ^ absolute_step;
+        Rng::new(seed).chance(probability)
+    }
+}
+
+/// A musical boundary on which a [VolumeChange] can land.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Boundary {
+    /// Start immediately.
+    #[default]
+    Immediate,
+
+    /// Start at the next beat.
+    Beat,
+
+    /// Start at the next bar.
+    Bar,
+
+    /// Start at the next phrase of [BARS_PER_PHRASE] bars.
+    Phrase,
+}
+
+impl Boundary {
+    /// Returns the number of beats between each boundary in
+    /// `time_signature`, or `None` for [Boundary::Immediate].
+    pub fn beats(&self, time_signature: TimeSignature) -> Option<f64> {
+        match self {
+            Boundary::Immediate => None,
+            Boundary::Beat => Some(1.0),
+            Boundary::Bar => Some(time_signature.bar_beats()),
+            Boundary::Phrase => Some(time_signature.bar_beats() * BARS_PER_PHRASE as f64),
+        }
+    }
+
+    /// Returns the first boundary at or after `beat` in `time_signature`.
+    pub fn next_after(&self, beat: f64, time_signature: TimeSignature) -> f64 {
+        match self.beats(time_signature) {
+            Some(beats) => (beat / beats).ceil() * beats,
+            None => beat,
+        }
+    }
+}
+
+/// The number of beats in each bar of a [Piece], and the length of each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeSignature {
+    /// The number of beats in each bar.
+    pub beats: u32,
+
+    /// The note value of each beat, such as `4` for quarter notes.
+    pub unit: u32,
+}
+
+impl TimeSignature {
+    /// Returns a new time signature of `beats` beats of `unit` notes.
+    pub const fn new(beats: u32, unit: u32) -> Self {
+        Self { beats, unit }
+    }
+
+    /// Parses a time signature like `3/4` or `7/8`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (beats, unit) = s.split_once('/')?;
+        let (beats, unit): (u32, u32) = (beats.parse().ok()?, unit.parse().ok()?);
+        if beats == 0 || !unit.is_power_of_two() {
+            return None;
+        }
+
+        Some(Self::new(beats, unit))
+    }
+
+    /// Returns the length of each bar, in (quarter note) beats
+    /// of the piece's tempo. A bar of `7/8` lasts `3.5` beats.
+    pub fn bar_beats(&self) -> f64 {
+        self.beats as f64 * 4.0 / self.unit as f64
+    }
+}
+
+impl fmt::Display for TimeSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.beats, self.unit)
+    }
+}
+
+/// A description of a [Piece]'s timing at a moment, from [Piece::timing].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timing {
+    /// The tempo, in beats per minute.
+    pub tempo_bpm: f32,
+
+    /// The time signature.
+    pub time_signature: TimeSignature,
+
+    /// The current beat, including the fraction of the beat elapsed.
+    pub beat: f64,
+
+    /// The current bar, counting from `0`.
+    pub bar: u64,
+
+    /// The change of tempo in progress or scheduled to start, if there is one.
+    pub ramp: Option<TempoRamp>,
+}
+
+impl Timing {
+    /// Returns the length of each beat, in seconds.
+    pub fn secs_per_beat(&self) -> f32 {
+        60.0 / self.tempo_bpm
+    }
+
+    /// Returns the length of each bar, in seconds.
+    pub fn secs_per_bar(&self) -> f32 {
+        self.secs_per_beat() * self.time_signature.bar_beats() as f32
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1} BPM in {} ({:.3} secs/beat), bar {}",
+            self.tempo_bpm,
+            self.time_signature,
+            self.secs_per_beat(),
+            self.bar
+        )?;
+        if let Some(ramp) = self.ramp {
+            write!(
+                f,
+                ", ramping to {:.1} BPM by beat {:.1}",
+                ramp.to_bpm, ramp.end_beat
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A change to a [Track]'s volume, applied with [Piece::change_track_volume].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VolumeChange {
+    /// The volume to change to, from `0.0` (silent) to `1.0` (full volume).
+    pub volume: f32,
+
+    /// The time taken to fade to the new volume, in seconds.
+    pub fade_secs: f32,
+
+    /// The boundary at which the new volume is reached.
+    pub boundary: Boundary,
+}
+
+impl VolumeChange {
+    /// Returns a new change to `volume`, which applies immediately.
+    pub fn new(volume: f32) -> Self {
+        Self {
+            volume,
+            fade_secs: 0.0,
+            boundary: Boundary::Immediate,
+        }
+    }
+
+    /// Sets the time taken to fade to the new volume, in seconds.
+    pub fn with_fade(mut self, fade_secs: f32) -> Self {
+        self.fade_secs = fade_secs.max(0.0);
+        self
+    }
+
+    /// Sets the boundary at which the new volume is reached.
+    pub fn with_boundary(mut self, boundary: Boundary) -> Self {
+        self.boundary = boundary;
+        self
+    }
+}
+
+/// An in-progress linear change to a [Track]'s volume.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct VolumeRamp {
+    /// Volume at the start of the ramp.
+    from: f32,
+
+    /// Volume at the end of the ramp.
+    to: f32,
+
+    /// Beat at which the ramp starts.
+    start_beat: f64,
+
+    /// Beat at which the ramp ends.
+    end_beat: f64,
+}
+
+/// A single step in a [Track].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Step {
+    /// The velocity at which the step plays, from `0`
+    /// (silent) to [MAX_VELOCITY] (full volume).
+    pub velocity: u8,
+
+    /// The chance that the step plays each time it's
+    /// reached, from `0.0` (never) to `1.0` (always).
+    pub probability: f32,
+
+    /// The number of times the step's sound is triggered,
+    /// evenly spaced across the step ("ratchets").
+    pub repeats: u8,
 
-            self.interval_accumulator -= self.interval_secs;
+    /// The pitch at which the step's sound plays, in
+    /// semitones relative to the sound's original pitch.
+    pub pitch: i8,
+}
+
+impl Step {
+    /// A silent step.
+    pub const REST: Step = Step::new(0);
 
-            // @caer: todo: Track steps are pinned to the baseline track.
-            //        What happens when tracks have different lengths?
-            self.interval_step = (self.interval_step + 1) % self.tracks[0].steps.len();
+    /// Returns a new step which always plays once at `velocity`.
+    pub const fn new(velocity: u8) -> Self {
+        Self {
+            velocity,
+            probability: 1.0,
+            repeats: 1,
+            pitch: 0,
+        }
+    }
+
+    /// Sets the chance that the step plays.
+    pub const fn with_probability(mut self, probability: f32) -> Self {
+        self.probability = probability;
+        self
+    }
+
+    /// Sets the number of times the step's sound is triggered.
+    pub const fn with_repeats(mut self, repeats: u8) -> Self {
+        self.repeats = repeats;
+        self
+    }
+
+    /// Sets the pitch at which the step's sound plays, in semitones.
+    pub const fn with_pitch(mut self, pitch: i8) -> Self {
+        self.pitch = pitch;
+        self
+    }
+}
 
-            // Clamp floating point error.
-            if self.interval_accumulator < 0.0 {
-                self.interval_accumulator = 0.0;
+/// Returns `pulses` hits spread as evenly as possible across
+/// `steps` steps (a Euclidean rhythm), rotated left by `rotation`.
+///
+/// For example, 3 pulses across 8 steps gives `x..x..x.`.
+pub fn euclidean(pulses: usize, steps: usize, rotation: usize) -> Vec<Step> {
+    (0..steps)
+        .map(|i| {
+            let i = (i + rotation) % steps;
+            if (i * pulses) % steps < pulses.min(steps) {
+                Step::new(MAX_VELOCITY)
+            } else {
+                Step::REST
             }
+        })
+        .collect()
+}
+
+/// Converts a velocity into a step.
+impl From<u8> for Step {
+    fn from(velocity: u8) -> Self {
+        Step::new(velocity.min(MAX_VELOCITY))
+    }
+}
+
+/// The duration of each step in a [Track], relative to a beat.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Resolution {
+    /// One step per beat.
+    Quarter,
+
+    /// Two steps per beat.
+    Eighth,
+
+    /// Three steps per beat.
+    EighthTriplet,
+
+    /// Four steps per beat.
+    #[default]
+    Sixteenth,
+
+    /// Six steps per beat.
+    SixteenthTriplet,
+}
+
+impl Resolution {
+    /// Returns the number of steps in each beat.
+    pub fn steps_per_beat(&self) -> f64 {
+        match self {
+            Resolution::Quarter => 1.0,
+            Resolution::Eighth => 2.0,
+            Resolution::EighthTriplet => 3.0,
+            Resolution::Sixteenth => 4.0,
+            Resolution::SixteenthTriplet => 6.0,
         }
+    }
+}
+
+/// How a [Track] with limited voices chooses which
+/// voice to cut off when every voice is playing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum VoiceStealing {
+    /// Cut off the voice which started first.
+    #[default]
+    Oldest,
 
-        &self.track_states
+    /// Cut off the voice which started at the lowest volume.
+    Quietest,
+}
+
+impl VoiceStealing {
+    /// Returns the voice stealing with the given name, as used in compositions.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "oldest" => Some(VoiceStealing::Oldest),
+            "quietest" => Some(VoiceStealing::Quietest),
+            _ => None,
+        }
     }
 }
 
+/// A sound playing in one of a [Track]'s voices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Voice {
+    /// The sound playing.
+    sound: SoundId,
+
+    /// Clock time at which the sound started, in seconds.
+    start: f64,
+
+    /// Clock time at which the sound finishes, in seconds.
+    end: f64,
+
+    /// Volume the sound started at.
+    volume: f32,
+}
+
+/// One of a [Track]'s voices, which plays a single sound at a time.
+///
+/// Each voice has its own copy of the track's sounds, so
+/// stopping a voice doesn't stop any of the track's other voices.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct VoiceSlot {
+    /// The voice's copy of each of the track's sounds, by
+    /// pitch in semitones, with their durations in seconds.
+    sounds: BTreeMap<i8, (SoundId, f64)>,
+
+    /// The sound the voice is playing, if any.
+    playing: Option<Voice>,
+}
+
 /// A single track within a [Piece], representing a single
 /// instrument or sound source.
 ///
-/// @caer: todo: Currently, tracks define a single "phrase"
-///        of 32 beat-steps (8 measures of 4 beat-steps).
+/// Tracks loop over any number of steps, independently
+/// of the other tracks in their piece.
 pub struct Track {
+    /// Name of the track, used to identify it in [StepEvent]s.
+    name: String,
+
     /// The sound to play at each step.
-    sound: macroquad::audio::Sound,
+    sound: SoundId,
+
+    /// Pitched copies of the sound, by their pitch in semitones.
+    pitched_sounds: BTreeMap<i8, SoundId>,
 
     /// List of steps (beat subdivions) in the track.
-    ///
-    /// Each step containing a `0` means no sound
-    /// should be played at that step, while a `1`
-    /// means the sound should be played.
-    steps: [u8; 32],
+    steps: Vec<Step>,
+
+    /// The duration of each step.
+    resolution: Resolution,
 
     /// The track's relative volume in a piece, ranging
     /// from `0.0` (silent) to `1.0` (full volume).
     volume: f32,
+
+    /// The change to the track's volume in progress, if any.
+    ramp: Option<VolumeRamp>,
+
+    /// The track's voices, or none if it plays any
+    /// number of sounds at once.
+    voices: Vec<VoiceSlot>,
+
+    /// How the track cuts off a voice when every voice is playing.
+    voice_stealing: VoiceStealing,
+
+    /// The chance that each step is flipped between a rest
+    /// and a hit, rolled afresh each phrase.
+    mutation: f32,
+
+    /// The fill the track plays every few bars, if any.
+    fill: Option<Fill>,
 }
 
-impl Track {
-    pub async fn new(sound_bytes: &[u8], steps: [u8; 32]) -> Self {
-        let sound = macroquad::audio::load_sound_from_bytes(sound_bytes)
-            .await
-            .unwrap();
+/// Steps a [Track] plays instead of its own, in the
+/// last bar of every `every_bars` bars.
+#[derive(Clone, Debug, PartialEq)]
+struct Fill {
+    every_bars: u32,
+    steps: Vec<Step>,
+}
 
+impl Track {
+    /// Creates a new, muted track playing `sound`, which must
+    /// be loaded by the backend of the piece the track is added to.
+    ///
+    /// Steps can be given as velocities, where
+    /// `0` means no sound is played at that step.
+    pub fn new<S: Into<Step>>(sound: SoundId, steps: impl IntoIterator<Item = S>) -> Self {
         Self {
+            name: String::new(),
             sound,
-            steps,
+            pitched_sounds: BTreeMap::new(),
+            steps: steps.into_iter().map(Into::into).collect(),
+            resolution: Resolution::default(),
             volume: 0.0,
+            ramp: None,
+            voices: vec![],
+            voice_stealing: VoiceStealing::default(),
+            mutation: 0.0,
+            fill: None,
+        }
+    }
+
+    /// Sets the name of the track.
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Adds a copy of the track's sound pitched by `pitch` semitones,
+    /// played by steps with that pitch instead of the original sound.
+    ///
+    /// Steps with a pitch which has no copy play the original sound.
+    pub fn with_pitched_sound(mut self, pitch: i8, sound: SoundId) -> Self {
+        self.pitched_sounds.insert(pitch, sound);
+        self
+    }
+
+    /// Adds a voice to the track, limiting how many of its sounds play at once
+    /// to its number of voices. Tracks without voices play any number.
+    ///
+    /// Each voice needs its own copy of the track's `sounds`, given
+    /// by their pitch in semitones, with their durations in seconds.
+    /// Without a copy of the root (pitch `0`) sound, the voice plays the
+    /// track's own sound, taken to last as long as its longest copy.
+    pub fn with_voice(mut self, sounds: impl IntoIterator<Item = (i8, SoundId, f64)>) -> Self {
+        let mut sounds: BTreeMap<i8, (SoundId, f64)> = sounds
+            .into_iter()
+            .map(|(pitch, sound, duration)| (pitch, (sound, duration)))
+            .collect();
+        let longest = sounds.values().map(|&(_, duration)| duration);
+        let duration = longest.fold(0.0, f64::max);
+        sounds.entry(0).or_insert((self.sound, duration));
+
+        self.voices.push(VoiceSlot {
+            sounds,
+            playing: None,
+        });
+        self
+    }
+
+    /// Sets how the track cuts off a voice when every voice is playing.
+    pub fn with_voice_stealing(mut self, voice_stealing: VoiceStealing) -> Self {
+        self.voice_stealing = voice_stealing;
+        self
+    }
+
+    /// Sets the chance that each of the track's steps is flipped between
+    /// a rest and a hit, from `0.0` (never) to `1.0` (always).
+    ///
+    /// Each phrase flips a different set of steps, so the track varies
+    /// over long play sessions, while its pattern still anchors it.
+    pub fn with_mutation(mut self, probability: f32) -> Self {
+        self.mutation = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets `steps` to play instead of the track's own steps in
+    /// the last bar of every `every_bars` bars, like a drum fill.
+    ///
+    /// Fills shorter than a bar repeat to fill it.
+    pub fn with_fill(mut self, every_bars: u32, steps: impl IntoIterator<Item = Step>) -> Self {
+        let steps: Vec<Step> = steps.into_iter().collect();
+        self.fill = (every_bars > 0 && !steps.is_empty()).then_some(Fill { every_bars, steps });
+        self
+    }
+
+    /// Returns the distinct pitches of the track's steps, including its fill.
+    pub fn pitches(&self) -> BTreeSet<i8> {
+        let fill = self.fill.iter().flat_map(|fill| &fill.steps);
+        self.steps
+            .iter()
+            .chain(fill)
+            .map(|step| step.pitch)
+            .collect()
+    }
+
+    /// Returns the sound to play at `pitch`.
+    fn sound_at(&self, pitch: i8) -> SoundId {
+        self.pitched_sounds
+            .get(&pitch)
+            .copied()
+            .unwrap_or(self.sound)
+    }
+
+    /// Returns the sound the voice at `index` plays at `pitch`, with its duration.
+    ///
+    /// Like [Track::sound_at], the voice plays its own copy at `pitch` if it has
+    /// one, or else the track's pitched copy, or else its copy of the root sound.
+    /// Pitched copies shared with the track are taken to last as long as the root.
+    fn voice_sound_at(&self, index: usize, pitch: i8) -> (SoundId, f64) {
+        let sounds = &self.voices[index].sounds;
+        let (root, duration) = sounds[&0];
+        match (sounds.get(&pitch), self.pitched_sounds.get(&pitch)) {
+            (Some(&own), _) => own,
+            (None, Some(&shared)) => (shared, duration),
+            (None, None) => (root, duration),
+        }
+    }
+
+    /// Starts a voice playing `pitch` at `time` and `volume`, returning the
+    /// sound to play, and the sound of any voice it cut off.
+    ///
+    /// Tracks without voices play every sound, and never cut any off.
+    fn start_voice(&mut self, pitch: i8, time: f64, volume: f32) -> (SoundId, Option<SoundId>) {
+        if self.voices.is_empty() {
+            return (self.sound_at(pitch), None);
+        }
+
+        // Use a free voice, or else steal one.
+        let free = self
+            .voices
+            .iter()
+            .position(|slot| slot.playing.is_none_or(|voice| voice.end <= time));
+        let index = free.unwrap_or_else(|| {
+            let playing = self.voices.iter().map(|slot| slot.playing.unwrap());
+            let stolen = match self.voice_stealing {
+                VoiceStealing::Oldest => playing
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| a.start.total_cmp(&b.start)),
+                VoiceStealing::Quietest => playing.enumerate().min_by(|(_, a), (_, b)| {
+                    a.volume
+                        .total_cmp(&b.volume)
+                        .then(a.start.total_cmp(&b.start))
+                }),
+            };
+            stolen.unwrap().0
+        });
+
+        let (sound, duration) = self.voice_sound_at(index, pitch);
+        let slot = &mut self.voices[index];
+        let stolen = free.is_none().then(|| slot.playing.unwrap().sound);
+        slot.playing = Some(Voice {
+            sound,
+            start: time,
+            end: time + duration,
+            volume,
+        });
+
+        (sound, stolen)
+    }
+
+    /// Returns the number of the track's voices playing at `time`,
+    /// or `None` if the track doesn't limit its voices.
+    fn voices_at(&self, time: f64) -> Option<usize> {
+        if self.voices.is_empty() {
+            return None;
+        }
+
+        let playing = self.voices.iter().filter_map(|slot| slot.playing);
+        Some(
+            playing
+                .filter(|voice| voice.start <= time && time < voice.end)
+                .count(),
+        )
+    }
+
+    /// Sets the duration of each step in the track.
+    pub fn with_resolution(mut self, resolution: Resolution) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    /// Returns the track's volume at `beat`, following any change in progress.
+    fn volume_at(&self, beat: f64) -> f32 {
+        let Some(ramp) = self.ramp else {
+            return self.volume;
+        };
+
+        if beat >= ramp.end_beat {
+            ramp.to
+        } else if beat <= ramp.start_beat {
+            ramp.from
+        } else {
+            let t = (beat - ramp.start_beat) / (ramp.end_beat - ramp.start_beat);
+            ramp.from + (ramp.to - ramp.from) * t as f32
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::backend::{AudioEvent, RecordingBackend};
+    use super::scheduler::FakeClock;
+    use super::*;
+
+    /// Tempo at which each sixteenth step lasts exactly an eighth of a second.
+    const TEMPO_BPM: f32 = 120.0;
+
+    /// Returns a piece playing `steps` on a single track at full
+    /// volume, logging its sounds to `backend` and measuring
+    /// time with `clock`.
+    fn piece(backend: &RecordingBackend, clock: &FakeClock, steps: Vec<Step>) -> Piece {
+        let track = Track::new(SoundId(0), steps);
+        let mut piece =
+            Piece::new(Rc::new(backend.clone()), track, TEMPO_BPM).with_clock(clock.clone());
+        piece.set_track_volume(0, 1.0);
+        piece
+    }
+
+    /// Updates `piece` at 60 frames per second for the first
+    /// `secs` seconds, returning the time and volume of every
+    /// sound due within them.
+    fn play(
+        piece: &mut Piece,
+        backend: &RecordingBackend,
+        clock: &FakeClock,
+        secs: f64,
+    ) -> Vec<(f64, f32)> {
+        for frame in 0..(secs * 60.0).round() as u64 {
+            clock.set(frame as f64 / 60.0);
+            piece.update();
+        }
+
+        backend
+            .take_events()
+            .into_iter()
+            .filter_map(|(time, event)| match event {
+                AudioEvent::Play { params, .. } if time < secs => Some((time, params.volume)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn steps_play_at_their_velocity() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let steps = vec![Step::new(127), Step::new(64), Step::REST, Step::new(32)];
+        let mut piece = piece(&backend, &clock, steps);
+
+        let played = play(&mut piece, &backend, &clock, 0.5);
+        assert_eq!(
+            played,
+            vec![(0.0, 1.0), (0.125, 64.0 / 127.0), (0.375, 32.0 / 127.0)]
+        );
+    }
+
+    #[test]
+    fn swing_delays_off_beat_steps() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let mut piece = piece(&backend, &clock, vec![Step::new(127); 4]);
+        piece.set_swing(0.5);
+
+        let times: Vec<f64> = play(&mut piece, &backend, &clock, 0.5)
+            .into_iter()
+            .map(|(time, _)| time)
+            .collect();
+        assert_eq!(times, vec![0.0, 0.1875, 0.25, 0.4375]);
+    }
+
+    #[test]
+    fn ratchets_subdivide_their_step() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let steps = vec![
+            Step::new(127).with_repeats(2),
+            Step::REST,
+            Step::new(64).with_repeats(4),
+            Step::REST,
+        ];
+        let mut piece = piece(&backend, &clock, steps);
+
+        let played = play(&mut piece, &backend, &clock, 0.5);
+        let quiet = 64.0 / 127.0;
+        assert_eq!(
+            played,
+            vec![
+                (0.0, 1.0),
+                (0.0625, 1.0),
+                (0.25, quiet),
+                (0.28125, quiet),
+                (0.3125, quiet),
+                (0.34375, quiet),
+            ]
+        );
+    }
+
+    #[test]
+    fn swung_ratchets_span_the_next_step() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let steps = vec![Step::REST, Step::new(127).with_repeats(4)];
+        let mut piece = piece(&backend, &clock, steps);
+        piece.set_swing(MAX_SWING);
+
+        // The off-beat step starts three quarters of a step late,
+        // so its last repeats land during the following step.
+        let times: Vec<f64> = play(&mut piece, &backend, &clock, 0.5)
+            .into_iter()
+            .map(|(time, _)| time)
+            .collect();
+        assert_eq!(times, vec![0.21875, 0.25, 0.28125, 0.3125, 0.46875]);
+    }
+
+    #[test]
+    fn rolls_depend_only_on_seed_and_step() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let rolls = |seed: u64| -> Vec<bool> {
+            let piece = piece(&backend, &clock, vec![Step::new(127)]).with_seed(seed);
+            (0..64).map(|step| piece.roll(0, step, 0.5)).collect()
+        };
+
+        // The same seed always decides the same way, unlike another seed.
+        assert_eq!(rolls(7), rolls(7));
+        assert_ne!(rolls(7), rolls(8));
+
+        // Rolls don't depend on when they're made, either.
+        let piece = piece(&backend, &clock, vec![Step::new(127)]).with_seed(7);
+        let backwards: Vec<bool> = (0..64).rev().map(|step| piece.roll(0, step, 0.5)).collect();
+        assert_eq!(backwards.into_iter().rev().collect::<Vec<_>>(), rolls(7));
+    }
+
+    /// Returns `steps` written as hits (`x`) and rests (`.`).
+    fn hits(steps: &[Step]) -> String {
+        steps
+            .iter()
+            .map(|step| if step.velocity > 0 { 'x' } else { '.' })
+            .collect()
+    }
+
+    #[test]
+    fn euclidean_rhythms_spread_their_pulses() {
+        assert_eq!(hits(&euclidean(3, 8, 0)), "x..x..x.");
+        assert_eq!(hits(&euclidean(3, 8, 1)), "..x..x.x");
+        assert_eq!(hits(&euclidean(4, 16, 0)), "x...x...x...x...");
+        assert_eq!(hits(&euclidean(0, 4, 0)), "....");
+        assert_eq!(hits(&euclidean(6, 4, 0)), "xxxx");
+    }
+
+    #[test]
+    fn mutations_repeat_within_a_phrase() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let track = Track::new(SoundId(0), euclidean(5, 16, 0)).with_mutation(0.5);
+        let piece = Piece::new(Rc::new(backend.clone()), track, TEMPO_BPM)
+            .with_clock(clock.clone())
+            .with_seed(11);
+
+        // Returns the steps played in the bar at `bar`.
+        let bar = |bar: u64| -> String {
+            let steps: Vec<Step> = (bar * 16..(bar + 1) * 16)
+                .map(|step| piece.step_at(0, step).2)
+                .collect();
+            hits(&steps)
+        };
+
+        // Every bar of a phrase plays the same variation,
+        // which differs from the pattern and the next phrase's.
+        let phrase = BARS_PER_PHRASE as u64;
+        let first = bar(0);
+        assert!((1..phrase).all(|i| bar(i) == first));
+        assert_ne!(first, hits(&euclidean(5, 16, 0)));
+        assert_ne!(bar(phrase), first);
+        assert!((phrase + 1..2 * phrase).all(|i| bar(i) == bar(phrase)));
+    }
+
+    #[test]
+    fn certain_and_impossible_steps_ignore_the_roll() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let steps = vec![
+            Step::new(127).with_probability(1.0),
+            Step::new(64).with_probability(0.0),
+        ];
+        let mut piece = piece(&backend, &clock, steps).with_seed(3);
+
+        // Only the certain step plays, every time it's reached.
+        let played = play(&mut piece, &backend, &clock, 2.0);
+        assert_eq!(played.len(), 8);
+        assert!(played.iter().all(|&(_, volume)| volume == 1.0));
+    }
+
+    #[test]
+    fn tracks_of_different_lengths_cycle_independently() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let three = Track::new(SoundId(0), [127, 0, 0]);
+        let four = Track::new(SoundId(1), [127, 0, 0, 0]);
+        let mut piece = Piece::new(Rc::new(backend.clone()), three, TEMPO_BPM)
+            .with(four)
+            .with_clock(clock.clone());
+        piece.set_track_volume(0, 1.0);
+        piece.set_track_volume(1, 1.0);
+
+        // Play three beats, after which both tracks line up again.
+        let mut played: [Vec<f64>; 2] = Default::default();
+        for frame in 0..(1.6 * 60.0) as u64 {
+            clock.set(frame as f64 / 60.0);
+            for event in piece.update() {
+                played[event.track].push(event.time);
+            }
         }
+
+        assert_eq!(played[0], vec![0.0, 0.375, 0.75, 1.125, 1.5]);
+        assert_eq!(played[1], vec![0.0, 0.5, 1.0, 1.5]);
+    }
+
+    /// Updates `piece` at clock time `time`.
+    fn update_at(piece: &mut Piece, clock: &FakeClock, time: f64) {
+        clock.set(time);
+        piece.update();
+    }
+
+    #[test]
+    fn volume_changes_land_on_their_boundary() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let mut piece = piece(&backend, &clock, vec![Step::new(127)]);
+        piece.set_track_volume(0, 0.0);
+        update_at(&mut piece, &clock, 0.0);
+
+        // Fade in over a beat, landing on the next bar.
+        update_at(&mut piece, &clock, 0.6);
+        let change = VolumeChange::new(1.0)
+            .with_fade(0.5)
+            .with_boundary(Boundary::Bar);
+        piece.change_track_volume(0, change);
+
+        let track = &piece.tracks[0];
+        assert_eq!(
+            track.ramp,
+            Some(VolumeRamp {
+                from: 0.0,
+                to: 1.0,
+                start_beat: 3.0,
+                end_beat: 4.0,
+            })
+        );
+        assert_eq!(track.volume_at(2.0), 0.0);
+        assert_eq!(track.volume_at(3.5), 0.5);
+        assert_eq!(track.volume_at(4.0), 1.0);
+
+        // The ramp settles once it's finished.
+        update_at(&mut piece, &clock, 2.0);
+        assert_eq!(piece.tracks[0].ramp, None);
+        assert_eq!(piece.tracks[0].volume, 1.0);
+    }
+
+    #[test]
+    fn volume_changes_wait_for_a_boundary_a_fade_away() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let mut piece = piece(&backend, &clock, vec![Step::new(127)]);
+        update_at(&mut piece, &clock, 0.0);
+        let change = VolumeChange::new(0.0)
+            .with_fade(0.5)
+            .with_boundary(Boundary::Bar);
+
+        // A fade's duration before a bar, the change lands on it.
+        update_at(&mut piece, &clock, 0.75);
+        update_at(&mut piece, &clock, 1.5);
+        piece.change_track_volume(0, change);
+        let ramp = piece.tracks[0].ramp.unwrap();
+        assert_eq!((ramp.start_beat, ramp.end_beat), (3.0, 4.0));
+
+        // Any later, the fade wouldn't fit, so it lands on the bar after.
+        update_at(&mut piece, &clock, 1.75);
+        piece.change_track_volume(0, change);
+        let ramp = piece.tracks[0].ramp.unwrap();
+        assert_eq!((ramp.start_beat, ramp.end_beat), (7.0, 8.0));
+    }
+
+    #[test]
+    fn volume_ramps_start_from_the_volume_reached() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let mut piece = piece(&backend, &clock, vec![Step::new(127); 4]);
+        piece.set_track_volume(0, 0.0);
+        piece.ramp_track_volume(0, 1.0, 0.0..1.0);
+
+        // Steps play at the volume the ramp has reached.
+        let volumes: Vec<f32> = play(&mut piece, &backend, &clock, 0.5)
+            .into_iter()
+            .map(|(_, volume)| volume)
+            .collect();
+        assert_eq!(volumes, vec![0.25, 0.5, 0.75]);
+
+        // Replacing the ramp partway starts from where it would have been.
+        piece.ramp_track_volume(0, 0.0, 2.0..2.5);
+        piece.ramp_track_volume(0, 1.0, 2.25..3.25);
+        let track = &piece.tracks[0];
+        assert_eq!(track.volume_at(2.25), 0.5);
+        assert_eq!(track.volume_at(2.75), 0.75);
+    }
+
+    #[test]
+    fn fill_steps_report_their_fill_index() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let fill = vec![Step::REST, Step::new(127), Step::REST, Step::new(127)];
+        let track = Track::new(SoundId(0), [Step::new(127)]).with_fill(2, fill);
+        let mut piece =
+            Piece::new(Rc::new(backend.clone()), track, TEMPO_BPM).with_clock(clock.clone());
+        piece.set_track_volume(0, 1.0);
+
+        // Play the first two bars, the second of which is the fill.
+        let mut steps = vec![];
+        for frame in 0..4 * 60 {
+            clock.set(frame as f64 / 60.0);
+            let events = piece.update();
+            steps.extend(events.iter().map(|e| (e.bar, e.step, e.fill)));
+        }
+
+        assert_eq!(steps.len(), 16 + 8);
+        assert!(steps[..16].iter().all(|&step| step == (0, 0, false)));
+        assert!(
+            steps[16..]
+                .iter()
+                .zip([1, 3].iter().cycle())
+                .all(|(&step, &index)| step == (1, index, true))
+        );
+    }
+
+    /// Returns a piece playing `steps` at full volume on a single track,
+    /// with a voice for each of `voices`, given by its root sound's id
+    /// and duration, cutting voices off by `stealing`.
+    fn voiced_piece(
+        backend: &RecordingBackend,
+        clock: &FakeClock,
+        steps: Vec<Step>,
+        voices: &[(usize, f64)],
+        stealing: VoiceStealing,
+    ) -> Piece {
+        let mut track = Track::new(SoundId(0), steps).with_voice_stealing(stealing);
+        for &(sound, duration) in voices {
+            track = track.with_voice([(0, SoundId(sound), duration)]);
+        }
+        let mut piece =
+            Piece::new(Rc::new(backend.clone()), track, TEMPO_BPM).with_clock(clock.clone());
+        piece.set_track_volume(0, 1.0);
+        piece
+    }
+
+    /// Time at which a sound is played or stopped, and its id.
+    type SoundAt = (f64, usize);
+
+    /// Updates `piece` at 60 frames per second for the first `secs`
+    /// seconds, returning every sound played, and every sound stopped,
+    /// within them.
+    fn voice_events(
+        piece: &mut Piece,
+        backend: &RecordingBackend,
+        clock: &FakeClock,
+        secs: f64,
+    ) -> (Vec<SoundAt>, Vec<SoundAt>) {
+        for frame in 0..(secs * 60.0).round() as u64 {
+            clock.set(frame as f64 / 60.0);
+            piece.update();
+        }
+        plays_and_stops(backend, secs)
+    }
+
+    /// Returns every sound played, and every sound stopped, before
+    /// `secs` seconds in `backend`'s log, clearing the log.
+    fn plays_and_stops(backend: &RecordingBackend, secs: f64) -> (Vec<SoundAt>, Vec<SoundAt>) {
+        let (mut plays, mut stops) = (vec![], vec![]);
+        for (time, event) in backend.take_events() {
+            match event {
+                AudioEvent::Play { sound, .. } if time < secs => plays.push((time, sound.0)),
+                AudioEvent::Stop { sound } if time < secs => stops.push((time, sound.0)),
+                _ => {}
+            }
+        }
+        (plays, stops)
+    }
+
+    #[test]
+    fn voices_limit_how_many_sounds_play_at_once() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let steps = vec![Step::new(127); 4];
+        let voices = [(1, 0.3), (2, 0.3)];
+        let mut piece = voiced_piece(&backend, &clock, steps, &voices, VoiceStealing::Oldest);
+
+        // Each step cuts off the oldest voice as it starts.
+        let (plays, stops) = voice_events(&mut piece, &backend, &clock, 0.5);
+        assert_eq!(plays, [(0.0, 1), (0.125, 2), (0.25, 1), (0.375, 2)]);
+        assert_eq!(stops, [(0.25, 1), (0.375, 2)]);
+    }
+
+    #[test]
+    fn voices_are_reused_once_their_sound_ends() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let steps = vec![Step::new(127); 4];
+        let voices = [(1, 0.2), (2, 0.2)];
+        let mut piece = voiced_piece(&backend, &clock, steps, &voices, VoiceStealing::Oldest);
+
+        let (plays, stops) = voice_events(&mut piece, &backend, &clock, 0.5);
+        assert_eq!(plays, [(0.0, 1), (0.125, 2), (0.25, 1), (0.375, 2)]);
+        assert_eq!(stops, []);
+    }
+
+    #[test]
+    fn voice_stealing_chooses_oldest_or_quietest() {
+        let steps = vec![
+            Step::new(127),
+            Step::new(32),
+            Step::new(127),
+            Step::new(127),
+        ];
+        let voices = [(1, 1.0), (2, 1.0)];
+        let stops = |stealing| {
+            let clock = FakeClock::new();
+            let backend = RecordingBackend::new(clock.clone());
+            let mut piece = voiced_piece(&backend, &clock, steps.clone(), &voices, stealing);
+            voice_events(&mut piece, &backend, &clock, 0.5).1
+        };
+
+        assert_eq!(stops(VoiceStealing::Oldest), [(0.25, 1), (0.375, 2)]);
+
+        // Equally quiet voices are cut off oldest first.
+        assert_eq!(stops(VoiceStealing::Quietest), [(0.25, 2), (0.375, 1)]);
+    }
+
+    #[test]
+    fn stalls_only_catch_up_on_the_latest_overdue_step() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let steps = vec![Step::new(127); 16];
+        let voices = [(1, 10.0), (2, 10.0), (3, 10.0), (4, 10.0)];
+        let mut piece = voiced_piece(&backend, &clock, steps, &voices, VoiceStealing::Oldest);
+        piece.update();
+
+        // Skipped steps don't take voices from the steps played after the stall.
+        clock.set(0.9);
+        piece.update();
+        let (plays, stops) = plays_and_stops(&backend, f64::INFINITY);
+        assert_eq!(plays, [(0.0, 1), (0.75, 2), (0.875, 3)]);
+        assert_eq!(stops, []);
+    }
+
+    #[test]
+    fn voices_play_the_track_sound_at_each_pitch() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let steps = vec![
+            Step::new(127),
+            Step::new(127).with_pitch(5),
+            Step::new(127).with_pitch(7),
+            Step::REST,
+        ];
+
+        // The voice has only a copy of the sound at 7 semitones, so it
+        // falls back to the track's pitched copy and its root sound.
+        let track = Track::new(SoundId(0), steps)
+            .with_pitched_sound(5, SoundId(5))
+            .with_voice([(7, SoundId(7), 0.1)]);
+        let mut piece =
+            Piece::new(Rc::new(backend.clone()), track, TEMPO_BPM).with_clock(clock.clone());
+        piece.set_track_volume(0, 1.0);
+
+        let (plays, _) = voice_events(&mut piece, &backend, &clock, 0.5);
+        assert_eq!(plays, [(0.0, 0), (0.125, 5), (0.25, 7)]);
     }
 }
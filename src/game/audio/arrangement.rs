@@ -0,0 +1,431 @@
+//! Adaptive arrangement of a [Piece] in response to game events.
+//!
+//! An [Arrangement] splits a piece into named [Section]s, each playing
+//! a subset of the piece's tracks, with [Rule]s for moving between
+//! sections when [MusicEvent]s happen. Tracks are also unlocked in
+//! groups as objectives are cleared, and only unlocked tracks play.
+//!
+//! Section changes always land on a bar boundary.
+
+use super::{Boundary, Piece, UNLOCK_FADE_SECS};
+
+/// Something happening in the game which the music can respond to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MusicEvent {
+    /// A level started, locking every track and
+    /// returning to the arrangement's first section.
+    Start,
+
+    /// An objective was cleared, unlocking the next group of tracks.
+    Objective,
+
+    /// Every objective in a level was cleared.
+    Clear,
+
+    /// The current section played through to its end.
+    End,
+}
+
+impl MusicEvent {
+    /// Returns the event with the name used in compositions, like `objective`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "start" => Some(MusicEvent::Start),
+            "objective" => Some(MusicEvent::Objective),
+            "clear" => Some(MusicEvent::Clear),
+            "end" => Some(MusicEvent::End),
+            _ => None,
+        }
+    }
+}
+
+/// A named part of an [Arrangement], playing a subset of a piece's tracks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Section {
+    /// Name of the section.
+    pub name: String,
+
+    /// Length of the section in bars, after which a [MusicEvent::End]
+    /// happens, or `0` if the section never ends.
+    pub bars: u32,
+
+    /// Indices of the tracks playing in the section.
+    pub tracks: Vec<usize>,
+}
+
+/// Moves an [Arrangement] to a new section when an event happens.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    /// Event which triggers the rule.
+    pub event: MusicEvent,
+
+    /// Index of the section the rule applies in, or `None` for any section.
+    pub from: Option<usize>,
+
+    /// Index of the section to move to.
+    pub to: usize,
+}
+
+/// Sections, rules and unlock order for a [Piece].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Arrangement {
+    /// Sections of the piece. The first section plays when a level starts.
+    pub sections: Vec<Section>,
+
+    /// Rules for moving between sections, in order of precedence.
+    pub rules: Vec<Rule>,
+
+    /// Groups of track indices, unlocked in order as objectives are cleared.
+    pub unlocks: Vec<Vec<usize>>,
+}
+
+impl Arrangement {
+    /// Returns an arrangement with a single endless section playing
+    /// all `track_count` tracks, unlocking one track per objective.
+    pub fn linear(track_count: usize) -> Self {
+        Self {
+            sections: vec![Section {
+                name: "main".to_string(),
+                bars: 0,
+                tracks: (0..track_count).collect(),
+            }],
+            rules: vec![],
+            unlocks: (0..track_count).map(|track| vec![track]).collect(),
+        }
+    }
+
+    /// Returns the index of the section called `name`.
+    pub fn section_index(&self, name: &str) -> Option<usize> {
+        self.sections
+            .iter()
+            .position(|section| section.name == name)
+    }
+
+    /// Returns the section to move to from `section` when `event` happens.
+    fn next_section(&self, section: usize, event: MusicEvent) -> Option<usize> {
+        self.rules
+            .iter()
+            .find(|rule| rule.event == event && rule.from.is_none_or(|from| from == section))
+            .map(|rule| rule.to)
+    }
+}
+
+/// Follows an [Arrangement] as game events happen,
+/// changing the volumes of a piece's tracks to match.
+pub struct Arranger {
+    /// The arrangement being followed.
+    arrangement: Arrangement,
+
+    /// Index of the current section.
+    section: usize,
+
+    /// Beat at which the current section started (or will start).
+    section_start: f64,
+
+    /// Number of unlock groups which have been unlocked.
+    unlocked: usize,
+
+    /// The volume each track was last changed to.
+    targets: Vec<f32>,
+}
+
+impl Arranger {
+    /// Returns a new arranger following `arrangement`,
+    /// at the start of its first section with every track locked.
+    pub fn new(arrangement: Arrangement) -> Self {
+        Self {
+            arrangement,
+            section: 0,
+            section_start: 0.0,
+            unlocked: 0,
+            targets: vec![],
+        }
+    }
+
+    /// Returns the arrangement being followed.
+    pub fn arrangement(&self) -> &Arrangement {
+        &self.arrangement
+    }
+
+    /// Returns the name of the current section.
+    pub fn section(&self) -> &str {
+        &self.arrangement.sections[self.section].name
+    }
+
+    /// Returns the number of unlock groups which have been unlocked.
+    pub fn unlocked(&self) -> usize {
+        self.unlocked
+    }
+
+    /// Responds to `event`, moving between sections and unlocking
+    /// tracks, with changes landing on the piece's next bar (or
+    /// next beat, for the first tracks to play).
+    pub fn handle(&mut self, event: MusicEvent, piece: &mut Piece) {
+        match event {
+            // Silence every track immediately, before
+            // any rules move on from the first section.
+            MusicEvent::Start => {
+                self.section = 0;
+                self.unlocked = 0;
+                self.targets = vec![0.0; piece.track_count()];
+                for track in 0..piece.track_count() {
+                    piece.set_track_volume(track, 0.0);
+                }
+            }
+
+            MusicEvent::Objective => {
+                self.unlocked = (self.unlocked + 1).min(self.arrangement.unlocks.len());
+            }
+
+            MusicEvent::Clear | MusicEvent::End => {}
+        }
+
+        let fade_beats = piece.beats_in(UNLOCK_FADE_SECS);
+        let next_bar = piece.next_boundary(Boundary::Bar, piece.beat() + fade_beats);
+
+        // Bring the first tracks in on the next beat, rather than
+        // leaving silence until the next bar, unless the section changes.
+        let mut landing = if self.targets.iter().all(|&volume| volume <= 0.0) {
+            piece.next_boundary(Boundary::Beat, piece.beat() + fade_beats)
+        } else {
+            next_bar
+        };
+
+        if event == MusicEvent::Start {
+            self.section_start = next_bar;
+        }
+        if let Some(section) = self.arrangement.next_section(self.section, event) {
+            self.section = section;
+            self.section_start = next_bar;
+            landing = next_bar;
+        }
+
+        self.apply(piece, landing, fade_beats);
+    }
+
+    /// Updates the arranger, moving on from sections which are ending.
+    ///
+    /// Sections are moved on from a beat before they end,
+    /// so that the next section starts exactly on time.
+    pub fn update(&mut self, piece: &mut Piece) {
+        let bars = self.arrangement.sections[self.section].bars;
+        if bars == 0 {
+            return;
+        }
+
+        let section_end = self.section_start + bars as f64 * piece.time_signature().bar_beats();
+        if piece.beat() + 1.0 < section_end {
+            return;
+        }
+
+        // Sections without a rule for ending repeat.
+        self.section_start = section_end;
+        if let Some(section) = self.arrangement.next_section(self.section, MusicEvent::End) {
+            self.section = section;
+            self.apply(piece, section_end, 0.0);
+        }
+    }
+
+    /// Re-applies the volumes of the current section's tracks
+    /// from the next beat, after something else changed them.
+    pub fn restore(&mut self, piece: &mut Piece) {
+        let fade_beats = piece.beats_in(UNLOCK_FADE_SECS);
+        let landing = piece.next_boundary(Boundary::Beat, piece.beat() + fade_beats);
+
+        // Forget the targets, so every track is changed.
+        self.targets = vec![f32::NAN; piece.track_count()];
+        self.apply(piece, landing, fade_beats);
+    }
+
+    /// Changes the volume of every track which should start or stop
+    /// playing, fading over `fade_beats` so that it lands at `landing`.
+    fn apply(&mut self, piece: &mut Piece, landing: f64, fade_beats: f64) {
+        let section = &self.arrangement.sections[self.section];
+        self.targets.resize(piece.track_count(), 0.0);
+
+        for track in 0..piece.track_count() {
+            let unlocked = self.arrangement.unlocks[..self.unlocked]
+                .iter()
+                .any(|group| group.contains(&track));
+            let target = if unlocked && section.tracks.contains(&track) {
+                1.0
+            } else {
+                0.0
+            };
+
+            if target != self.targets[track] {
+                self.targets[track] = target;
+                piece.ramp_track_volume(track, target, landing - fade_beats..landing);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::super::Track;
+    use super::super::backend::{NullBackend, SoundId};
+    use super::super::scheduler::{AudioClock, FakeClock};
+    use super::*;
+
+    /// Tempo at which each beat lasts exactly half a second,
+    /// and each unlock fades in over a beat.
+    const TEMPO_BPM: f32 = 120.0;
+
+    /// Returns a piece of three tracks, measuring time with `clock`.
+    fn piece(clock: &FakeClock) -> Piece {
+        let track = |sound| Track::new(SoundId(sound), [127]);
+        let mut piece = Piece::new(Rc::new(NullBackend::default()), track(0), TEMPO_BPM)
+            .with(track(1))
+            .with(track(2))
+            .with_clock(clock.clone());
+        piece.update();
+        piece
+    }
+
+    /// Returns an arrangement of an endless intro playing the first two
+    /// tracks, and a bar-long outro playing every track after a clear.
+    fn arrangement() -> Arrangement {
+        let section = |name: &str, bars, tracks: &[usize]| Section {
+            name: name.to_string(),
+            bars,
+            tracks: tracks.to_vec(),
+        };
+        Arrangement {
+            sections: vec![
+                section("intro", 0, &[0, 1]),
+                section("outro", 1, &[0, 1, 2]),
+            ],
+            rules: vec![
+                Rule {
+                    event: MusicEvent::Clear,
+                    from: None,
+                    to: 1,
+                },
+                Rule {
+                    event: MusicEvent::End,
+                    from: Some(1),
+                    to: 0,
+                },
+            ],
+            unlocks: vec![vec![1], vec![0], vec![2]],
+        }
+    }
+
+    /// Updates `piece` and `arranger` every quarter of a second up to `time`.
+    fn advance(piece: &mut Piece, arranger: &mut Arranger, clock: &FakeClock, time: f64) {
+        while clock.now() < time {
+            clock.advance(0.25);
+            piece.update();
+            arranger.update(piece);
+        }
+    }
+
+    /// Returns the volume of each of `piece`'s tracks at `beat`.
+    fn volumes(piece: &Piece, beat: f64) -> Vec<f32> {
+        piece
+            .tracks
+            .iter()
+            .map(|track| track.volume_at(beat))
+            .collect()
+    }
+
+    #[test]
+    fn start_silences_every_track() {
+        let clock = FakeClock::new();
+        let mut piece = piece(&clock);
+        for track in 0..3 {
+            piece.set_track_volume(track, 1.0);
+        }
+
+        let mut arranger = Arranger::new(arrangement());
+        arranger.handle(MusicEvent::Start, &mut piece);
+
+        assert_eq!(arranger.section(), "intro");
+        assert_eq!(arranger.unlocked(), 0);
+        assert_eq!(volumes(&piece, 0.0), [0.0; 3]);
+        assert_eq!(volumes(&piece, 100.0), [0.0; 3]);
+    }
+
+    #[test]
+    fn objectives_unlock_groups_in_order() {
+        let clock = FakeClock::new();
+        let mut piece = piece(&clock);
+        let mut arranger = Arranger::new(arrangement());
+        arranger.handle(MusicEvent::Start, &mut piece);
+
+        // The first group fades in by the next beat a fade away.
+        arranger.handle(MusicEvent::Objective, &mut piece);
+        assert_eq!(arranger.unlocked(), 1);
+        assert_eq!(volumes(&piece, 0.5), [0.0, 0.5, 0.0]);
+        assert_eq!(volumes(&piece, 1.0), [0.0, 1.0, 0.0]);
+
+        // Later groups wait for the next bar.
+        advance(&mut piece, &mut arranger, &clock, 1.0);
+        arranger.handle(MusicEvent::Objective, &mut piece);
+        assert_eq!(volumes(&piece, 3.0), [0.0, 1.0, 0.0]);
+        assert_eq!(volumes(&piece, 4.0), [1.0, 1.0, 0.0]);
+
+        // Tracks outside the current section stay silent once unlocked.
+        arranger.handle(MusicEvent::Objective, &mut piece);
+        assert_eq!(arranger.unlocked(), 3);
+        assert_eq!(volumes(&piece, 8.0), [1.0, 1.0, 0.0]);
+
+        // There's nothing left to unlock.
+        arranger.handle(MusicEvent::Objective, &mut piece);
+        assert_eq!(arranger.unlocked(), 3);
+    }
+
+    #[test]
+    fn clear_and_end_move_between_sections_on_the_bar() {
+        let clock = FakeClock::new();
+        let mut piece = piece(&clock);
+        let mut arranger = Arranger::new(arrangement());
+        arranger.handle(MusicEvent::Start, &mut piece);
+        for _ in 0..3 {
+            arranger.handle(MusicEvent::Objective, &mut piece);
+        }
+        advance(&mut piece, &mut arranger, &clock, 2.5);
+
+        // Clearing the level brings in the outro at the next bar.
+        arranger.handle(MusicEvent::Clear, &mut piece);
+        assert_eq!(arranger.section(), "outro");
+        assert_eq!(volumes(&piece, 7.0), [1.0, 1.0, 0.0]);
+        assert_eq!(volumes(&piece, 8.0), [1.0, 1.0, 1.0]);
+
+        // The outro ends after a bar, cutting straight back to the intro.
+        advance(&mut piece, &mut arranger, &clock, 5.25);
+        assert_eq!(arranger.section(), "outro");
+        advance(&mut piece, &mut arranger, &clock, 5.5);
+        assert_eq!(arranger.section(), "intro");
+        assert_eq!(volumes(&piece, 11.99), [1.0, 1.0, 1.0]);
+        assert_eq!(volumes(&piece, 12.0), [1.0, 1.0, 0.0]);
+
+        // Starting again locks every track and returns to the first section.
+        arranger.handle(MusicEvent::Start, &mut piece);
+        assert_eq!((arranger.section(), arranger.unlocked()), ("intro", 0));
+        assert_eq!(volumes(&piece, 11.0), [0.0; 3]);
+    }
+
+    #[test]
+    fn restore_brings_back_the_current_section() {
+        let clock = FakeClock::new();
+        let mut piece = piece(&clock);
+        let mut arranger = Arranger::new(arrangement());
+        arranger.handle(MusicEvent::Start, &mut piece);
+        arranger.handle(MusicEvent::Objective, &mut piece);
+        arranger.handle(MusicEvent::Objective, &mut piece);
+        advance(&mut piece, &mut arranger, &clock, 1.0);
+
+        // Something else silences the piece, like the jukebox opening.
+        piece.silence();
+        assert_eq!(volumes(&piece, 2.0), [0.0; 3]);
+
+        // The unlocked tracks come back by the next beat a fade away.
+        arranger.restore(&mut piece);
+        assert_eq!(volumes(&piece, 2.5), [0.5, 0.5, 0.0]);
+        assert_eq!(volumes(&piece, 3.0), [1.0, 1.0, 0.0]);
+    }
+}
@@ -0,0 +1,401 @@
+//! Audio playback backends.
+//!
+//! [Piece](super::Piece)s play sounds through an [AudioBackend] rather
+//! than calling `macroquad::audio` directly, so the sequencer can run
+//! without an audio device, or record exactly what it would have played.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use super::scheduler::{AudioClock, FakeClock, GameClock};
+
+/// Handle to a sound loaded by an [AudioBackend].
+///
+/// Handles are only meaningful to the backend which loaded them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SoundId(pub usize);
+
+/// Parameters for playing a sound.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PlayParams {
+    /// Clock time at which the sound is due to start, in seconds.
+    ///
+    /// Sounds may be played ahead of this time, and
+    /// backends start them as close to it as they can.
+    pub time: f64,
+
+    /// Volume of the sound, from `0.0` (silent) to `1.0` (full volume).
+    pub volume: f32,
+
+    /// Whether the sound loops until it's stopped.
+    pub looped: bool,
+}
+
+/// Future returned by [AudioBackend::load].
+pub type LoadFuture<'a> = Pin<Box<dyn Future<Output = Result<SoundId, AudioError>> + 'a>>;
+
+/// Plays sounds on behalf of the game.
+///
+/// Methods take `&self` so that a single backend can
+/// be shared between pieces and other sound sources.
+pub trait AudioBackend {
+    /// Loads a sound from encoded audio (such as WAV) bytes.
+    fn load<'a>(&'a self, bytes: &'a [u8]) -> LoadFuture<'a>;
+
+    /// Starts playing a sound.
+    fn play(&self, sound: SoundId, params: PlayParams);
+
+    /// Changes the volume of every playing instance of a sound.
+    fn set_volume(&self, sound: SoundId, volume: f32);
+
+    /// Stops every playing instance of a sound.
+    fn stop(&self, sound: SoundId);
+
+    /// Stops every instance of a sound playing at clock time `time`, in seconds.
+    ///
+    /// Backends which can't schedule sounds stop them immediately.
+    fn stop_at(&self, sound: SoundId, time: f64) {
+        let _ = time;
+        self.stop(sound);
+    }
+
+    /// Starts and stops any sounds which were held until their time.
+    ///
+    /// Called every frame; backends which don't hold sounds needn't do anything.
+    fn update(&self) {}
+}
+
+/// Backend playing sounds through `macroquad::audio`.
+///
+/// Macroquad can't schedule sounds, so sounds played or stopped ahead
+/// of time are held until the first call at or after their time
+/// (usually [AudioBackend::update], once a frame).
+#[derive(Default)]
+pub struct MacroquadBackend {
+    sounds: RefCell<Vec<macroquad::audio::Sound>>,
+
+    /// Plays and stops which aren't due yet, in the order they're due.
+    pending: RefCell<Vec<(f64, AudioEvent)>>,
+}
+
+impl MacroquadBackend {
+    /// Calls `f` with the loaded sound for `sound`, if there is one.
+    fn with_sound(&self, sound: SoundId, f: impl FnOnce(&macroquad::audio::Sound)) {
+        if let Some(sound) = self.sounds.borrow().get(sound.0) {
+            f(sound);
+        }
+    }
+
+    /// Sends `event` to macroquad at `time`, holding it until then.
+    fn send_at(&self, time: f64, event: AudioEvent) {
+        self.release();
+        if time <= GameClock.now() {
+            return self.send(event);
+        }
+
+        // Stops go before plays due at the same time, so a
+        // stolen voice doesn't cut off the sound replacing it.
+        let order =
+            |time: f64, event: &AudioEvent| (time, matches!(event, AudioEvent::Play { .. }));
+        let mut pending = self.pending.borrow_mut();
+        let i = pending.partition_point(|(t, e)| order(*t, e) <= order(time, &event));
+        pending.insert(i, (time, event));
+    }
+
+    /// Sends every held event which is now due to macroquad.
+    fn release(&self) {
+        let now = GameClock.now();
+        let due: Vec<_> = {
+            let mut pending = self.pending.borrow_mut();
+            let count = pending.partition_point(|(time, _)| *time <= now);
+            pending.drain(..count).collect()
+        };
+
+        for (_, event) in due {
+            self.send(event);
+        }
+    }
+
+    /// Sends `event` to macroquad straight away.
+    fn send(&self, event: AudioEvent) {
+        match event {
+            AudioEvent::Play { sound, params } => self.with_sound(sound, |sound| {
+                macroquad::audio::play_sound(
+                    sound,
+                    macroquad::audio::PlaySoundParams {
+                        looped: params.looped,
+                        volume: params.volume,
+                    },
+                )
+            }),
+            AudioEvent::SetVolume { sound, volume } => self.with_sound(sound, |sound| {
+                macroquad::audio::set_sound_volume(sound, volume)
+            }),
+            AudioEvent::Stop { sound } => self.with_sound(sound, macroquad::audio::stop_sound),
+        }
+    }
+}
+
+impl AudioBackend for MacroquadBackend {
+    fn load<'a>(&'a self, bytes: &'a [u8]) -> LoadFuture<'a> {
+        Box::pin(async move {
+            let sound = macroquad::audio::load_sound_from_bytes(bytes)
+                .await
+                .map_err(|e| AudioError::Load(e.to_string()))?;
+
+            let mut sounds = self.sounds.borrow_mut();
+            sounds.push(sound);
+            Ok(SoundId(sounds.len() - 1))
+        })
+    }
+
+    fn play(&self, sound: SoundId, params: PlayParams) {
+        self.send_at(params.time, AudioEvent::Play { sound, params });
+    }
+
+    fn set_volume(&self, sound: SoundId, volume: f32) {
+        // Held plays of the sound start at the new volume too.
+        for (_, event) in self.pending.borrow_mut().iter_mut() {
+            if let AudioEvent::Play {
+                sound: held,
+                params,
+            } = event
+                && *held == sound
+            {
+                params.volume = volume;
+            }
+        }
+        self.send(AudioEvent::SetVolume { sound, volume });
+    }
+
+    fn stop(&self, sound: SoundId) {
+        // Stopping a sound also cancels its held plays.
+        self.pending.borrow_mut().retain(
+            |(_, event)| !matches!(event, AudioEvent::Play { sound: held, .. } if *held == sound),
+        );
+        self.send(AudioEvent::Stop { sound });
+    }
+
+    fn stop_at(&self, sound: SoundId, time: f64) {
+        self.send_at(time, AudioEvent::Stop { sound });
+    }
+
+    fn update(&self) {
+        self.release();
+    }
+}
+
+/// Backend which loads and plays nothing, for running without a sound device.
+#[derive(Default)]
+pub struct NullBackend {
+    sound_count: Cell<usize>,
+}
+
+impl AudioBackend for NullBackend {
+    fn load<'a>(&'a self, _bytes: &'a [u8]) -> LoadFuture<'a> {
+        let sound = SoundId(self.sound_count.get());
+        self.sound_count.set(sound.0 + 1);
+
+        Box::pin(std::future::ready(Ok(sound)))
+    }
+
+    fn play(&self, _sound: SoundId, _params: PlayParams) {}
+
+    fn set_volume(&self, _sound: SoundId, _volume: f32) {}
+
+    fn stop(&self, _sound: SoundId) {}
+}
+
+/// Something an [AudioBackend] was asked to do, as logged by a [RecordingBackend].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioEvent {
+    /// A sound was played.
+    Play { sound: SoundId, params: PlayParams },
+
+    /// A sound's volume was changed.
+    SetVolume { sound: SoundId, volume: f32 },
+
+    /// A sound was stopped.
+    Stop { sound: SoundId },
+}
+
+/// Backend which plays nothing, but logs every event it's sent,
+/// with the clock time it applies from.
+///
+/// Clones share the same log, so a clone can be given
+/// to a piece while the original inspects the events.
+#[derive(Clone, Default)]
+pub struct RecordingBackend {
+    /// Clock used to time volume changes, and stops which aren't scheduled.
+    clock: FakeClock,
+
+    sound_count: Rc<Cell<usize>>,
+    events: Rc<RefCell<Vec<(f64, AudioEvent)>>>,
+}
+
+impl RecordingBackend {
+    /// Returns a new backend with an empty log, timing events with `clock`.
+    pub fn new(clock: FakeClock) -> Self {
+        Self {
+            clock,
+            ..Default::default()
+        }
+    }
+
+    /// Returns every event logged so far, in the order they were sent.
+    pub fn events(&self) -> Vec<(f64, AudioEvent)> {
+        self.events.borrow().clone()
+    }
+
+    /// Removes and returns every event logged so far.
+    pub fn take_events(&self) -> Vec<(f64, AudioEvent)> {
+        self.events.take()
+    }
+}
+
+impl AudioBackend for RecordingBackend {
+    fn load<'a>(&'a self, _bytes: &'a [u8]) -> LoadFuture<'a> {
+        let sound = SoundId(self.sound_count.get());
+        self.sound_count.set(sound.0 + 1);
+
+        Box::pin(std::future::ready(Ok(sound)))
+    }
+
+    fn play(&self, sound: SoundId, params: PlayParams) {
+        self.events
+            .borrow_mut()
+            .push((params.time, AudioEvent::Play { sound, params }));
+    }
+
+    fn set_volume(&self, sound: SoundId, volume: f32) {
+        self.events
+            .borrow_mut()
+            .push((self.clock.now(), AudioEvent::SetVolume { sound, volume }));
+    }
+
+    fn stop(&self, sound: SoundId) {
+        self.stop_at(sound, self.clock.now());
+    }
+
+    fn stop_at(&self, sound: SoundId, time: f64) {
+        self.events
+            .borrow_mut()
+            .push((time, AudioEvent::Stop { sound }));
+    }
+}
+
+/// Errors encountered by an [AudioBackend].
+#[derive(Clone, Debug, PartialEq)]
+pub enum AudioError {
+    /// A sound couldn't be loaded.
+    Load(String),
+
+    /// A sound couldn't be decoded for processing.
+    Decode(String),
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::Load(message) => write!(f, "failed to load sound: {message}"),
+            AudioError::Decode(message) => write!(f, "failed to decode sound: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for AudioError {}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mixdown::block_on;
+    use super::*;
+
+    /// Returns parameters playing a sound once at `time`, at full volume.
+    fn once_at(time: f64) -> PlayParams {
+        PlayParams {
+            time,
+            volume: 1.0,
+            looped: false,
+        }
+    }
+
+    #[test]
+    fn null_backend_loads_without_a_device() {
+        let backend = NullBackend::default();
+        let first = block_on(backend.load(&[])).unwrap();
+        let second = block_on(backend.load(&[])).unwrap();
+        assert_eq!((first, second), (SoundId(0), SoundId(1)));
+
+        // Playing and stopping does nothing, but doesn't fail either.
+        backend.play(first, once_at(0.0));
+        backend.set_volume(first, 0.5);
+        backend.stop_at(first, 1.0);
+        backend.stop(second);
+        backend.update();
+    }
+
+    #[test]
+    fn recording_backend_logs_each_event_at_its_time() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let sound = block_on(backend.load(&[])).unwrap();
+
+        // Plays and scheduled stops keep their own times...
+        clock.set(1.0);
+        backend.play(sound, once_at(1.25));
+        backend.stop_at(sound, 1.5);
+        backend.play(sound, once_at(1.5));
+
+        // ...while everything else applies straight away.
+        clock.set(2.0);
+        backend.set_volume(sound, 0.5);
+        backend.stop(sound);
+
+        assert_eq!(
+            backend.take_events(),
+            vec![
+                (
+                    1.25,
+                    AudioEvent::Play {
+                        sound,
+                        params: once_at(1.25)
+                    }
+                ),
+                (1.5, AudioEvent::Stop { sound }),
+                (
+                    1.5,
+                    AudioEvent::Play {
+                        sound,
+                        params: once_at(1.5)
+                    }
+                ),
+                (2.0, AudioEvent::SetVolume { sound, volume: 0.5 }),
+                (2.0, AudioEvent::Stop { sound }),
+            ]
+        );
+        assert_eq!(backend.events(), vec![]);
+    }
+
+    #[test]
+    fn recording_backend_clones_share_their_log() {
+        let backend = RecordingBackend::new(FakeClock::new());
+        let clone = backend.clone();
+        let sound = block_on(clone.load(&[])).unwrap();
+        clone.play(sound, once_at(0.0));
+
+        assert_eq!(
+            backend.events(),
+            vec![(
+                0.0,
+                AudioEvent::Play {
+                    sound,
+                    params: once_at(0.0)
+                }
+            )]
+        );
+        assert_eq!(block_on(backend.load(&[])), Ok(SoundId(1)));
+    }
+}
@@ -0,0 +1,498 @@
+//! Effects applied to sounds before they're played.
+//!
+//! Backends can only play loaded sounds, so effects are applied to a
+//! sound's samples as it's loaded, like [Wav::pitched]. An [EffectChain]
+//! can be baked into a track's sounds (with `fx` in a [pattern]), or into
+//! every sound loaded through a [Mixer] bus.
+//!
+//! Effects are automated by loading a second, processed copy of each
+//! sound, and crossfading between the dry and wet copies as they play
+//! (see [Mixer::set_wet]).
+//!
+//! [pattern]: super::pattern
+//! [Mixer]: super::mixer::Mixer
+//! [Mixer::set_wet]: super::mixer::Mixer::set_wet
+
+use std::f32::consts::TAU;
+use std::fmt;
+
+use super::synth::one_pole_alpha;
+use super::wav::Wav;
+
+/// Level below which the tail of a delay or reverb is cut off.
+const TAIL_THRESHOLD: f32 = 0.001;
+
+/// The longest tail added to a sound by a delay or reverb, in seconds.
+const MAX_TAIL_SECS: f32 = 4.0;
+
+/// Delays of each of the reverb's comb filters, in seconds.
+///
+/// The delays are mutually prime in samples at common
+/// rates, so their echoes don't line up into a pitch.
+const REVERB_COMBS: [f32; 4] = [0.0297, 0.0371, 0.0411, 0.0437];
+
+/// Delays and gain of the reverb's all-pass filters, in seconds.
+const REVERB_ALLPASSES: [f32; 2] = [0.005, 0.0017];
+const REVERB_ALLPASS_GAIN: f32 = 0.7;
+
+/// Rate of the flutter in a [Effect::WowFlutter], in hertz.
+const FLUTTER_RATE: f32 = 7.0;
+
+/// A single effect processing a sound.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Effect {
+    /// One-pole low-pass filter, removing frequencies above `cutoff` hertz.
+    LowPass { cutoff: f32 },
+
+    /// One-pole high-pass filter, removing frequencies below `cutoff` hertz.
+    HighPass { cutoff: f32 },
+
+    /// Echoes repeating every `time` seconds, each `feedback` times
+    /// as loud as the last, mixed in at `mix`.
+    Delay { time: f32, feedback: f32, mix: f32 },
+
+    /// Schroeder reverb, ringing longer in a larger `room` (from `0.0`
+    /// to `1.0`), mixed in at `mix`.
+    Reverb { room: f32, mix: f32 },
+
+    /// Reduces the sound to `bits` of resolution,
+    /// holding each sample for `downsample` frames.
+    Bitcrush { bits: u32, downsample: u32 },
+
+    /// Slow (`wow`) and fast (`flutter`) wavering of the pitch, like
+    /// a worn tape or record, in seconds of delay. Wow cycles at `rate` hertz.
+    WowFlutter { wow: f32, rate: f32, flutter: f32 },
+}
+
+impl Effect {
+    /// Parses an effect from its name and `key=value`
+    /// parameters, like `delay` with `time=0.375 mix=0.3`.
+    ///
+    /// Effects and their keys (and defaults) are:
+    ///
+    /// - `lowpass`: `cutoff` (`800`).
+    /// - `highpass`: `cutoff` (`200`).
+    /// - `delay`: `time` (`0.25`), `feedback` (`0.4`) and `mix` (`0.3`).
+    /// - `reverb`: `room` (`0.5`) and `mix` (`0.3`).
+    /// - `bitcrush`: `bits` (`8`) and `downsample` (`1`).
+    /// - `wow`: `wow` (`0.002`), `rate` (`0.5`) and `flutter` (`0.0002`).
+    pub fn parse<'a>(
+        name: &str,
+        params: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, EffectError> {
+        let mut effect = match name {
+            "lowpass" => Effect::LowPass { cutoff: 800.0 },
+            "highpass" => Effect::HighPass { cutoff: 200.0 },
+            "delay" => Effect::Delay {
+                time: 0.25,
+                feedback: 0.4,
+                mix: 0.3,
+            },
+            "reverb" => Effect::Reverb {
+                room: 0.5,
+                mix: 0.3,
+            },
+            "bitcrush" => Effect::Bitcrush {
+                bits: 8,
+                downsample: 1,
+            },
+            "wow" => Effect::WowFlutter {
+                wow: 0.002,
+                rate: 0.5,
+                flutter: 0.0002,
+            },
+            _ => return Err(EffectError::UnknownEffect(name.to_string())),
+        };
+
+        for param in params {
+            let invalid = || EffectError::InvalidParam(param.to_string());
+            let (key, value) = param.split_once('=').ok_or_else(invalid)?;
+            let value: f32 = value.parse().map_err(|_| invalid())?;
+            if !value.is_finite() || value < 0.0 {
+                return Err(invalid());
+            }
+
+            match (&mut effect, key) {
+                (Effect::LowPass { cutoff } | Effect::HighPass { cutoff }, "cutoff") => {
+                    *cutoff = value;
+                }
+                (Effect::Delay { time, .. }, "time") => *time = value.max(0.001),
+                (Effect::Delay { feedback, .. }, "feedback") => *feedback = value.min(0.95),
+                (Effect::Delay { mix, .. } | Effect::Reverb { mix, .. }, "mix") => {
+                    *mix = value.min(1.0);
+                }
+                (Effect::Reverb { room, .. }, "room") => *room = value.min(1.0),
+                (Effect::Bitcrush { bits, .. }, "bits") => *bits = (value as u32).clamp(1, 16),
+                (Effect::Bitcrush { downsample, .. }, "downsample") => {
+                    *downsample = (value as u32).max(1);
+                }
+                (Effect::WowFlutter { wow, .. }, "wow") => *wow = value,
+                (Effect::WowFlutter { rate, .. }, "rate") => *rate = value,
+                (Effect::WowFlutter { flutter, .. }, "flutter") => *flutter = value,
+                _ => return Err(EffectError::UnknownParam(key.to_string())),
+            }
+        }
+
+        Ok(effect)
+    }
+
+    /// Returns `wav` processed by the effect.
+    ///
+    /// Delays and reverbs lengthen the audio by their tail.
+    pub fn apply(&self, wav: &Wav) -> Wav {
+        let rate = wav.sample_rate as f32;
+        match *self {
+            Effect::LowPass { cutoff } => wav.lowpassed(cutoff),
+
+            Effect::HighPass { cutoff } => {
+                let alpha = 1.0 - one_pole_alpha(cutoff, 1.0 / rate);
+                map_channels(wav, 0, |samples| {
+                    let (mut last_input, mut last_output) = (0.0, 0.0);
+                    for sample in samples {
+                        last_output = alpha * (last_output + *sample - last_input);
+                        last_input = *sample;
+                        *sample = last_output;
+                    }
+                })
+            }
+
+            Effect::Delay {
+                time,
+                feedback,
+                mix,
+            } => {
+                // Keep echoing until the echoes fall silent.
+                let echoes = if feedback > 0.0 {
+                    (TAIL_THRESHOLD.ln() / feedback.ln()).ceil().max(1.0)
+                } else {
+                    1.0
+                };
+                let delay = ((time * rate) as usize).max(1);
+                let tail = tail_frames(time * echoes, rate);
+
+                map_channels(wav, tail, |samples| {
+                    let mut echo = vec![0.0; samples.len()];
+                    for i in delay..samples.len() {
+                        echo[i] = samples[i - delay] + feedback * echo[i - delay];
+                    }
+                    for (sample, echo) in samples.iter_mut().zip(echo) {
+                        *sample += echo * mix;
+                    }
+                })
+            }
+
+            Effect::Reverb { room, mix } => {
+                // Larger rooms feed more of each comb back into itself.
+                let feedback = 0.7 + 0.25 * room;
+                let longest = REVERB_COMBS.iter().copied().fold(0.0, f32::max);
+                let decay = longest * TAIL_THRESHOLD.ln() / feedback.ln();
+                let tail = tail_frames(decay, rate);
+
+                map_channels(wav, tail, |samples| {
+                    let mut wet = vec![0.0; samples.len()];
+                    for secs in REVERB_COMBS {
+                        let delay = (secs * rate) as usize;
+                        let mut comb = vec![0.0; samples.len()];
+                        for i in delay..samples.len() {
+                            comb[i] = samples[i - delay] + feedback * comb[i - delay];
+                        }
+                        for (wet, comb) in wet.iter_mut().zip(comb) {
+                            *wet += comb / REVERB_COMBS.len() as f32;
+                        }
+                    }
+
+                    // Diffuse the combs' echoes.
+                    for secs in REVERB_ALLPASSES {
+                        let delay = (secs * rate) as usize;
+                        let input = wet.clone();
+                        for i in 0..wet.len() {
+                            let delayed_input = if i >= delay { input[i - delay] } else { 0.0 };
+                            let delayed_output = if i >= delay { wet[i - delay] } else { 0.0 };
+                            wet[i] = -REVERB_ALLPASS_GAIN * input[i]
+                                + delayed_input
+                                + REVERB_ALLPASS_GAIN * delayed_output;
+                        }
+                    }
+
+                    for (sample, wet) in samples.iter_mut().zip(wet) {
+                        *sample = *sample * (1.0 - mix) + wet * mix;
+                    }
+                })
+            }
+
+            Effect::Bitcrush { bits, downsample } => {
+                // Effects built in code may not have been clamped like parsed ones.
+                let levels = (1u32 << (bits.clamp(1, 16) - 1)) as f32;
+                let downsample = downsample.max(1) as usize;
+                map_channels(wav, 0, |samples| {
+                    let mut held = 0.0;
+                    for (i, sample) in samples.iter_mut().enumerate() {
+                        if i % downsample == 0 {
+                            held = (*sample * levels).round() / levels;
+                        }
+                        *sample = held;
+                    }
+                })
+            }
+
+            Effect::WowFlutter {
+                wow,
+                rate: wow_rate,
+                flutter,
+            } => {
+                // Read each sample from behind where it was, by a delay
+                // which wavers slowly (wow) and quickly (flutter).
+                map_channels(wav, 0, |samples| {
+                    let input = samples.to_vec();
+                    for (i, sample) in samples.iter_mut().enumerate() {
+                        let t = i as f32 / rate;
+                        let delay = wow * (1.0 - (TAU * wow_rate * t).cos()) / 2.0
+                            + flutter * (1.0 - (TAU * FLUTTER_RATE * t).cos()) / 2.0;
+
+                        let position = i as f32 - delay * rate;
+                        if position < 0.0 {
+                            *sample = 0.0;
+                            continue;
+                        }
+                        let index = position as usize;
+                        let t = position - index as f32;
+                        let a = input[index];
+                        let b = input.get(index + 1).copied().unwrap_or(0.0);
+                        *sample = a + (b - a) * t;
+                    }
+                })
+            }
+        }
+    }
+}
+
+/// A series of [Effect]s, each processing the output of the last.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EffectChain {
+    pub effects: Vec<Effect>,
+}
+
+impl EffectChain {
+    /// Returns an empty chain, which leaves sounds unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `effect` to the end of the chain.
+    pub fn with(mut self, effect: Effect) -> Self {
+        self.effects.push(effect);
+        self
+    }
+
+    /// Returns true if the chain has no effects.
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    /// Returns `wav` processed by each effect in the chain, in order.
+    pub fn apply(&self, wav: &Wav) -> Wav {
+        let mut wav = wav.clone();
+        for effect in &self.effects {
+            wav = effect.apply(&wav);
+        }
+
+        wav
+    }
+}
+
+/// Returns the number of frames in a tail lasting `secs`
+/// seconds at `rate`, up to [MAX_TAIL_SECS].
+fn tail_frames(secs: f32, rate: f32) -> usize {
+    (secs.min(MAX_TAIL_SECS) * rate).ceil() as usize
+}
+
+/// Returns `wav`, followed by `tail` frames of silence,
+/// with `process` applied to each channel separately.
+fn map_channels(wav: &Wav, tail: usize, mut process: impl FnMut(&mut [f32])) -> Wav {
+    let channels = wav.channels as usize;
+    let frames = wav.frames() + tail;
+
+    let mut samples = vec![0.0; frames * channels];
+    for channel in 0..channels {
+        let mut channel_samples: Vec<f32> = wav
+            .samples
+            .iter()
+            .skip(channel)
+            .step_by(channels)
+            .copied()
+            .chain(std::iter::repeat_n(0.0, tail))
+            .collect();
+        process(&mut channel_samples);
+
+        for (frame, sample) in channel_samples.into_iter().enumerate() {
+            samples[frame * channels + channel] = sample;
+        }
+    }
+
+    Wav {
+        channels: wav.channels,
+        sample_rate: wav.sample_rate,
+        samples,
+    }
+}
+
+/// Errors encountered while parsing an [Effect].
+#[derive(Clone, Debug, PartialEq)]
+pub enum EffectError {
+    /// The effect's name is unknown.
+    UnknownEffect(String),
+
+    /// A parameter has an unknown key.
+    UnknownParam(String),
+
+    /// A parameter isn't `key=value`, or its value is invalid.
+    InvalidParam(String),
+}
+
+impl fmt::Display for EffectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EffectError::UnknownEffect(name) => write!(f, "unknown effect `{name}`"),
+            EffectError::UnknownParam(key) => write!(f, "unknown effect parameter `{key}`"),
+            EffectError::InvalidParam(param) => write!(f, "invalid effect parameter `{param}`"),
+        }
+    }
+}
+
+impl std::error::Error for EffectError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a second of a mono sine wave at `frequency` hertz.
+    fn sine(frequency: f32, sample_rate: u32) -> Wav {
+        let samples = (0..sample_rate)
+            .map(|i| (TAU * frequency * i as f32 / sample_rate as f32).sin())
+            .collect();
+        Wav {
+            channels: 1,
+            sample_rate,
+            samples,
+        }
+    }
+
+    /// Returns the root mean square of `wav`'s samples.
+    fn rms(wav: &Wav) -> f32 {
+        let sum: f32 = wav.samples.iter().map(|sample| sample * sample).sum();
+        (sum / wav.samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn lowpass_attenuates_high_frequencies() {
+        let lowpass = Effect::LowPass { cutoff: 200.0 };
+        let (low, high) = (sine(50.0, 44100), sine(5000.0, 44100));
+
+        assert!(rms(&lowpass.apply(&low)) > 0.9 * rms(&low));
+        assert!(rms(&lowpass.apply(&high)) < 0.1 * rms(&high));
+    }
+
+    #[test]
+    fn highpass_attenuates_low_frequencies() {
+        let highpass = Effect::HighPass { cutoff: 2000.0 };
+        let (low, high) = (sine(50.0, 44100), sine(10000.0, 44100));
+
+        assert!(rms(&highpass.apply(&low)) < 0.1 * rms(&low));
+        assert!(rms(&highpass.apply(&high)) > 0.8 * rms(&high));
+    }
+
+    #[test]
+    fn tails_last_until_echoes_fall_silent() {
+        let wav = sine(100.0, 1000);
+
+        // Ten echoes, each half as loud, fall below the threshold.
+        let delay = Effect::Delay {
+            time: 0.125,
+            feedback: 0.5,
+            mix: 0.3,
+        };
+        assert_eq!(delay.apply(&wav).frames(), 1000 + 1250);
+
+        let dry = Effect::Delay {
+            time: 0.125,
+            feedback: 0.0,
+            mix: 0.3,
+        };
+        assert_eq!(dry.apply(&wav).frames(), 1000 + 125);
+
+        // The largest rooms ring for longer than the longest tail.
+        let reverb = Effect::Reverb {
+            room: 1.0,
+            mix: 0.3,
+        };
+        assert_eq!(reverb.apply(&wav).frames(), 1000 + 4000);
+
+        let lowpass = Effect::LowPass { cutoff: 800.0 };
+        assert_eq!(lowpass.apply(&wav).frames(), 1000);
+    }
+
+    #[test]
+    fn bitcrush_quantises_and_holds_samples() {
+        let wav = Wav {
+            channels: 1,
+            sample_rate: 1000,
+            samples: vec![0.1, 0.3, 0.6, 0.9],
+        };
+
+        let crushed = Effect::Bitcrush {
+            bits: 2,
+            downsample: 2,
+        };
+        assert_eq!(crushed.apply(&wav).samples, [0.0, 0.0, 0.5, 0.5]);
+
+        // Out of range parameters are clamped rather than panicking.
+        let extreme = Effect::Bitcrush {
+            bits: 0,
+            downsample: 0,
+        };
+        assert_eq!(extreme.apply(&wav).samples, [0.0, 0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn parse_applies_defaults_and_clamps() {
+        assert_eq!(
+            Effect::parse("delay", ["time=0.5", "feedback=2"]),
+            Ok(Effect::Delay {
+                time: 0.5,
+                feedback: 0.95,
+                mix: 0.3,
+            })
+        );
+        assert_eq!(
+            Effect::parse("bitcrush", ["bits=0"]),
+            Ok(Effect::Bitcrush {
+                bits: 1,
+                downsample: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_rejects_invalid_params() {
+        for param in [
+            "cutoff",
+            "cutoff=high",
+            "cutoff=-1",
+            "cutoff=NaN",
+            "cutoff=inf",
+        ] {
+            assert_eq!(
+                Effect::parse("lowpass", [param]),
+                Err(EffectError::InvalidParam(param.to_string())),
+            );
+        }
+        assert_eq!(
+            Effect::parse("lowpass", ["room=1"]),
+            Err(EffectError::UnknownParam("room".to_string())),
+        );
+        assert_eq!(
+            Effect::parse("chorus", []),
+            Err(EffectError::UnknownEffect("chorus".to_string())),
+        );
+    }
+}
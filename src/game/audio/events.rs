@@ -0,0 +1,154 @@
+//! Events describing the steps a [Piece](super::Piece) plays.
+//!
+//! Systems which react to the music (such as the fog's pulses)
+//! [subscribe](super::Piece::subscribe) to a piece's steps, rather
+//! than checking which track indices played each frame.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// A step played by a track in a piece.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StepEvent {
+    /// Index of the track which played the step.
+    pub track: usize,
+
+    /// Name of the track which played the step.
+    pub track_name: String,
+
+    /// Index of the step within its track's steps, or
+    /// within its track's fill if [Self::fill] is set.
+    pub step: usize,
+
+    /// Whether the step is from its track's fill.
+    pub fill: bool,
+
+    /// Bar in which the step played, counting from `0`.
+    pub bar: u64,
+
+    /// Beat at which the step played.
+    pub beat: f64,
+
+    /// Velocity of the step.
+    pub velocity: u8,
+
+    /// Pitch of the step, in semitones.
+    pub pitch: i8,
+
+    /// Volume the step played at, including its velocity and track volume.
+    pub volume: f32,
+
+    /// Clock time at which the step was due to play, in seconds.
+    pub time: f64,
+}
+
+/// Queue of [StepEvent]s from one or more pieces, optionally
+/// limited to tracks with particular names.
+///
+/// Clones share the same queue, so a clone can be
+/// given to each piece while the original drains it.
+#[derive(Clone, Debug, Default)]
+pub struct Subscription {
+    /// Names of the tracks to receive events from, or every track if empty.
+    tracks: Rc<Vec<String>>,
+
+    /// Events which haven't been drained yet.
+    queue: Rc<RefCell<Vec<StepEvent>>>,
+}
+
+impl Subscription {
+    /// Returns a new subscription to every track.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the subscription to the tracks called `names`.
+    pub fn with_tracks<S: ToString>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+        self.tracks = Rc::new(names.into_iter().map(|name| name.to_string()).collect());
+        self
+    }
+
+    /// Returns true if the subscription receives events from the track called `name`.
+    pub fn wants(&self, name: &str) -> bool {
+        self.tracks.is_empty() || self.tracks.iter().any(|track| track == name)
+    }
+
+    /// Queues `event`, if the subscription wants it.
+    pub fn push(&self, event: &StepEvent) {
+        if self.wants(&event.track_name) {
+            self.queue.borrow_mut().push(event.clone());
+        }
+    }
+
+    /// Removes and returns every queued event, in the order they played.
+    pub fn drain(&self) -> Vec<StepEvent> {
+        self.queue.take()
+    }
+
+    /// Discards every queued event.
+    pub fn clear(&self) {
+        self.queue.borrow_mut().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::backend::{NullBackend, SoundId};
+    use super::super::scheduler::FakeClock;
+    use super::super::{Piece, Track};
+    use super::*;
+
+    #[test]
+    fn subscriptions_receive_steps_from_their_tracks() {
+        let clock = FakeClock::new();
+        let kick = Track::new(SoundId(0), [127, 0]).with_name("kick");
+        let hat = Track::new(SoundId(1), [0, 127]).with_name("hat");
+        let mut piece = Piece::new(Rc::new(NullBackend::default()), kick, 120.0)
+            .with(hat)
+            .with_clock(clock.clone());
+        piece.set_track_volume(0, 1.0);
+        piece.set_track_volume(1, 1.0);
+
+        let everything = Subscription::new();
+        let hats = Subscription::new().with_tracks(["hat"]);
+        piece.subscribe(&everything);
+        piece.subscribe(&hats);
+
+        // Play the first half second, four sixteenth steps.
+        for frame in 0..30 {
+            clock.set(frame as f64 / 60.0);
+            piece.update();
+        }
+
+        let steps = |subscription: &Subscription| -> Vec<(String, usize, f64)> {
+            subscription
+                .drain()
+                .into_iter()
+                .map(|event| (event.track_name, event.step, event.time))
+                .collect()
+        };
+        assert_eq!(
+            steps(&everything),
+            [
+                ("kick".to_string(), 0, 0.0),
+                ("hat".to_string(), 1, 0.125),
+                ("kick".to_string(), 0, 0.25),
+                ("hat".to_string(), 1, 0.375),
+            ]
+        );
+        assert_eq!(
+            steps(&hats),
+            [("hat".to_string(), 1, 0.125), ("hat".to_string(), 1, 0.375)]
+        );
+
+        // Draining empties the queue, as does clearing it.
+        assert!(everything.drain().is_empty());
+        for frame in 30..40 {
+            clock.set(frame as f64 / 60.0);
+            piece.update();
+        }
+        hats.clear();
+        assert!(hats.drain().is_empty());
+        assert_eq!(everything.drain().len(), 2);
+    }
+}
@@ -0,0 +1,661 @@
+//! Standard MIDI file import.
+//!
+//! MIDI files are imported as [Composition]s, with each MIDI track or
+//! note number mapped onto a [Track](super::Track) playing an embedded
+//! sample. Notes are quantised to the nearest step, and keep their
+//! velocity as the step's velocity. Mappings with a root note also
+//! keep each note's pitch, relative to the root.
+//!
+//! Only the first tempo and time signature are imported; later changes
+//! are reported as [MidiWarning]s.
+
+use std::fmt;
+
+use super::arrangement::Arrangement;
+use super::effects::EffectChain;
+use super::pattern::{Composition, MIDDLE_C, SoundSource, TrackPattern};
+use super::{MAX_VELOCITY, Resolution, SAMPLES, Step, TimeSignature, VoiceStealing};
+
+/// Most steps a track imported from a MIDI file can hold.
+pub const MAX_STEPS: usize = 4096;
+
+/// Highest MIDI note number.
+pub const MAX_KEY: u8 = 127;
+
+/// Notes and timing read from a standard MIDI file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiFile {
+    /// Number of ticks in each beat (quarter note).
+    pub ticks_per_beat: u16,
+
+    /// The file's first tempo, in beats per minute, if it sets one.
+    pub tempo_bpm: Option<f32>,
+
+    /// The file's first time signature, if it sets one.
+    pub time_signature: Option<TimeSignature>,
+
+    /// Every note started in the file, ordered by track and time.
+    pub notes: Vec<MidiNote>,
+
+    /// Events in the file which were ignored.
+    pub warnings: Vec<MidiWarning>,
+}
+
+/// A single note started in a [MidiFile].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MidiNote {
+    /// Index of the MIDI track containing the note.
+    pub track: usize,
+
+    /// MIDI channel of the note.
+    pub channel: u8,
+
+    /// MIDI note number, where `60` is middle C.
+    pub key: u8,
+
+    /// Velocity of the note, from `1` to `127`.
+    pub velocity: u8,
+
+    /// Time at which the note starts, in ticks.
+    pub tick: u64,
+}
+
+/// Notes in a [MidiFile] which map onto a single track.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiSource {
+    /// Every note in the MIDI track at this index.
+    Track(usize),
+
+    /// Every note with this MIDI note number, in any track.
+    Note(u8),
+}
+
+/// Maps notes in a [MidiFile] onto a track playing an embedded sample.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiMapping {
+    /// Notes played by the track.
+    pub source: MidiSource,
+
+    /// Name of the embedded sample the track plays.
+    pub asset: String,
+
+    /// MIDI note number the sample plays at, or `None` to play
+    /// every note at the sample's own pitch.
+    pub root: Option<u8>,
+}
+
+impl MidiFile {
+    /// Parses a standard MIDI file.
+    pub fn parse(bytes: &[u8]) -> Result<Self, MidiError> {
+        let mut reader = Reader { bytes };
+
+        // Read the header chunk.
+        let (id, mut header) = reader.chunk()?;
+        if id != *b"MThd" {
+            return Err(MidiError::InvalidHeader);
+        }
+        let _format = header.u16()?;
+        let track_count = header.u16()?;
+        let division = header.u16()?;
+        if division & 0x8000 != 0 {
+            return Err(MidiError::UnsupportedTimecode);
+        }
+        if division == 0 {
+            return Err(MidiError::InvalidDivision);
+        }
+
+        let mut file = MidiFile {
+            ticks_per_beat: division,
+            tempo_bpm: None,
+            time_signature: None,
+            notes: vec![],
+            warnings: vec![],
+        };
+
+        // Read each track chunk, skipping any unknown chunks.
+        let mut track = 0;
+        while track < track_count as usize && !reader.bytes.is_empty() {
+            let (id, chunk) = reader.chunk()?;
+            if id == *b"MTrk" {
+                file.parse_track(track, chunk)?;
+                track += 1;
+            }
+        }
+
+        Ok(file)
+    }
+
+    /// Parses the events in a single track chunk.
+    fn parse_track(&mut self, track: usize, mut chunk: Reader) -> Result<(), MidiError> {
+        let mut tick = 0;
+        let mut running_status = None;
+
+        while !chunk.bytes.is_empty() {
+            tick += chunk.variable_length()?;
+
+            // Data bytes without a status byte reuse the previous status.
+            let mut status = chunk.u8()?;
+            let first_data = if status & 0x80 == 0 {
+                let first_data = status;
+                status = running_status.ok_or(MidiError::MissingStatus)?;
+                Some(first_data)
+            } else {
+                None
+            };
+
+            match status {
+                // Meta events.
+                0xff => {
+                    let kind = chunk.u8()?;
+                    let len = chunk.variable_length()? as usize;
+                    let data = chunk.slice(len)?;
+
+                    // Set tempo, in microseconds per beat.
+                    if kind == 0x51 && len == 3 {
+                        let micros = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                        if self.tempo_bpm.is_some() {
+                            self.warnings.push(MidiWarning::TempoChange { track, tick });
+                        } else if micros > 0 {
+                            self.tempo_bpm = Some(60_000_000.0 / micros as f32);
+                        }
+                    }
+
+                    // Time signature, with the unit as a power of two.
+                    if kind == 0x58 && len >= 2 {
+                        let unit = 1u32.checked_shl(data[1] as u32).unwrap_or(0);
+                        if self.time_signature.is_some() {
+                            self.warnings
+                                .push(MidiWarning::TimeSignatureChange { track, tick });
+                        } else if data[0] > 0 && unit > 0 {
+                            self.time_signature = Some(TimeSignature::new(data[0] as u32, unit));
+                        }
+                    }
+                }
+
+                // System exclusive events.
+                0xf0 | 0xf7 => {
+                    let len = chunk.variable_length()? as usize;
+                    chunk.slice(len)?;
+                }
+
+                // Channel events.
+                0x80..=0xef => {
+                    running_status = Some(status);
+                    let data_len = match status & 0xf0 {
+                        0xc0 | 0xd0 => 1,
+                        _ => 2,
+                    };
+
+                    let mut data = [0; 2];
+                    for (i, byte) in data.iter_mut().take(data_len).enumerate() {
+                        *byte = match (i, first_data) {
+                            (0, Some(first_data)) => first_data,
+                            _ => chunk.u8()?,
+                        };
+                    }
+
+                    // Note-on events with zero velocity are note-offs.
+                    if status & 0xf0 == 0x90 && data[1] > 0 {
+                        if data[0] > MAX_KEY {
+                            return Err(MidiError::InvalidKey(data[0]));
+                        }
+                        self.notes.push(MidiNote {
+                            track,
+                            channel: status & 0x0f,
+                            key: data[0],
+                            velocity: data[1],
+                            tick,
+                        });
+                    }
+                }
+
+                _ => return Err(MidiError::InvalidStatus(status)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns a mapping for each MIDI track containing notes, playing
+    /// the embedded [SAMPLES] in turn, pitched relative to middle C.
+    ///
+    /// Files with more tracks than there are samples reuse the samples.
+    pub fn default_mappings(&self) -> Vec<MidiMapping> {
+        let mut tracks: Vec<usize> = self.notes.iter().map(|n| n.track).collect();
+        tracks.dedup();
+
+        tracks
+            .into_iter()
+            .zip(SAMPLES.iter().cycle())
+            .map(|(track, (asset, _))| MidiMapping {
+                source: MidiSource::Track(track),
+                asset: asset.to_string(),
+                root: Some(MIDDLE_C as u8),
+            })
+            .collect()
+    }
+
+    /// Converts the file into a composition, with one track per
+    /// mapping, quantised to steps of `resolution`.
+    ///
+    /// Every track is padded to the same whole number of bars, in
+    /// the file's time signature (or `4/4`). When several notes land on one step, the
+    /// step takes the loudest note's velocity (and pitch).
+    ///
+    /// Tracks are named after their sample, numbered from the
+    /// second track playing the same sample, like `decay4-2`.
+    ///
+    /// Fails if any note lands on or after step [MAX_STEPS], or
+    /// if a mapping's key or root is above [MAX_KEY].
+    pub fn to_composition(
+        &self,
+        mappings: &[MidiMapping],
+        resolution: Resolution,
+    ) -> Result<Composition, MidiError> {
+        if self.ticks_per_beat == 0 {
+            return Err(MidiError::InvalidDivision);
+        }
+
+        let ticks_per_step = self.ticks_per_beat as f64 / resolution.steps_per_beat();
+        let quantise = |tick: u64| (tick as f64 / ticks_per_step).round() as usize;
+
+        // Pad every track to the end of the bar containing the last note.
+        let time_signature = self.time_signature.unwrap_or(super::TIME_SIGNATURE);
+        let steps_per_bar =
+            ((resolution.steps_per_beat() * time_signature.bar_beats()).ceil() as usize).max(1);
+        let last_step = self
+            .notes
+            .iter()
+            .map(|n| quantise(n.tick))
+            .max()
+            .unwrap_or(0);
+        if last_step >= MAX_STEPS {
+            return Err(MidiError::TooLong { steps: last_step });
+        }
+        let step_count = (last_step / steps_per_bar + 1) * steps_per_bar;
+
+        let mut tracks: Vec<TrackPattern> = vec![];
+        for mapping in mappings {
+            if !SAMPLES.iter().any(|(name, _)| *name == mapping.asset) {
+                return Err(MidiError::UnknownAsset(mapping.asset.clone()));
+            }
+            if let MidiSource::Note(key) = mapping.source
+                && key > MAX_KEY
+            {
+                return Err(MidiError::InvalidKey(key));
+            }
+            if let Some(root) = mapping.root.filter(|root| *root > MAX_KEY) {
+                return Err(MidiError::InvalidKey(root));
+            }
+
+            let mut steps = vec![Step::REST; step_count];
+            let notes = self.notes.iter().filter(|n| match mapping.source {
+                MidiSource::Track(track) => n.track == track,
+                MidiSource::Note(key) => n.key == key,
+            });
+            for note in notes {
+                let step = &mut steps[quantise(note.tick)];
+                if note.velocity.min(MAX_VELOCITY) < step.velocity {
+                    continue;
+                }
+
+                step.velocity = note.velocity.min(MAX_VELOCITY);
+                if let Some(root) = mapping.root {
+                    step.pitch = (note.key as i8).saturating_sub(root as i8);
+                }
+            }
+
+            let mut name = mapping.asset.clone();
+            for number in 2.. {
+                if tracks.iter().all(|track| track.name != name) {
+                    break;
+                }
+                name = format!("{}-{number}", mapping.asset);
+            }
+
+            tracks.push(TrackPattern {
+                name,
+                sound: SoundSource::Asset(mapping.asset.clone()),
+                resolution,
+                steps,
+                effects: EffectChain::new(),
+                voices: 0,
+                voice_stealing: VoiceStealing::default(),
+                mutation: 0.0,
+                fill: None,
+            });
+        }
+
+        if tracks.is_empty() {
+            return Err(MidiError::NoTracks);
+        }
+
+        Ok(Composition {
+            title: String::new(),
+            notes: vec![],
+            tempo_bpm: self.tempo_bpm.unwrap_or(super::TEMPO_BPM),
+            time_signature,
+            swing: 0.0,
+            seed: 0,
+            transpose: 0,
+            arrangement: Arrangement::linear(tracks.len()),
+            tracks,
+        })
+    }
+}
+
+/// Errors encountered while importing a MIDI file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MidiError {
+    /// The file doesn't start with a MIDI header.
+    InvalidHeader,
+
+    /// The file's timing is in SMPTE timecode, rather than beats.
+    UnsupportedTimecode,
+
+    /// The file's header has zero ticks per beat.
+    InvalidDivision,
+
+    /// The file ended unexpectedly.
+    UnexpectedEof,
+
+    /// A track event has an invalid status byte.
+    InvalidStatus(u8),
+
+    /// A track event has no status byte, and there's no running status.
+    MissingStatus,
+
+    /// A note or mapping has a note number above [MAX_KEY].
+    InvalidKey(u8),
+
+    /// A mapping names an asset which isn't embedded.
+    UnknownAsset(String),
+
+    /// No mappings were given.
+    NoTracks,
+
+    /// A note lands on this step, past [MAX_STEPS].
+    TooLong { steps: usize },
+}
+
+impl fmt::Display for MidiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MidiError::InvalidHeader => write!(f, "not a MIDI file"),
+            MidiError::UnsupportedTimecode => write!(f, "SMPTE timecode isn't supported"),
+            MidiError::InvalidDivision => write!(f, "zero ticks per beat"),
+            MidiError::UnexpectedEof => write!(f, "unexpected end of file"),
+            MidiError::InvalidStatus(status) => write!(f, "invalid status byte {status:#04x}"),
+            MidiError::MissingStatus => write!(f, "event without a status byte"),
+            MidiError::InvalidKey(key) => {
+                write!(f, "invalid note number {key} (expected at most {MAX_KEY})")
+            }
+            MidiError::UnknownAsset(asset) => write!(f, "unknown sample asset `{asset}`"),
+            MidiError::NoTracks => write!(f, "no tracks"),
+            MidiError::TooLong { steps } => {
+                write!(
+                    f,
+                    "note on step {steps} (expected at most {MAX_STEPS} steps)"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for MidiError {}
+
+/// Events in a MIDI file which were ignored while importing it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiWarning {
+    /// A tempo change after the first tempo, in `track` at `tick`.
+    TempoChange { track: usize, tick: u64 },
+
+    /// A time signature change after the first
+    /// time signature, in `track` at `tick`.
+    TimeSignatureChange { track: usize, tick: u64 },
+}
+
+impl fmt::Display for MidiWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MidiWarning::TempoChange { track, tick } => write!(
+                f,
+                "track {track}, tick {tick}: ignored tempo change (only the first tempo is used)"
+            ),
+            MidiWarning::TimeSignatureChange { track, tick } => write!(
+                f,
+                "track {track}, tick {tick}: ignored time signature change \
+                 (only the first time signature is used)"
+            ),
+        }
+    }
+}
+
+/// Big-endian reader for MIDI data.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn slice(&mut self, len: usize) -> Result<&'a [u8], MidiError> {
+        if self.bytes.len() < len {
+            return Err(MidiError::UnexpectedEof);
+        }
+
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, MidiError> {
+        Ok(self.slice(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MidiError> {
+        Ok(u16::from_be_bytes(self.slice(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, MidiError> {
+        Ok(u32::from_be_bytes(self.slice(4)?.try_into().unwrap()))
+    }
+
+    /// Reads a chunk's ID and a reader over its contents.
+    fn chunk(&mut self) -> Result<([u8; 4], Reader<'a>), MidiError> {
+        let id = self.slice(4)?.try_into().unwrap();
+        let len = self.u32()? as usize;
+        let bytes = self.slice(len)?;
+        Ok((id, Reader { bytes }))
+    }
+
+    /// Reads a variable-length quantity of up to four bytes.
+    fn variable_length(&mut self) -> Result<u64, MidiError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7f) as u64;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// Ticks per beat in the test files.
+    const DIVISION: u16 = 96;
+
+    /// Returns a format 1 MIDI file containing a track chunk for each of `tracks`.
+    fn smf(tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend(6u32.to_be_bytes());
+        bytes.extend(1u16.to_be_bytes());
+        bytes.extend((tracks.len() as u16).to_be_bytes());
+        bytes.extend(DIVISION.to_be_bytes());
+        for track in tracks {
+            bytes.extend(b"MTrk");
+            bytes.extend((track.len() as u32).to_be_bytes());
+            bytes.extend(*track);
+        }
+        bytes
+    }
+
+    /// Returns the key and tick of each note in `file`.
+    fn keys_and_ticks(file: &MidiFile) -> Vec<(u8, u64)> {
+        file.notes
+            .iter()
+            .map(|note| (note.key, note.tick))
+            .collect()
+    }
+
+    #[test]
+    fn parses_the_header_and_notes() {
+        let file = MidiFile::parse(&smf(&[
+            &[0x00, 0x90, 0x3c, 0x40, 0x60, 0x80, 0x3c, 0x00],
+            &[0x00, 0x99, 0x24, 0x7f],
+        ]))
+        .unwrap();
+
+        assert_eq!(file.ticks_per_beat, DIVISION);
+        assert_eq!((file.tempo_bpm, file.time_signature), (None, None));
+        assert_eq!(
+            file.notes,
+            [
+                MidiNote {
+                    track: 0,
+                    channel: 0,
+                    key: 0x3c,
+                    velocity: 0x40,
+                    tick: 0,
+                },
+                MidiNote {
+                    track: 1,
+                    channel: 9,
+                    key: 0x24,
+                    velocity: 0x7f,
+                    tick: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        let mut bytes = smf(&[]);
+        bytes[0] = b'X';
+        assert_eq!(MidiFile::parse(&bytes), Err(MidiError::InvalidHeader));
+
+        let mut bytes = smf(&[]);
+        bytes[12] = 0xe7;
+        assert_eq!(MidiFile::parse(&bytes), Err(MidiError::UnsupportedTimecode));
+
+        let mut bytes = smf(&[]);
+        bytes[12..14].copy_from_slice(&[0, 0]);
+        assert_eq!(MidiFile::parse(&bytes), Err(MidiError::InvalidDivision));
+
+        let bytes = smf(&[&[0x00, 0x90, 0x3c, 0x40]]);
+        assert_eq!(
+            MidiFile::parse(&bytes[..bytes.len() - 1]),
+            Err(MidiError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn running_status_reuses_the_previous_status() {
+        let file = MidiFile::parse(&smf(&[&[
+            0x00, 0x90, 0x3c, 0x40, // Note on.
+            0x10, 0x3e, 0x40, // Note on, with running status.
+            0x10, 0x3c, 0x00, // Note off, as a note on without velocity.
+            0x10, 0x40, 0x40, // Note on again.
+        ]]))
+        .unwrap();
+        assert_eq!(keys_and_ticks(&file), [(0x3c, 0), (0x3e, 16), (0x40, 48)]);
+
+        // Without a previous status, there's nothing to run.
+        assert_eq!(
+            MidiFile::parse(&smf(&[&[0x00, 0x3c, 0x40]])),
+            Err(MidiError::MissingStatus)
+        );
+    }
+
+    #[test]
+    fn reads_variable_length_deltas() {
+        let file = MidiFile::parse(&smf(&[&[
+            0x81, 0x00, 0x90, 0x3c, 0x40, // 128 ticks.
+            0x83, 0xff, 0x7f, 0x90, 0x3c, 0x40, // 65535 ticks.
+        ]]))
+        .unwrap();
+        assert_eq!(keys_and_ticks(&file), [(0x3c, 128), (0x3c, 128 + 65535)]);
+    }
+
+    #[test]
+    fn keeps_the_first_tempo_and_time_signature() {
+        let file = MidiFile::parse(&smf(&[&[
+            0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // 500000 µs per beat.
+            0x00, 0xff, 0x58, 0x04, 0x03, 0x02, 0x18, 0x08, // 3/4.
+            0x60, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40, // 1000000 µs per beat.
+            0x00, 0xff, 0x58, 0x04, 0x06, 0x03, 0x18, 0x08, // 6/8.
+        ]]))
+        .unwrap();
+
+        assert_eq!(file.tempo_bpm, Some(120.0));
+        assert_eq!(file.time_signature, Some(TimeSignature::new(3, 4)));
+        assert_eq!(
+            file.warnings,
+            [
+                MidiWarning::TempoChange { track: 0, tick: 96 },
+                MidiWarning::TimeSignatureChange { track: 0, tick: 96 },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_keys_above_127() {
+        assert_eq!(
+            MidiFile::parse(&smf(&[&[0x00, 0x90, 0x80, 0x40]])),
+            Err(MidiError::InvalidKey(0x80))
+        );
+
+        let file = MidiFile::parse(&smf(&[&[0x00, 0x90, 0x3c, 0x40]])).unwrap();
+        let (asset, _) = SAMPLES[0];
+        let mappings = [
+            (MidiSource::Note(200), None, 200),
+            (MidiSource::Track(0), Some(128), 128),
+        ];
+        for (source, root, key) in mappings {
+            let mapping = MidiMapping {
+                source,
+                asset: asset.to_string(),
+                root,
+            };
+            assert_eq!(
+                file.to_composition(&[mapping], Resolution::Sixteenth),
+                Err(MidiError::InvalidKey(key))
+            );
+        }
+    }
+
+    #[test]
+    fn default_mappings_name_every_track_uniquely() {
+        let note: &[u8] = &[0x00, 0x90, 0x3c, 0x40];
+        let tracks = vec![note; SAMPLES.len() + 2];
+        let file = MidiFile::parse(&smf(&tracks)).unwrap();
+
+        let mappings = file.default_mappings();
+        assert_eq!(mappings.len(), tracks.len());
+        let composition = file
+            .to_composition(&mappings, Resolution::Sixteenth)
+            .unwrap();
+
+        let names: Vec<&str> = composition.tracks.iter().map(|t| t.name.as_str()).collect();
+        let unique: BTreeSet<&str> = names.iter().copied().collect();
+        assert_eq!(unique.len(), names.len());
+        assert_eq!(names[SAMPLES.len()], format!("{}-2", SAMPLES[0].0));
+    }
+}
@@ -0,0 +1,415 @@
+//! Offline rendering of [Piece]s to WAV.
+//!
+//! A [Mixdown] plays a piece against a [FakeClock] as fast as it can,
+//! through a [MixdownBackend] which logs every sound it's asked to play
+//! rather than playing it. The log is then mixed into a single buffer,
+//! with each sound at its exact start time and volume.
+//!
+//! Renders are deterministic, so composers can preview a level's
+//! arrangement without playing it, and renders can be compared
+//! against earlier ones.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use super::arrangement::{Arranger, MusicEvent};
+use super::backend::{AudioBackend, AudioError, AudioEvent, LoadFuture, PlayParams, SoundId};
+use super::midi::{MidiError, MidiFile};
+use super::pattern::{Composition, PatternError};
+use super::scheduler::{AudioClock, FakeClock};
+use super::wav::{SAMPLE_RATE, Wav};
+use super::{Boundary, LEVEL_PIECES, Piece, Resolution};
+
+/// Command-line argument rendering a level's music to a WAV file,
+/// followed by the level's index, the number of phrases and the path.
+///
+/// A path to a `.mid` file can be given instead of a level, to render
+/// the MIDI file with [MidiFile::default_mappings].
+pub const MIXDOWN_ARG: &str = "--mixdown";
+
+/// Interval at which a piece is updated while rendering, in seconds.
+const UPDATE_INTERVAL: f64 = 0.05;
+
+/// Backend which plays nothing, but logs when each sound plays,
+/// so that the log can be mixed into a single buffer.
+#[derive(Default)]
+pub struct MixdownBackend {
+    /// Clock used to time volume changes, and stops which aren't scheduled.
+    clock: FakeClock,
+
+    /// Loaded sounds, in stereo at [SAMPLE_RATE].
+    sounds: RefCell<Vec<Wav>>,
+
+    /// Every event logged so far, with the time it applies from.
+    events: RefCell<Vec<(f64, AudioEvent)>>,
+}
+
+impl MixdownBackend {
+    /// Returns a new backend, timing events with `clock`.
+    pub fn new(clock: FakeClock) -> Self {
+        Self {
+            clock,
+            ..Default::default()
+        }
+    }
+
+    /// Mixes every sound played before `end` seconds into a stereo
+    /// buffer, including the tails of sounds still playing at `end`.
+    ///
+    /// Looped sounds play until they're stopped, or until `end`.
+    pub fn mix(&self, end: f64) -> Wav {
+        let sounds = self.sounds.borrow();
+        let events = self.events.borrow();
+        let rate = SAMPLE_RATE as f64;
+        let end_frame = (end * rate).round() as usize;
+
+        // Group volume changes and stops by sound, with the index
+        // of each event so plays only pick up later changes.
+        let mut changes: BTreeMap<SoundId, Vec<(usize, usize, Option<f32>)>> = BTreeMap::new();
+        for (i, &(time, event)) in events.iter().enumerate() {
+            let (sound, change) = match event {
+                AudioEvent::SetVolume { sound, volume } => (sound, Some(volume)),
+                AudioEvent::Stop { sound } => (sound, None),
+                AudioEvent::Play { .. } => continue,
+            };
+            let frame = (time * rate).round() as usize;
+            changes.entry(sound).or_default().push((i, frame, change));
+        }
+
+        let mut samples: Vec<f32> = vec![0.0; end_frame * 2];
+        for (i, &(time, event)) in events.iter().enumerate() {
+            let AudioEvent::Play { sound, params } = event else {
+                continue;
+            };
+            if time >= end {
+                continue;
+            }
+
+            // Later changes to the sound apply to this instance too.
+            let changes = changes.get(&sound).map_or(&[][..], |changes| {
+                &changes[changes.partition_point(|(index, ..)| *index <= i)..]
+            });
+
+            let wav = &sounds[sound.0];
+            let frames = wav.frames();
+            if frames == 0 {
+                continue;
+            }
+
+            let start = (time * rate).round() as usize;
+            let mut volume = params.volume;
+            let mut changes = changes
+                .iter()
+                .map(|&(_, frame, change)| (frame, change))
+                .peekable();
+            for frame in start.. {
+                let source = frame - start;
+                if (params.looped && frame >= end_frame) || (!params.looped && source >= frames) {
+                    break;
+                }
+
+                // Apply any changes which are due.
+                let mut stopped = false;
+                while let Some((_, change)) = changes.next_if(|(at, _)| *at <= frame) {
+                    match change {
+                        Some(changed) => volume = changed,
+                        None => stopped = true,
+                    }
+                }
+                if stopped {
+                    break;
+                }
+
+                if samples.len() < (frame + 1) * 2 {
+                    samples.resize((frame + 1) * 2, 0.0);
+                }
+                let source = source % frames;
+                samples[frame * 2] += wav.samples[source * 2] * volume;
+                samples[frame * 2 + 1] += wav.samples[source * 2 + 1] * volume;
+            }
+        }
+
+        Wav {
+            channels: 2,
+            sample_rate: SAMPLE_RATE,
+            samples,
+        }
+    }
+}
+
+impl AudioBackend for MixdownBackend {
+    fn load<'a>(&'a self, bytes: &'a [u8]) -> LoadFuture<'a> {
+        let wav = Wav::decode(bytes).map_err(|e| AudioError::Decode(e.to_string()));
+        let sound = wav.map(|wav| {
+            let mut sounds = self.sounds.borrow_mut();
+            sounds.push(wav.to_stereo().resampled(SAMPLE_RATE));
+            SoundId(sounds.len() - 1)
+        });
+
+        Box::pin(std::future::ready(sound))
+    }
+
+    fn play(&self, sound: SoundId, params: PlayParams) {
+        self.events
+            .borrow_mut()
+            .push((params.time, AudioEvent::Play { sound, params }));
+    }
+
+    fn set_volume(&self, sound: SoundId, volume: f32) {
+        self.events
+            .borrow_mut()
+            .push((self.clock.now(), AudioEvent::SetVolume { sound, volume }));
+    }
+
+    fn stop(&self, sound: SoundId) {
+        self.stop_at(sound, self.clock.now());
+    }
+
+    fn stop_at(&self, sound: SoundId, time: f64) {
+        self.events
+            .borrow_mut()
+            .push((time, AudioEvent::Stop { sound }));
+    }
+}
+
+/// Renders pieces offline, through a shared [MixdownBackend].
+pub struct Mixdown {
+    clock: FakeClock,
+    backend: Rc<MixdownBackend>,
+}
+
+impl Default for Mixdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mixdown {
+    /// Returns a new mixdown, with nothing loaded.
+    pub fn new() -> Self {
+        let clock = FakeClock::new();
+        Self {
+            backend: Rc::new(MixdownBackend::new(clock.clone())),
+            clock,
+        }
+    }
+
+    /// Returns the backend pieces must load their sounds into to be rendered.
+    pub fn backend(&self) -> Rc<dyn AudioBackend> {
+        self.backend.clone()
+    }
+
+    /// Plays `piece` for `phrases` phrases, and mixes it into a stereo buffer.
+    ///
+    /// If there's an `arranger`, the piece follows its arrangement from
+    /// the start of a level, unlocking a group of tracks every phrase;
+    /// otherwise it plays at its tracks' current volumes.
+    pub fn render(&self, piece: Piece, mut arranger: Option<Arranger>, phrases: u32) -> Wav {
+        let mut piece = piece.with_clock(self.clock.clone());
+        let phrase_beats = Boundary::Phrase.beats(piece.time_signature()).unwrap();
+        let end_beat = phrases as f64 * phrase_beats;
+
+        if let Some(arranger) = &mut arranger {
+            arranger.handle(MusicEvent::Start, &mut piece);
+        }
+
+        let mut next_unlock = 0.0;
+        while piece.beat() < end_beat {
+            if let Some(arranger) = &mut arranger {
+                let unlocks = arranger.arrangement().unlocks.len();
+                if piece.beat() >= next_unlock && arranger.unlocked() < unlocks {
+                    arranger.handle(MusicEvent::Objective, &mut piece);
+                    next_unlock += phrase_beats;
+                }
+                arranger.update(&mut piece);
+            }
+
+            piece.update();
+            self.clock.advance(UPDATE_INTERVAL);
+        }
+
+        let end = piece.time_at(end_beat);
+        self.backend.mix(end)
+    }
+}
+
+/// Renders `phrases` phrases of the music for the level at `level`,
+/// following its arrangement, encoded as WAV.
+pub fn render_level(level: usize, phrases: u32) -> Result<Vec<u8>, MixdownError> {
+    let source = LEVEL_PIECES[level % LEVEL_PIECES.len()];
+    let composition = Composition::parse(source).map_err(MixdownError::Pattern)?;
+    render_composition(composition, phrases)
+}
+
+/// Renders `phrases` phrases of a standard MIDI file, with each of its
+/// tracks playing an embedded sample, encoded as WAV.
+pub fn render_midi(bytes: &[u8], phrases: u32) -> Result<Vec<u8>, MixdownError> {
+    let file = MidiFile::parse(bytes).map_err(MixdownError::Midi)?;
+    for warning in &file.warnings {
+        eprintln!("{warning}");
+    }
+    let composition = file
+        .to_composition(&file.default_mappings(), Resolution::default())
+        .map_err(MixdownError::Midi)?;
+    render_composition(composition, phrases)
+}
+
+/// Renders `phrases` phrases of `composition`, following
+/// its arrangement, encoded as WAV.
+fn render_composition(composition: Composition, phrases: u32) -> Result<Vec<u8>, MixdownError> {
+    let mixdown = Mixdown::new();
+    let piece = block_on(composition.into_piece(mixdown.backend())).map_err(MixdownError::Audio)?;
+    let arranger = Arranger::new(composition.arrangement);
+
+    Ok(mixdown.render(piece, Some(arranger), phrases).encode())
+}
+
+/// Runs the mixdown command, if it's in `args`, rendering the chosen
+/// level's music (or MIDI file) to a WAV file.
+///
+/// Returns `None` if `args` don't include [MIXDOWN_ARG].
+#[cfg(not(target_arch = "wasm32"))]
+pub fn run(args: impl IntoIterator<Item = String>) -> Option<Result<String, MixdownError>> {
+    let mut args = args.into_iter().skip_while(|arg| arg != MIXDOWN_ARG);
+    args.next()?;
+    let Some(level) = args.next() else {
+        return Some(Err(MixdownError::Usage));
+    };
+    let (phrases, path) = (args.next(), args.next());
+
+    let result = (|| {
+        let phrases = match phrases {
+            Some(phrases) => phrases.parse().map_err(|_| MixdownError::Usage)?,
+            None => 4,
+        };
+
+        let (wav, default_path) = match level.strip_suffix(".mid") {
+            Some(name) => {
+                let bytes = std::fs::read(&level)
+                    .map_err(|e| MixdownError::Io(format!("failed to read {level}: {e}")))?;
+                (render_midi(&bytes, phrases)?, format!("{name}.wav"))
+            }
+            None => {
+                let level: usize = level.parse().map_err(|_| MixdownError::Usage)?;
+                (render_level(level, phrases)?, format!("level-{level}.wav"))
+            }
+        };
+        let path = path.unwrap_or(default_path);
+
+        std::fs::write(&path, wav)
+            .map_err(|e| MixdownError::Io(format!("failed to write mixdown to {path}: {e}")))?;
+        Ok(path)
+    })();
+
+    Some(result)
+}
+
+/// Polls `future` until it's ready.
+///
+/// Only suitable for futures which never wait on anything
+/// else, like loading into a [MixdownBackend].
+pub(super) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
+
+/// Errors encountered while rendering a mixdown.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MixdownError {
+    /// The command-line arguments are invalid.
+    Usage,
+
+    /// The level's piece couldn't be parsed.
+    Pattern(PatternError),
+
+    /// The MIDI file couldn't be imported.
+    Midi(MidiError),
+
+    /// The level's sounds couldn't be loaded.
+    Audio(AudioError),
+
+    /// A file couldn't be read or the render couldn't be written.
+    Io(String),
+}
+
+impl fmt::Display for MixdownError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MixdownError::Usage => {
+                write!(f, "usage: {MIXDOWN_ARG} <level|file.mid> [phrases] [path]")
+            }
+            MixdownError::Pattern(error) => write!(f, "invalid piece: {error}"),
+            MixdownError::Midi(error) => write!(f, "invalid MIDI file: {error}"),
+            MixdownError::Audio(error) => write!(f, "{error}"),
+            MixdownError::Io(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for MixdownError {}
+
+#[cfg(test)]
+mod tests {
+    use super::super::arrangement::Arrangement;
+    use super::*;
+
+    /// A piece of synths only, so it renders without any assets.
+    const SYNTH_PIECE: &str = "tempo 120
+        seed 7
+        synth blip wave=square freq=440 attack=0 decay=0.05 sustain=0 release=0
+        synth hat wave=noise attack=0 decay=0.02 sustain=0 release=0
+        track blip ..x.
+        track hat x.x.x.x.";
+
+    #[test]
+    fn renders_are_identical() {
+        let composition = || Composition::parse(SYNTH_PIECE).unwrap();
+        let first = render_composition(composition(), 2).unwrap();
+        let second = render_composition(composition(), 2).unwrap();
+
+        assert!(first.len() > 44);
+        assert!(first == second, "renders differ");
+    }
+
+    #[test]
+    fn sounds_start_on_the_frame_of_their_event() {
+        let composition = Composition::parse(SYNTH_PIECE).unwrap();
+        let blip = Composition {
+            tracks: composition.tracks[..1].to_vec(),
+            arrangement: Arrangement::linear(1),
+            ..composition
+        };
+        let mixdown = Mixdown::new();
+        let piece = block_on(blip.into_piece(mixdown.backend())).unwrap();
+        let wav = mixdown.render(piece, Some(Arranger::new(blip.arrangement)), 1);
+
+        let plays: Vec<f64> = mixdown
+            .backend
+            .events
+            .borrow()
+            .iter()
+            .filter(|(_, event)| matches!(event, AudioEvent::Play { .. }))
+            .map(|&(time, _)| time)
+            .collect();
+        assert!(plays.len() > 1);
+
+        // Each blip is silent before its frame, and sounds on it.
+        for time in plays {
+            let frame = (time * SAMPLE_RATE as f64).round() as usize;
+            assert_eq!(
+                wav.samples[frame * 2 - 2],
+                0.0,
+                "blip at {time} starts early"
+            );
+            assert_ne!(wav.samples[frame * 2], 0.0, "blip at {time} starts late");
+        }
+    }
+}
@@ -0,0 +1,448 @@
+//! Mixing of the game's sounds into buses.
+//!
+//! Every sound plays through one of a [Mixer]'s [Bus]es, each with its
+//! own gain and mute, underneath the master bus. The mixer can also
+//! duck, briefly lowering some buses (like the music) so that a sound
+//! effect stands out.
+//!
+//! Each bus is itself an [AudioBackend], so pieces and other sound
+//! sources are routed through a bus by being given it as their backend.
+//!
+//! Buses can also have [effects](super::effects), applied to sounds as
+//! they're loaded through the bus. A bus's send loads a processed copy
+//! of each sound alongside the original, and the mixer crossfades between
+//! the two, so the effect can be faded in and out as the game plays.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use super::backend::{AudioBackend, AudioError, LoadFuture, PlayParams, SoundId};
+use super::effects::EffectChain;
+use super::wav::Wav;
+
+/// Rate at which ducking lowers and restores volume, in gain per second.
+const DUCK_RATE: f32 = 4.0;
+
+/// A bus in a [Mixer].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Bus {
+    /// The bus every other bus plays through.
+    Master,
+
+    /// Level pieces.
+    Music,
+
+    /// Background loops, like the vinyl texture and objective hums.
+    Ambience,
+
+    /// One-shot sound effects.
+    Sfx,
+}
+
+impl Bus {
+    /// Every bus, in the order they're usually listed in settings.
+    pub const ALL: [Bus; 4] = [Bus::Master, Bus::Music, Bus::Ambience, Bus::Sfx];
+
+    /// Returns the bus's name, for display in settings.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Bus::Master => "master",
+            Bus::Music => "music",
+            Bus::Ambience => "ambience",
+            Bus::Sfx => "sfx",
+        }
+    }
+}
+
+/// User settings for a single [Bus].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BusSettings {
+    /// Gain of the bus, from `0.0` (silent) to `1.0` (full volume).
+    pub gain: f32,
+
+    /// Whether the bus is muted, regardless of its gain.
+    pub muted: bool,
+
+    /// Whether the bus is lowered when the mixer ducks.
+    pub ducked: bool,
+}
+
+impl BusSettings {
+    /// Returns settings for an unmuted bus at full volume.
+    pub const fn new(ducked: bool) -> Self {
+        Self {
+            gain: 1.0,
+            muted: false,
+            ducked,
+        }
+    }
+
+    /// Returns the bus's volume, accounting for its mute.
+    pub fn volume(&self) -> f32 {
+        if self.muted { 0.0 } else { self.gain }
+    }
+}
+
+/// User settings for every [Bus] in a [Mixer].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MixerSettings {
+    pub master: BusSettings,
+    pub music: BusSettings,
+    pub ambience: BusSettings,
+    pub sfx: BusSettings,
+}
+
+impl Default for MixerSettings {
+    fn default() -> Self {
+        Self {
+            master: BusSettings::new(false),
+            music: BusSettings::new(true),
+            ambience: BusSettings::new(true),
+            sfx: BusSettings::new(false),
+        }
+    }
+}
+
+impl MixerSettings {
+    /// Returns the settings for `bus`.
+    pub fn bus(&self, bus: Bus) -> &BusSettings {
+        match bus {
+            Bus::Master => &self.master,
+            Bus::Music => &self.music,
+            Bus::Ambience => &self.ambience,
+            Bus::Sfx => &self.sfx,
+        }
+    }
+
+    /// Returns the settings for `bus`, for changing them.
+    pub fn bus_mut(&mut self, bus: Bus) -> &mut BusSettings {
+        match bus {
+            Bus::Master => &mut self.master,
+            Bus::Music => &mut self.music,
+            Bus::Ambience => &mut self.ambience,
+            Bus::Sfx => &mut self.sfx,
+        }
+    }
+}
+
+/// Effects applied to the sounds loaded through a [Bus].
+#[derive(Clone, Debug, Default, PartialEq)]
+struct BusEffects {
+    /// Effects applied to every sound.
+    insert: EffectChain,
+
+    /// Effects applied to the wet copy of every sound.
+    send: EffectChain,
+
+    /// Mix of the wet copies, from `0.0` (dry) to `1.0` (wet).
+    wet: f32,
+}
+
+/// State shared between a [Mixer] and its buses.
+struct MixerState {
+    settings: MixerSettings,
+
+    /// Current gain applied to ducked buses.
+    duck_gain: f32,
+
+    /// Gain ducked buses are lowered to while ducking.
+    duck_level: f32,
+
+    /// Time left until ducked buses are restored, in seconds.
+    duck_hold: f32,
+
+    /// Bus and unmixed volume of each looping sound, so
+    /// their volumes can follow changes to the mix.
+    loops: BTreeMap<SoundId, (Bus, f32)>,
+
+    /// Effects on each bus which has any.
+    effects: BTreeMap<Bus, BusEffects>,
+
+    /// Wet copy of each sound loaded through a bus with a send.
+    wet_sounds: BTreeMap<SoundId, SoundId>,
+}
+
+/// Mixes sounds from several [Bus]es into a single backend.
+///
+/// Clones share the same mix, so a clone can be given to
+/// the pause menu while the game keeps playing through it.
+#[derive(Clone)]
+pub struct Mixer {
+    backend: Rc<dyn AudioBackend>,
+    state: Rc<RefCell<MixerState>>,
+}
+
+impl Mixer {
+    /// Returns a new mixer playing through `backend`, with default settings.
+    pub fn new(backend: Rc<dyn AudioBackend>) -> Self {
+        Self {
+            backend,
+            state: Rc::new(RefCell::new(MixerState {
+                settings: MixerSettings::default(),
+                duck_gain: 1.0,
+                duck_level: 1.0,
+                duck_hold: 0.0,
+                loops: BTreeMap::new(),
+                effects: BTreeMap::new(),
+                wet_sounds: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Returns a backend playing sounds through `bus`.
+    pub fn bus(&self, bus: Bus) -> Rc<dyn AudioBackend> {
+        Rc::new(BusBackend {
+            mixer: self.clone(),
+            bus,
+        })
+    }
+
+    /// Returns the current settings of every bus.
+    pub fn settings(&self) -> MixerSettings {
+        self.state.borrow().settings
+    }
+
+    /// Replaces the settings of every bus.
+    pub fn set_settings(&self, settings: MixerSettings) {
+        self.state.borrow_mut().settings = settings;
+        self.refresh();
+    }
+
+    /// Returns the gain of `bus`.
+    pub fn gain(&self, bus: Bus) -> f32 {
+        self.state.borrow().settings.bus(bus).gain
+    }
+
+    /// Sets the gain of `bus`, from `0.0` (silent) to `1.0` (full volume).
+    pub fn set_gain(&self, bus: Bus, gain: f32) {
+        self.state.borrow_mut().settings.bus_mut(bus).gain = gain.clamp(0.0, 1.0);
+        self.refresh();
+    }
+
+    /// Returns true if `bus` is muted.
+    pub fn is_muted(&self, bus: Bus) -> bool {
+        self.state.borrow().settings.bus(bus).muted
+    }
+
+    /// Mutes or unmutes `bus`.
+    pub fn set_muted(&self, bus: Bus, muted: bool) {
+        self.state.borrow_mut().settings.bus_mut(bus).muted = muted;
+        self.refresh();
+    }
+
+    /// Lowers every ducked bus to `level` for `secs` seconds,
+    /// then restores them.
+    ///
+    /// Ducking while already ducked keeps the lower level and later end.
+    pub fn duck(&self, level: f32, secs: f32) {
+        let mut state = self.state.borrow_mut();
+        if state.duck_hold <= 0.0 {
+            state.duck_level = 1.0;
+        }
+        state.duck_level = state.duck_level.min(level.clamp(0.0, 1.0));
+        state.duck_hold = state.duck_hold.max(secs);
+    }
+
+    /// Applies `effects` to every sound loaded through `bus` from now on.
+    ///
+    /// Sounds loaded through any bus also have the master bus's effects
+    /// applied, after their own bus's.
+    pub fn set_effects(&self, bus: Bus, effects: EffectChain) {
+        let mut state = self.state.borrow_mut();
+        state.effects.entry(bus).or_default().insert = effects;
+    }
+
+    /// Sends every sound loaded through `bus` from now on through `effects`,
+    /// which are faded in and out by [set_wet](Self::set_wet).
+    pub fn set_send(&self, bus: Bus, effects: EffectChain) {
+        let mut state = self.state.borrow_mut();
+        state.effects.entry(bus).or_default().send = effects;
+    }
+
+    /// Returns the mix of `bus`'s send.
+    pub fn wet(&self, bus: Bus) -> f32 {
+        let state = self.state.borrow();
+        state.effects.get(&bus).map_or(0.0, |effects| effects.wet)
+    }
+
+    /// Crossfades the sounds on `bus` between their original copies (`0.0`)
+    /// and the copies sent through the bus's send effects (`1.0`).
+    ///
+    /// Looping sounds change immediately, and other
+    /// sounds change the next time they're played.
+    pub fn set_wet(&self, bus: Bus, wet: f32) {
+        {
+            let mut state = self.state.borrow_mut();
+            let effects = state.effects.entry(bus).or_default();
+            if effects.wet == wet.clamp(0.0, 1.0) {
+                return;
+            }
+            effects.wet = wet.clamp(0.0, 1.0);
+        }
+
+        self.refresh();
+    }
+
+    /// Returns the volume sounds on `bus` are scaled
+    /// by, including the master bus and any ducking.
+    pub fn volume(&self, bus: Bus) -> f32 {
+        let state = self.state.borrow();
+        let settings = state.settings.bus(bus);
+
+        let mut volume = state.settings.master.volume();
+        if bus != Bus::Master {
+            volume *= settings.volume();
+        }
+        if settings.ducked {
+            volume *= state.duck_gain;
+        }
+
+        volume
+    }
+
+    /// Updates the mixer by `frame_time` seconds, moving ducked buses
+    /// towards their ducked or restored volume.
+    pub fn update(&self, frame_time: f32) {
+        {
+            let mut state = self.state.borrow_mut();
+            state.duck_hold = (state.duck_hold - frame_time).max(0.0);
+
+            let target = if state.duck_hold > 0.0 {
+                state.duck_level
+            } else {
+                1.0
+            };
+            if state.duck_gain == target {
+                return;
+            }
+
+            let step = DUCK_RATE * frame_time;
+            state.duck_gain = if state.duck_gain < target {
+                (state.duck_gain + step).min(target)
+            } else {
+                (state.duck_gain - step).max(target)
+            };
+        }
+
+        self.refresh();
+    }
+
+    /// Returns the effects applied to sounds loaded through `bus`,
+    /// and the effects applied to their wet copies.
+    fn chains(&self, bus: Bus) -> (EffectChain, EffectChain) {
+        let state = self.state.borrow();
+        let effects = state.effects.get(&bus).cloned().unwrap_or_default();
+
+        let mut insert = effects.insert;
+        if bus != Bus::Master
+            && let Some(master) = state.effects.get(&Bus::Master)
+        {
+            insert.effects.extend(master.insert.effects.iter().copied());
+        }
+
+        (insert, effects.send)
+    }
+
+    /// Returns each copy of `sound` with its volume on
+    /// `bus`, when the sound is played at `volume`.
+    fn mix(&self, bus: Bus, sound: SoundId, volume: f32) -> Vec<(SoundId, f32)> {
+        let volume = volume * self.volume(bus);
+
+        let state = self.state.borrow();
+        match state.wet_sounds.get(&sound) {
+            Some(&wet_sound) => {
+                let wet = state.effects.get(&bus).map_or(0.0, |effects| effects.wet);
+                vec![(sound, volume * (1.0 - wet)), (wet_sound, volume * wet)]
+            }
+            None => vec![(sound, volume)],
+        }
+    }
+
+    /// Re-applies the mix to every looping sound.
+    fn refresh(&self) {
+        let loops: Vec<_> = self.state.borrow().loops.clone().into_iter().collect();
+        for (sound, (bus, volume)) in loops {
+            for (sound, volume) in self.mix(bus, sound, volume) {
+                self.backend.set_volume(sound, volume);
+            }
+        }
+    }
+}
+
+/// Backend playing sounds through one of a [Mixer]'s buses.
+struct BusBackend {
+    mixer: Mixer,
+    bus: Bus,
+}
+
+impl AudioBackend for BusBackend {
+    fn load<'a>(&'a self, bytes: &'a [u8]) -> LoadFuture<'a> {
+        let (insert, send) = self.mixer.chains(self.bus);
+        if insert.is_empty() && send.is_empty() {
+            return self.mixer.backend.load(bytes);
+        }
+
+        Box::pin(async move {
+            let wav = Wav::decode(bytes).map_err(|e| AudioError::Decode(e.to_string()))?;
+            let dry = insert.apply(&wav);
+            let sound = self.mixer.backend.load(&dry.encode()).await?;
+
+            // Load the wet copy alongside, to crossfade to.
+            if !send.is_empty() {
+                let wet = send.apply(&dry).encode();
+                let wet_sound = self.mixer.backend.load(&wet).await?;
+                let mut state = self.mixer.state.borrow_mut();
+                state.wet_sounds.insert(sound, wet_sound);
+            }
+
+            Ok(sound)
+        })
+    }
+
+    fn play(&self, sound: SoundId, params: PlayParams) {
+        if params.looped {
+            let mut state = self.mixer.state.borrow_mut();
+            state.loops.insert(sound, (self.bus, params.volume));
+        }
+
+        // Loops play every copy, since the mix may change while they play.
+        for (sound, volume) in self.mixer.mix(self.bus, sound, params.volume) {
+            if volume > 0.0 || params.looped {
+                self.mixer
+                    .backend
+                    .play(sound, PlayParams { volume, ..params });
+            }
+        }
+    }
+
+    fn set_volume(&self, sound: SoundId, volume: f32) {
+        let mut state = self.mixer.state.borrow_mut();
+        if let Some(looping) = state.loops.get_mut(&sound) {
+            looping.1 = volume;
+        }
+        drop(state);
+
+        for (sound, volume) in self.mixer.mix(self.bus, sound, volume) {
+            self.mixer.backend.set_volume(sound, volume);
+        }
+    }
+
+    fn stop(&self, sound: SoundId) {
+        self.mixer.state.borrow_mut().loops.remove(&sound);
+        for (sound, _) in self.mixer.mix(self.bus, sound, 0.0) {
+            self.mixer.backend.stop(sound);
+        }
+    }
+
+    fn stop_at(&self, sound: SoundId, time: f64) {
+        self.mixer.state.borrow_mut().loops.remove(&sound);
+        for (sound, _) in self.mixer.mix(self.bus, sound, 0.0) {
+            self.mixer.backend.stop_at(sound, time);
+        }
+    }
+
+    fn update(&self) {
+        self.mixer.backend.update();
+    }
+}
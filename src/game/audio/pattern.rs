@@ -0,0 +1,1055 @@
+//! Drum-machine style text format for composing [Piece]s.
+//!
+//! Each non-empty line which isn't a `#` comment is one of:
+//!
+//! - `title <text>`: the piece's title, shown on its collected records.
+//! - `notes <text>`: a line of the piece's liner notes. Repeat for more lines.
+//! - `tempo <bpm>`: the piece's tempo, in (quarter note) beats per minute.
+//! - `time <beats>/<unit>`: the piece's time signature, like `3/4` or `7/8`
+//!   (`4/4` by default). Sections are measured in bars of this length.
+//! - `swing <fraction>`: the piece's swing (see [Piece::set_swing]).
+//! - `seed <number>`: the seed for steps' random probabilities.
+//! - `transpose <semitones>`: shifts the pitch of every step in the piece.
+//! - `sample <name> <asset> [root]`: names one of the embedded [SAMPLES],
+//!   where `[root]` is the note the sample plays at (`C4` by default).
+//! - `synth <name> [root=<note>] <params...>`: names a sound generated by
+//!   a [Synth], from `key=value` parameters (see [Synth::parse]).
+//! - `track <name>[=<sample>] [resolution] <pattern>`: adds a track called
+//!   `<name>`, playing the sample called `<sample>` (or `<name>`, if it's
+//!   left out), where `[resolution]` is one of `1/4`, `1/8`, `1/8t`, `1/16`
+//!   or `1/16t`, and `<pattern>` is the rest of the line.
+//! - `fx <track> <effect> [params...]`: adds an [Effect] to the end of the
+//!   named tracks' effect chains, from `key=value` parameters (see
+//!   [Effect::parse]). Effects are applied to the tracks' sounds as
+//!   they're loaded.
+//! - `voices <track> <count> [oldest|quietest]`: limits the named tracks
+//!   to playing `<count>` sounds at once, cutting off the oldest (by
+//!   default) or quietest sound to play another.
+//! - `mutate <track> <probability>`: flips each of the named tracks' steps
+//!   between a rest and a hit with `<probability>`, rolled afresh each phrase.
+//! - `fill <track> <bars> <pattern>`: plays `<pattern>` instead of the
+//!   named tracks' steps in the last bar of every `<bars>` bars.
+//! - `section <name> <bars> <tracks...>`: adds an arrangement section
+//!   lasting `<bars>` (`0` for endless), playing the named tracks
+//!   (or every track, for `*`).
+//! - `on <event> <from> <to>`: moves from section `<from>` (or any
+//!   section, for `*`) to section `<to>` when `<event>` happens, where
+//!   `<event>` is one of `start`, `objective`, `clear` or `end`.
+//! - `unlock <tracks...>`: adds a group of tracks unlocked by
+//!   the next objective.
+//!
+//! Track names must be unique, so tracks sharing a sample need names of
+//! their own, like `track kick2=kick`. Tracks play their sample as it's
+//! declared at the time, and must be declared before the sections and
+//! unlocks using them. Likewise, sections must be
+//! declared before the rules using them. Without any sections, every
+//! track plays in a single endless section; without any unlocks,
+//! each objective unlocks the next track.
+//!
+//! Patterns contain one character per step:
+//!
+//! - `.` or `-`: a rest.
+//! - `x`: full velocity.
+//! - `o`: soft velocity.
+//! - `1` to `9`: velocity, from quiet (`1`) to full (`9`).
+//! - `?`: full velocity, played half of the time.
+//! - `r`: full velocity, played twice within the step.
+//! - `E(<pulses>,<steps>)` or `E(<pulses>,<steps>,<rotation>)`: a
+//!   Euclidean rhythm of `<steps>` steps, with `<pulses>` hits spread as
+//!   evenly as possible, rotated left by `<rotation>` (see [euclidean]).
+//!
+//! A step can be followed by its pitch in brackets, either in semitones
+//! (`x[+7]`, `x[-12]`) or as a note name relative to the sample's root
+//! (`x[E4]`, `x[Bb3]`, `x[F#5]`). Pitched steps play a resampled copy of
+//! the sample, so one sample can carry a melody.
+//!
+//! Spaces and `|` in patterns are ignored, so they can be used to group steps.
+//!
+//! The first track in a composition is the piece's baseline track.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::rc::Rc;
+
+use super::arrangement::{Arrangement, MusicEvent, Rule, Section};
+use super::backend::{AudioBackend, AudioError};
+use super::effects::{Effect, EffectChain, EffectError};
+use super::synth::{Synth, SynthError};
+use super::wav::Wav;
+use super::{
+    MAX_VELOCITY, Piece, Resolution, SAMPLES, Step, TimeSignature, Track, VoiceStealing, euclidean,
+};
+
+/// Parsed text representation of a [Piece].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Composition {
+    /// Title of the piece.
+    pub title: String,
+
+    /// Liner notes for the piece, one entry per line.
+    pub notes: Vec<String>,
+
+    /// Tempo of the piece, in beats per minute.
+    pub tempo_bpm: f32,
+
+    /// Time signature of the piece.
+    pub time_signature: TimeSignature,
+
+    /// Swing of the piece.
+    pub swing: f32,
+
+    /// Seed for the piece's step probabilities.
+    pub seed: u64,
+
+    /// Semitones by which every step in the piece is pitched.
+    pub transpose: i8,
+
+    /// Tracks in the piece, in order.
+    pub tracks: Vec<TrackPattern>,
+
+    /// Arrangement of the piece's tracks.
+    pub arrangement: Arrangement,
+}
+
+/// Parsed text representation of a [Track].
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackPattern {
+    /// Name of the track, used by its composition's arrangement.
+    pub name: String,
+
+    /// The sound the track plays.
+    pub sound: SoundSource,
+
+    /// Duration of each step.
+    pub resolution: Resolution,
+
+    /// Steps in the track.
+    pub steps: Vec<Step>,
+
+    /// Effects applied to the track's sound.
+    pub effects: EffectChain,
+
+    /// The most sounds the track plays at once, or `0` for any number.
+    pub voices: usize,
+
+    /// How the track cuts off a sound when it's playing as many as it can.
+    pub voice_stealing: VoiceStealing,
+
+    /// Chance each step is flipped between a rest and a hit, each phrase.
+    pub mutation: f32,
+
+    /// Number of bars between each of the track's fills, and the fill's steps.
+    pub fill: Option<(u32, Vec<Step>)>,
+}
+
+/// Source of the sound played by a [TrackPattern].
+#[derive(Clone, Debug, PartialEq)]
+pub enum SoundSource {
+    /// One of the embedded [SAMPLES], by name.
+    Asset(String),
+
+    /// A sound generated by a synth.
+    Synth(Synth),
+}
+
+impl SoundSource {
+    /// Returns the source's sound, encoded as WAV.
+    pub fn to_wav(&self) -> Cow<'static, [u8]> {
+        match self {
+            SoundSource::Asset(asset) => {
+                let (_, bytes) = SAMPLES.iter().find(|(name, _)| name == asset).unwrap();
+                Cow::Borrowed(bytes)
+            }
+            SoundSource::Synth(synth) => Cow::Owned(synth.to_wav()),
+        }
+    }
+}
+
+impl Composition {
+    /// Parses a composition from its text representation.
+    pub fn parse(source: &str) -> Result<Self, PatternError> {
+        let mut composition = Composition {
+            title: String::new(),
+            notes: vec![],
+            tempo_bpm: super::TEMPO_BPM,
+            time_signature: super::TIME_SIGNATURE,
+            swing: 0.0,
+            seed: 0,
+            transpose: 0,
+            tracks: vec![],
+            arrangement: Arrangement::linear(0),
+        };
+        let mut samples: BTreeMap<&str, (SoundSource, i32)> = BTreeMap::new();
+        let mut roots = vec![];
+        let mut sections = vec![];
+        let mut rules = vec![];
+        let mut unlocks = vec![];
+
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            let invalid = || PatternError::InvalidValue {
+                line: line_number,
+                value: rest.to_string(),
+            };
+
+            match key {
+                "title" => composition.title = rest.to_string(),
+                "notes" => composition.notes.push(rest.to_string()),
+
+                "tempo" => {
+                    composition.tempo_bpm = rest
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|t| *t > 0.0)
+                        .ok_or_else(invalid)?;
+                }
+
+                "time" => {
+                    composition.time_signature = TimeSignature::parse(rest).ok_or_else(invalid)?;
+                }
+
+                "swing" => {
+                    composition.swing = rest.parse().map_err(|_| invalid())?;
+                }
+
+                "seed" => {
+                    composition.seed = rest.parse().map_err(|_| invalid())?;
+                }
+
+                "transpose" => {
+                    composition.transpose = rest.parse().map_err(|_| invalid())?;
+                }
+
+                "sample" => {
+                    let mut words = rest.split_whitespace();
+                    let (Some(name), Some(asset), root, None) =
+                        (words.next(), words.next(), words.next(), words.next())
+                    else {
+                        return Err(invalid());
+                    };
+                    let root = match root {
+                        Some(root) => note_number(root).ok_or_else(invalid)?,
+                        None => MIDDLE_C,
+                    };
+
+                    if !SAMPLES.iter().any(|(n, _)| *n == asset) {
+                        return Err(PatternError::UnknownAsset {
+                            line: line_number,
+                            asset: asset.to_string(),
+                        });
+                    }
+
+                    samples.insert(name, (SoundSource::Asset(asset.to_string()), root));
+                }
+
+                "synth" => {
+                    let (name, params) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                    if name.is_empty() {
+                        return Err(invalid());
+                    }
+
+                    let mut root = MIDDLE_C;
+                    let mut synth_params = vec![];
+                    for param in params.split_whitespace() {
+                        match param.strip_prefix("root=") {
+                            Some(note) => root = note_number(note).ok_or_else(invalid)?,
+                            None => synth_params.push(param),
+                        }
+                    }
+
+                    let synth =
+                        Synth::parse(synth_params).map_err(|error| PatternError::InvalidSynth {
+                            line: line_number,
+                            error,
+                        })?;
+                    samples.insert(name, (SoundSource::Synth(synth), root));
+                }
+
+                "track" => {
+                    let (name, rest) = rest.split_once(char::is_whitespace).ok_or_else(invalid)?;
+                    let (name, sample) = name.split_once('=').unwrap_or((name, name));
+                    if name.is_empty() || name == "*" {
+                        return Err(invalid());
+                    }
+                    if composition.tracks.iter().any(|track| track.name == name) {
+                        return Err(PatternError::DuplicateTrack {
+                            line: line_number,
+                            track: name.to_string(),
+                        });
+                    }
+
+                    let (sound, root) =
+                        samples
+                            .get(sample)
+                            .ok_or_else(|| PatternError::UnknownSample {
+                                line: line_number,
+                                sample: sample.to_string(),
+                            })?;
+
+                    // The resolution is optional.
+                    let rest = rest.trim();
+                    let (resolution, pattern) = match rest.split_once(char::is_whitespace) {
+                        Some((word, pattern)) => match parse_resolution(word) {
+                            Some(resolution) => (resolution, pattern),
+                            None => (Resolution::default(), rest),
+                        },
+                        None => (Resolution::default(), rest),
+                    };
+
+                    composition.tracks.push(TrackPattern {
+                        name: name.to_string(),
+                        sound: sound.clone(),
+                        resolution,
+                        steps: parse_steps(pattern, *root, line_number)?,
+                        effects: EffectChain::new(),
+                        voices: 0,
+                        voice_stealing: VoiceStealing::default(),
+                        mutation: 0.0,
+                        fill: None,
+                    });
+                    roots.push(*root);
+                }
+
+                "mutate" => {
+                    let mut words = rest.split_whitespace();
+                    let (Some(track), Some(probability), None) =
+                        (words.next(), words.next(), words.next())
+                    else {
+                        return Err(invalid());
+                    };
+
+                    let mutation = probability
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|p| (0.0..=1.0).contains(p))
+                        .ok_or_else(invalid)?;
+                    for i in composition.track_indices([track].into_iter(), line_number)? {
+                        composition.tracks[i].mutation = mutation;
+                    }
+                }
+
+                "fill" => {
+                    let (track, rest) = rest.split_once(char::is_whitespace).ok_or_else(invalid)?;
+                    let (bars, pattern) = rest
+                        .trim()
+                        .split_once(char::is_whitespace)
+                        .ok_or_else(invalid)?;
+
+                    let bars = bars.parse().ok().filter(|b| *b > 0).ok_or_else(invalid)?;
+                    for i in composition.track_indices([track].into_iter(), line_number)? {
+                        let steps = parse_steps(pattern, roots[i], line_number)?;
+                        composition.tracks[i].fill = Some((bars, steps));
+                    }
+                }
+
+                "fx" => {
+                    let mut words = rest.split_whitespace();
+                    let (Some(track), Some(name)) = (words.next(), words.next()) else {
+                        return Err(invalid());
+                    };
+
+                    let effect = Effect::parse(name, words).map_err(|error| {
+                        PatternError::InvalidEffect {
+                            line: line_number,
+                            error,
+                        }
+                    })?;
+                    for i in composition.track_indices([track].into_iter(), line_number)? {
+                        composition.tracks[i].effects.effects.push(effect);
+                    }
+                }
+
+                "voices" => {
+                    let mut words = rest.split_whitespace();
+                    let (Some(track), Some(count), stealing, None) =
+                        (words.next(), words.next(), words.next(), words.next())
+                    else {
+                        return Err(invalid());
+                    };
+
+                    let voices = count.parse().ok().filter(|v| *v > 0).ok_or_else(invalid)?;
+                    let voice_stealing = match stealing {
+                        Some(name) => VoiceStealing::from_name(name).ok_or_else(invalid)?,
+                        None => VoiceStealing::default(),
+                    };
+                    for i in composition.track_indices([track].into_iter(), line_number)? {
+                        composition.tracks[i].voices = voices;
+                        composition.tracks[i].voice_stealing = voice_stealing;
+                    }
+                }
+
+                "section" => {
+                    let mut words = rest.split_whitespace();
+                    let (Some(name), Some(bars)) = (words.next(), words.next()) else {
+                        return Err(invalid());
+                    };
+
+                    sections.push(Section {
+                        name: name.to_string(),
+                        bars: bars.parse().map_err(|_| invalid())?,
+                        tracks: composition.track_indices(words, line_number)?,
+                    });
+                }
+
+                "on" => {
+                    let mut words = rest.split_whitespace();
+                    let (Some(event), Some(from), Some(to), None) =
+                        (words.next(), words.next(), words.next(), words.next())
+                    else {
+                        return Err(invalid());
+                    };
+
+                    let event =
+                        MusicEvent::from_name(event).ok_or_else(|| PatternError::UnknownEvent {
+                            line: line_number,
+                            event: event.to_string(),
+                        })?;
+                    let section_index = |name: &str| {
+                        sections
+                            .iter()
+                            .position(|section: &Section| section.name == name)
+                            .ok_or_else(|| PatternError::UnknownSection {
+                                line: line_number,
+                                section: name.to_string(),
+                            })
+                    };
+
+                    rules.push(Rule {
+                        event,
+                        from: match from {
+                            "*" => None,
+                            from => Some(section_index(from)?),
+                        },
+                        to: section_index(to)?,
+                    });
+                }
+
+                "unlock" => {
+                    unlocks.push(composition.track_indices(rest.split_whitespace(), line_number)?);
+                }
+
+                _ => {
+                    return Err(PatternError::UnknownKey {
+                        line: line_number,
+                        key: key.to_string(),
+                    });
+                }
+            }
+        }
+
+        if composition.tracks.is_empty() {
+            return Err(PatternError::NoTracks);
+        }
+
+        // Fill in the default arrangement for anything not declared.
+        composition.arrangement = Arrangement::linear(composition.tracks.len());
+        if !sections.is_empty() {
+            composition.arrangement.sections = sections;
+        }
+        composition.arrangement.rules = rules;
+        if !unlocks.is_empty() {
+            composition.arrangement.unlocks = unlocks;
+        }
+
+        Ok(composition)
+    }
+
+    /// Returns the indices of the tracks named by `names`,
+    /// where `*` names every track.
+    fn track_indices<'a>(
+        &self,
+        names: impl Iterator<Item = &'a str>,
+        line: usize,
+    ) -> Result<Vec<usize>, PatternError> {
+        let mut indices = vec![];
+        for name in names {
+            let matches = self
+                .tracks
+                .iter()
+                .enumerate()
+                .filter(|(_, track)| name == "*" || track.name == name)
+                .map(|(i, _)| i);
+            let count = indices.len();
+            indices.extend(matches);
+
+            if indices.len() == count {
+                return Err(PatternError::UnknownTrack {
+                    line,
+                    track: name.to_string(),
+                });
+            }
+        }
+
+        Ok(indices)
+    }
+
+    /// Loads the composition's samples with `backend`,
+    /// returning a piece playing through it.
+    ///
+    /// All tracks in the piece start muted.
+    pub async fn into_piece(&self, backend: Rc<dyn AudioBackend>) -> Result<Piece, AudioError> {
+        let mut tracks = vec![];
+        for pattern in &self.tracks {
+            let transpose = |steps: &[Step]| -> Vec<Step> {
+                steps
+                    .iter()
+                    .map(|step| step.with_pitch(step.pitch.saturating_add(self.transpose)))
+                    .collect()
+            };
+            let steps = transpose(&pattern.steps);
+            let fill = pattern
+                .fill
+                .as_ref()
+                .map(|(bars, fill)| (*bars, transpose(fill)));
+
+            let fill_steps = fill.iter().flat_map(|(_, fill)| fill);
+            let mut pitches: BTreeSet<i8> = steps
+                .iter()
+                .chain(fill_steps)
+                .map(|step| step.pitch)
+                .collect();
+            pitches.insert(0);
+
+            // Render a copy of the sound for each pitch, pitched before
+            // its effects are applied so they keep their timing, along
+            // with its duration. Sounds which play as they are aren't decoded.
+            let wav = pattern.sound.to_wav();
+            let mut sounds: Vec<(i8, Cow<[u8]>, f64)> = vec![];
+            if pitches.len() == 1 && pattern.effects.is_empty() && pattern.voices == 0 {
+                sounds.push((0, wav, 0.0));
+            } else {
+                let decoded = Wav::decode(&wav).map_err(|e| AudioError::Decode(e.to_string()))?;
+                for pitch in pitches {
+                    let pitched = match pitch {
+                        0 => decoded.clone(),
+                        _ => decoded.pitched(pitch as f32),
+                    };
+                    let processed = pattern.effects.apply(&pitched);
+                    let duration = processed.frames() as f64 / processed.sample_rate as f64;
+                    sounds.push((pitch, Cow::Owned(processed.encode()), duration));
+                }
+            }
+
+            // Load the sounds once for each voice, since each voice
+            // needs its own copy to be cut off without the others.
+            let mut voices = vec![];
+            for _ in 0..pattern.voices.max(1) {
+                let mut voice = vec![];
+                for (pitch, bytes, duration) in &sounds {
+                    voice.push((*pitch, backend.load(bytes).await?, *duration));
+                }
+                voices.push(voice);
+            }
+
+            let (_, sound, _) = voices[0].iter().find(|(pitch, ..)| *pitch == 0).unwrap();
+            let mut track = Track::new(*sound, steps)
+                .with_name(&pattern.name)
+                .with_resolution(pattern.resolution)
+                .with_voice_stealing(pattern.voice_stealing)
+                .with_mutation(pattern.mutation);
+            if let Some((bars, fill)) = fill {
+                track = track.with_fill(bars, fill);
+            }
+            for &(pitch, sound, _) in voices[0].iter().filter(|(pitch, ..)| *pitch != 0) {
+                track = track.with_pitched_sound(pitch, sound);
+            }
+            if pattern.voices > 0 {
+                for voice in voices {
+                    track = track.with_voice(voice);
+                }
+            }
+
+            tracks.push(track);
+        }
+
+        let mut tracks = tracks.into_iter();
+        let mut piece = Piece::new(backend, tracks.next().unwrap(), self.tempo_bpm)
+            .with_time_signature(self.time_signature)
+            .with_seed(self.seed);
+        piece.set_swing(self.swing);
+        for track in tracks {
+            piece = piece.with(track);
+        }
+
+        Ok(piece)
+    }
+}
+
+/// Parses a step resolution like `1/16`.
+fn parse_resolution(word: &str) -> Option<Resolution> {
+    match word {
+        "1/4" => Some(Resolution::Quarter),
+        "1/8" => Some(Resolution::Eighth),
+        "1/8t" => Some(Resolution::EighthTriplet),
+        "1/16" => Some(Resolution::Sixteenth),
+        "1/16t" => Some(Resolution::SixteenthTriplet),
+        _ => None,
+    }
+}
+
+/// MIDI note number of middle C (`C4`).
+pub const MIDDLE_C: i32 = 60;
+
+/// Returns the MIDI note number of a note name like `C4`, `Eb3` or `F#5`.
+pub fn note_number(name: &str) -> Option<i32> {
+    let mut chars = name.chars();
+    let semitone = match chars.next()? {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.strip_prefix('#') {
+        Some(octave) => (1, octave),
+        None => match rest.strip_prefix('b') {
+            Some(octave) => (-1, octave),
+            None => (0, rest),
+        },
+    };
+
+    let octave: i32 = octave.parse().ok()?;
+    Some((octave + 1) * 12 + semitone + accidental)
+}
+
+/// Parses a step's pitch like `+7` or `E4`, in semitones
+/// relative to `root`.
+fn parse_pitch(pitch: &str, root: i32) -> Option<i8> {
+    let semitones = match pitch.strip_prefix('+') {
+        Some(semitones) => semitones.parse().ok()?,
+        None => match pitch.parse() {
+            Ok(semitones) => semitones,
+            Err(_) => note_number(pitch)? - root,
+        },
+    };
+
+    i8::try_from(semitones).ok()
+}
+
+/// Parses a pattern of steps like `x...x[+3]..x`,
+/// pitched relative to the sample's `root` note.
+fn parse_steps(pattern: &str, root: i32, line: usize) -> Result<Vec<Step>, PatternError> {
+    let mut steps: Vec<Step> = vec![];
+
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        let step = match c {
+            ' ' | '\t' | '|' => continue,
+            '.' | '-' => Step::REST,
+            'x' => Step::new(MAX_VELOCITY),
+            'o' => Step::new(MAX_VELOCITY / 2),
+            '1'..='9' => {
+                let level = c.to_digit(10).unwrap();
+                Step::new((level * MAX_VELOCITY as u32 / 9) as u8)
+            }
+            '?' => Step::new(MAX_VELOCITY).with_probability(0.5),
+            'r' => Step::new(MAX_VELOCITY).with_repeats(2),
+
+            // Expand a Euclidean rhythm.
+            'E' => {
+                let rest = chars.as_str();
+                let invalid = || PatternError::InvalidStep { line, step: c };
+                let (args, after) = rest
+                    .strip_prefix('(')
+                    .and_then(|rest| rest.split_once(')'))
+                    .ok_or_else(invalid)?;
+                let args: Vec<usize> = args
+                    .split(',')
+                    .map(|arg| arg.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| invalid())?;
+                let (pulses, count, rotation) = match args[..] {
+                    [pulses, count] => (pulses, count, 0),
+                    [pulses, count, rotation] => (pulses, count, rotation),
+                    _ => return Err(invalid()),
+                };
+                if count == 0 {
+                    return Err(invalid());
+                }
+
+                steps.extend(euclidean(pulses, count, rotation));
+                chars = after.chars();
+                continue;
+            }
+
+            // Pitch the previous step.
+            '[' => {
+                let rest = chars.as_str();
+                let (pitch, after) = rest
+                    .split_once(']')
+                    .ok_or(PatternError::InvalidStep { line, step: c })?;
+                let invalid = || PatternError::InvalidPitch {
+                    line,
+                    pitch: pitch.to_string(),
+                };
+
+                let step = steps.last_mut().ok_or_else(invalid)?;
+                *step = step.with_pitch(parse_pitch(pitch, root).ok_or_else(invalid)?);
+                chars = after.chars();
+                continue;
+            }
+
+            _ => return Err(PatternError::InvalidStep { line, step: c }),
+        };
+        steps.push(step);
+    }
+
+    if steps.is_empty() {
+        return Err(PatternError::EmptyPattern { line });
+    }
+
+    Ok(steps)
+}
+
+/// Errors encountered while parsing a [Composition].
+#[derive(Clone, Debug, PartialEq)]
+pub enum PatternError {
+    /// A line starts with an unknown key.
+    UnknownKey { line: usize, key: String },
+
+    /// A line's value is invalid for its key.
+    InvalidValue { line: usize, value: String },
+
+    /// A sample names an asset which isn't embedded.
+    UnknownAsset { line: usize, asset: String },
+
+    /// A synth has an invalid parameter.
+    InvalidSynth { line: usize, error: SynthError },
+
+    /// An effect is unknown, or has an invalid parameter.
+    InvalidEffect { line: usize, error: EffectError },
+
+    /// A track plays a sample which wasn't declared.
+    UnknownSample { line: usize, sample: String },
+
+    /// A section or unlock names a track which wasn't declared.
+    UnknownTrack { line: usize, track: String },
+
+    /// A track has the same name as an earlier track.
+    DuplicateTrack { line: usize, track: String },
+
+    /// A rule names a section which wasn't declared.
+    UnknownSection { line: usize, section: String },
+
+    /// A rule names an unknown event.
+    UnknownEvent { line: usize, event: String },
+
+    /// A pattern contains an invalid step pitch.
+    InvalidPitch { line: usize, pitch: String },
+
+    /// A pattern contains an unknown step character.
+    InvalidStep { line: usize, step: char },
+
+    /// A track's pattern has no steps.
+    EmptyPattern { line: usize },
+
+    /// The composition has no tracks.
+    NoTracks,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatternError::UnknownKey { line, key } => write!(f, "line {line}: unknown key `{key}`"),
+            PatternError::InvalidValue { line, value } => {
+                write!(f, "line {line}: invalid value `{value}`")
+            }
+            PatternError::UnknownAsset { line, asset } => {
+                write!(f, "line {line}: unknown sample asset `{asset}`")
+            }
+            PatternError::InvalidSynth { line, error } => write!(f, "line {line}: {error}"),
+            PatternError::InvalidEffect { line, error } => write!(f, "line {line}: {error}"),
+            PatternError::UnknownSample { line, sample } => {
+                write!(f, "line {line}: undeclared sample `{sample}`")
+            }
+            PatternError::UnknownTrack { line, track } => {
+                write!(f, "line {line}: undeclared track `{track}`")
+            }
+            PatternError::DuplicateTrack { line, track } => {
+                write!(f, "line {line}: track `{track}` is already declared")
+            }
+            PatternError::UnknownSection { line, section } => {
+                write!(f, "line {line}: undeclared section `{section}`")
+            }
+            PatternError::UnknownEvent { line, event } => {
+                write!(f, "line {line}: unknown event `{event}`")
+            }
+            PatternError::InvalidStep { line, step } => {
+                write!(f, "line {line}: invalid step `{step}`")
+            }
+            PatternError::InvalidPitch { line, pitch } => {
+                write!(f, "line {line}: invalid pitch `{pitch}`")
+            }
+            PatternError::EmptyPattern { line } => write!(f, "line {line}: empty pattern"),
+            PatternError::NoTracks => write!(f, "no tracks"),
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the error parsing `source`, which must be invalid.
+    fn error(source: &str) -> PatternError {
+        Composition::parse(source).unwrap_err()
+    }
+
+    #[test]
+    fn parses_every_line_kind() {
+        let composition = Composition::parse(
+            "# A test piece.
+            title Test
+            notes First line
+            notes Second line
+            tempo 90
+            time 3/4
+            swing 0.25
+            seed 42
+            transpose -2
+
+            sample kick decay4 C3
+            synth hat wave=noise decay=0.05
+            track kick 1/8 x.o.|9?r.
+            track hat x[+7]x[D4]E(3,8)
+            fx hat lowpass cutoff=2000
+            voices hat 2 quietest
+            mutate kick 0.1
+            fill kick 4 xxxx
+
+            section intro 2 kick
+            section main 0 *
+            on end intro main
+            on clear * intro
+            unlock hat
+            unlock kick",
+        )
+        .unwrap();
+
+        assert_eq!(composition.title, "Test");
+        assert_eq!(composition.notes, ["First line", "Second line"]);
+        assert_eq!(composition.tempo_bpm, 90.0);
+        assert_eq!(composition.time_signature, TimeSignature::new(3, 4));
+        assert_eq!(composition.swing, 0.25);
+        assert_eq!(composition.seed, 42);
+        assert_eq!(composition.transpose, -2);
+
+        let [kick, hat] = &composition.tracks[..] else {
+            panic!("expected two tracks");
+        };
+        assert_eq!(kick.name, "kick");
+        assert_eq!(kick.sound, SoundSource::Asset("decay4".to_string()));
+        assert_eq!(kick.resolution, Resolution::Eighth);
+        assert_eq!(
+            kick.steps,
+            [
+                Step::new(MAX_VELOCITY),
+                Step::REST,
+                Step::new(MAX_VELOCITY / 2),
+                Step::REST,
+                Step::new(MAX_VELOCITY),
+                Step::new(MAX_VELOCITY).with_probability(0.5),
+                Step::new(MAX_VELOCITY).with_repeats(2),
+                Step::REST,
+            ]
+        );
+        assert_eq!(kick.mutation, 0.1);
+        assert_eq!(kick.fill, Some((4, vec![Step::new(MAX_VELOCITY); 4])));
+
+        assert!(matches!(hat.sound, SoundSource::Synth(_)));
+        assert_eq!(hat.resolution, Resolution::Sixteenth);
+        let pitches: Vec<i8> = hat.steps.iter().map(|step| step.pitch).collect();
+        assert_eq!(pitches[..2], [7, 2]);
+        assert_eq!(hat.steps.len(), 2 + 8);
+        assert_eq!(hat.effects.effects, [Effect::LowPass { cutoff: 2000.0 }]);
+        assert_eq!(
+            (hat.voices, hat.voice_stealing),
+            (2, VoiceStealing::Quietest)
+        );
+
+        let arrangement = &composition.arrangement;
+        let sections: Vec<(&str, u32, &[usize])> = arrangement
+            .sections
+            .iter()
+            .map(|section| (section.name.as_str(), section.bars, &section.tracks[..]))
+            .collect();
+        assert_eq!(sections, [("intro", 2, &[0][..]), ("main", 0, &[0, 1][..])]);
+        assert_eq!(
+            arrangement.rules,
+            [
+                Rule {
+                    event: MusicEvent::End,
+                    from: Some(0),
+                    to: 1,
+                },
+                Rule {
+                    event: MusicEvent::Clear,
+                    from: None,
+                    to: 0,
+                },
+            ]
+        );
+        assert_eq!(arrangement.unlocks, [vec![1], vec![0]]);
+    }
+
+    #[test]
+    fn tracks_default_to_a_linear_arrangement() {
+        let composition = Composition::parse(
+            "sample kick decay4
+            track kick x...
+            track snare=kick ..x.",
+        )
+        .unwrap();
+
+        assert_eq!(composition.arrangement, Arrangement::linear(2));
+    }
+
+    #[test]
+    fn tracks_sharing_a_sample_need_their_own_names() {
+        let source = "sample kick decay4
+            track kick x...
+            track kick ..x.";
+        assert_eq!(
+            error(source),
+            PatternError::DuplicateTrack {
+                line: 3,
+                track: "kick".to_string(),
+            }
+        );
+
+        let composition = Composition::parse(
+            "sample kick decay4
+            track kick x...
+            track snare=kick ..x.
+            fx snare highpass",
+        )
+        .unwrap();
+        let names: Vec<&str> = composition.tracks.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["kick", "snare"]);
+        assert_eq!(composition.tracks[1].sound, composition.tracks[0].sound);
+        assert!(composition.tracks[0].effects.is_empty());
+        assert!(!composition.tracks[1].effects.is_empty());
+    }
+
+    #[test]
+    fn tracks_keep_the_sample_declared_at_the_time() {
+        let composition = Composition::parse(
+            "sample piano distant-piano-lo C4
+            track piano x[E4]
+            sample piano distant-piano-hi C5
+            fill piano 2 x[E4]",
+        )
+        .unwrap();
+
+        let piano = &composition.tracks[0];
+        assert_eq!(
+            piano.sound,
+            SoundSource::Asset("distant-piano-lo".to_string())
+        );
+        assert_eq!(piano.steps[0].pitch, 4);
+        assert_eq!(piano.fill.as_ref().unwrap().1[0].pitch, 4);
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        let cases = [
+            (
+                "bpm 90",
+                PatternError::UnknownKey {
+                    line: 1,
+                    key: "bpm".to_string(),
+                },
+            ),
+            (
+                "tempo -1",
+                PatternError::InvalidValue {
+                    line: 1,
+                    value: "-1".to_string(),
+                },
+            ),
+            (
+                "\nsample kick missing",
+                PatternError::UnknownAsset {
+                    line: 2,
+                    asset: "missing".to_string(),
+                },
+            ),
+            (
+                "track kick x",
+                PatternError::UnknownSample {
+                    line: 1,
+                    sample: "kick".to_string(),
+                },
+            ),
+            (
+                "sample kick decay4\ntrack kick x\nunlock snare",
+                PatternError::UnknownTrack {
+                    line: 3,
+                    track: "snare".to_string(),
+                },
+            ),
+            (
+                "sample kick decay4\ntrack kick x\non end * main",
+                PatternError::UnknownSection {
+                    line: 3,
+                    section: "main".to_string(),
+                },
+            ),
+            (
+                "sample kick decay4\ntrack kick x\nsection a 0 *\non drop * a",
+                PatternError::UnknownEvent {
+                    line: 4,
+                    event: "drop".to_string(),
+                },
+            ),
+            (
+                "sample kick decay4\ntrack kick x[H4]",
+                PatternError::InvalidPitch {
+                    line: 2,
+                    pitch: "H4".to_string(),
+                },
+            ),
+            (
+                "sample kick decay4\ntrack kick x.z",
+                PatternError::InvalidStep { line: 2, step: 'z' },
+            ),
+            (
+                "sample kick decay4\ntrack kick E(3,0)",
+                PatternError::InvalidStep { line: 2, step: 'E' },
+            ),
+            (
+                "sample kick decay4\ntrack kick | |",
+                PatternError::EmptyPattern { line: 2 },
+            ),
+            ("sample kick decay4", PatternError::NoTracks),
+        ];
+
+        for (source, expected) in cases {
+            assert_eq!(error(source), expected, "parsing {source:?}");
+        }
+
+        assert!(matches!(
+            error("synth hat wave=wobble"),
+            PatternError::InvalidSynth { line: 1, .. }
+        ));
+        assert!(matches!(
+            error("sample kick decay4\ntrack kick x\nfx kick fuzz"),
+            PatternError::InvalidEffect { line: 3, .. }
+        ));
+    }
+}
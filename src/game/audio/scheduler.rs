@@ -0,0 +1,597 @@
+//! Lookahead scheduling of [Piece](super::Piece) triggers against an audio clock.
+//!
+//! Rather than advancing by each frame's duration, the scheduler
+//! measures time with an [AudioClock], and queues triggers up to
+//! [LOOKAHEAD] ahead of when they're due. Each trigger keeps its exact
+//! time, so the schedule doesn't drift with the frame rate.
+//!
+//! Pieces hand each trigger to their backend as soon as it's queued,
+//! with its exact time, so that the backend can start the sound between
+//! frames (see [AudioBackend::play](super::backend::AudioBackend::play)).
+//! Since the backend already has them, queued triggers are never retimed:
+//! tempo changes only apply to beats which haven't been queued yet.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::rc::Rc;
+
+/// Source of the current time for a [Scheduler].
+pub trait AudioClock {
+    /// Returns the current time, in seconds, from an arbitrary origin.
+    fn now(&self) -> f64;
+}
+
+/// Clock measuring the time since the game started.
+///
+/// Macroquad doesn't expose the audio device's clock, so this is
+/// wall-clock time, sampled by the game rather than the audio output.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GameClock;
+
+impl AudioClock for GameClock {
+    fn now(&self) -> f64 {
+        macroquad::time::get_time()
+    }
+}
+
+/// Manually advanced clock, for driving a [Scheduler] without real time.
+///
+/// Clones share the same time, so a clone can be given
+/// to a scheduler while the original advances it.
+#[derive(Clone, Debug, Default)]
+pub struct FakeClock {
+    time: Rc<Cell<f64>>,
+}
+
+impl FakeClock {
+    /// Returns a new clock starting at `0.0` seconds.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the clock's time, in seconds.
+    pub fn set(&self, time: f64) {
+        self.time.set(time);
+    }
+
+    /// Advances the clock by `seconds`.
+    pub fn advance(&self, seconds: f64) {
+        self.time.set(self.time.get() + seconds);
+    }
+}
+
+impl AudioClock for FakeClock {
+    fn now(&self) -> f64 {
+        self.time.get()
+    }
+}
+
+/// The default time triggers are queued before they're due, in seconds.
+pub const LOOKAHEAD: f64 = 0.1;
+
+/// The longest gap between updates, in seconds, which is caught up on.
+///
+/// Longer gaps (such as while the game is in the level editor)
+/// pause the schedule, so playback resumes where it left off.
+pub const MAX_STALL: f64 = 1.0;
+
+/// The longest a trigger can be overdue when it's queued before it's
+/// caught up on, in seconds.
+///
+/// Triggers are usually queued ahead of time, but a hitch shorter
+/// than [MAX_STALL] can leave many of them overdue at once.
+pub const MAX_LATENESS: f64 = 0.15;
+
+/// The most triggers each track catches up on in one update, once
+/// they're more than [MAX_LATENESS] overdue. The rest are dropped,
+/// rather than played in a burst.
+pub const MAX_CATCH_UP: usize = 1;
+
+/// A queued sound start.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Trigger {
+    /// Clock time at which the sound starts, in seconds.
+    pub time: f64,
+
+    /// Beat at which the sound starts.
+    pub beat: f64,
+
+    /// Index of the track playing the sound.
+    pub track: usize,
+
+    /// Index of the step playing the sound, within its
+    /// track's steps or, for a fill step, its track's fill.
+    pub step: usize,
+
+    /// Whether the step playing the sound is from its track's fill.
+    pub fill: bool,
+
+    /// Velocity of the step playing the sound.
+    pub velocity: u8,
+
+    /// Pitch of the step playing the sound, in semitones.
+    pub pitch: i8,
+}
+
+/// A linear change of tempo across a window of beats.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TempoRamp {
+    /// Tempo at the start of the ramp, in beats per minute.
+    pub from_bpm: f64,
+
+    /// Tempo at the end of the ramp, in beats per minute.
+    pub to_bpm: f64,
+
+    /// Beat at which the ramp starts.
+    pub start_beat: f64,
+
+    /// Beat at which the ramp ends.
+    pub end_beat: f64,
+}
+
+impl TempoRamp {
+    /// Returns the change in tempo per beat.
+    fn slope(&self) -> f64 {
+        (self.to_bpm - self.from_bpm) / (self.end_beat - self.start_beat)
+    }
+
+    /// Returns the tempo at `beat`, which must be within the ramp.
+    pub fn tempo_at(&self, beat: f64) -> f64 {
+        self.from_bpm + self.slope() * (beat - self.start_beat)
+    }
+
+    /// Returns the time taken to reach `beat` from the start
+    /// of the ramp, in seconds. `beat` must be within the ramp.
+    fn secs_to(&self, beat: f64) -> f64 {
+        // Ramps without any beats change tempo instantly.
+        if self.end_beat <= self.start_beat {
+            return 0.0;
+        }
+
+        // Tempo changes linearly with beats, so time
+        // grows with the logarithm of the tempo.
+        let slope = self.slope();
+        if slope.abs() < f64::EPSILON {
+            return (beat - self.start_beat) * 60.0 / self.from_bpm;
+        }
+        60.0 / slope * (self.tempo_at(beat) / self.from_bpm).ln()
+    }
+
+    /// Returns the beat reached `secs` seconds after the start
+    /// of the ramp, which must be within the ramp.
+    fn beat_after(&self, secs: f64) -> f64 {
+        let slope = self.slope();
+        if slope.abs() < f64::EPSILON {
+            return self.start_beat + secs * self.from_bpm / 60.0;
+        }
+        self.start_beat + self.from_bpm / slope * ((slope * secs / 60.0).exp() - 1.0)
+    }
+}
+
+/// Maps clock time onto beats, and queues triggers ahead of time.
+pub struct Scheduler {
+    /// Clock used to measure time.
+    clock: Box<dyn AudioClock>,
+
+    /// Time triggers are queued before they're due, in seconds.
+    lookahead: f64,
+
+    /// The tempo at the anchor, in beats per minute.
+    tempo_bpm: f64,
+
+    /// A change of tempo after the anchor, if there is one.
+    ramp: Option<TempoRamp>,
+
+    /// Clock time and beat from which beats are measured,
+    /// or `None` until the schedule is first advanced.
+    anchor: Option<(f64, f64)>,
+
+    /// Clock time of the most recent update.
+    last_update: f64,
+
+    /// Beat up to which triggers have been queued.
+    scheduled_beat: f64,
+
+    /// Triggers which haven't been handed out yet, in no particular order.
+    queue: Vec<Trigger>,
+}
+
+impl Scheduler {
+    /// Creates a new scheduler, measuring time with `clock`.
+    ///
+    /// The schedule starts at beat `0.0` the first time it's advanced.
+    pub fn new(clock: impl AudioClock + 'static, tempo_bpm: f32) -> Self {
+        Self {
+            clock: Box::new(clock),
+            lookahead: LOOKAHEAD,
+            tempo_bpm: tempo_bpm as f64,
+            ramp: None,
+            anchor: None,
+            last_update: 0.0,
+            scheduled_beat: 0.0,
+            queue: vec![],
+        }
+    }
+
+    /// Sets the time triggers are queued before they're due, in seconds.
+    pub fn with_lookahead(mut self, lookahead: f64) -> Self {
+        self.lookahead = lookahead.max(0.0);
+        self
+    }
+
+    /// Returns the clock's current time, in seconds.
+    pub fn now(&self) -> f64 {
+        self.clock.now()
+    }
+
+    /// Returns the beat at clock time `time`.
+    pub fn beat_at(&self, time: f64) -> f64 {
+        let Some((anchor_time, anchor_beat)) = self.anchor else {
+            return 0.0;
+        };
+
+        let beat = anchor_beat + (time - anchor_time) * self.tempo_bpm / 60.0;
+        let Some(ramp) = self.ramp else {
+            return beat;
+        };
+        if beat <= ramp.start_beat {
+            return beat;
+        }
+
+        // Follow the ramp, then its final tempo.
+        let ramp_start = self.time_at(ramp.start_beat);
+        let ramp_end = ramp_start + ramp.secs_to(ramp.end_beat);
+        if time < ramp_end {
+            ramp.beat_after(time - ramp_start)
+        } else {
+            ramp.end_beat + (time - ramp_end) * ramp.to_bpm / 60.0
+        }
+    }
+
+    /// Returns the clock time, in seconds, at `beat`.
+    pub fn time_at(&self, beat: f64) -> f64 {
+        let (anchor_time, anchor_beat) = self.anchor.unwrap_or((self.now(), 0.0));
+
+        // Beats before the ramp are at the anchor's tempo.
+        let Some(ramp) = self.ramp.filter(|ramp| beat > ramp.start_beat) else {
+            return anchor_time + (beat - anchor_beat) * 60.0 / self.tempo_bpm;
+        };
+
+        let ramp_start = anchor_time + (ramp.start_beat - anchor_beat) * 60.0 / self.tempo_bpm;
+        if beat < ramp.end_beat {
+            ramp_start + ramp.secs_to(beat)
+        } else {
+            ramp_start + ramp.secs_to(ramp.end_beat) + (beat - ramp.end_beat) * 60.0 / ramp.to_bpm
+        }
+    }
+
+    /// Returns the tempo at `beat`, in beats per minute.
+    pub fn tempo_at(&self, beat: f64) -> f64 {
+        match self.ramp {
+            Some(ramp) if beat >= ramp.end_beat => ramp.to_bpm,
+            Some(ramp) if beat > ramp.start_beat => ramp.tempo_at(beat),
+            _ => self.tempo_bpm,
+        }
+    }
+
+    /// Returns the current tempo, in beats per minute.
+    pub fn tempo(&self) -> f64 {
+        self.tempo_at(self.beat())
+    }
+
+    /// Returns the change of tempo in progress or
+    /// scheduled to start, if there is one.
+    pub fn ramp(&self) -> Option<TempoRamp> {
+        self.ramp
+    }
+
+    /// Returns the current beat, including the fraction of the beat elapsed.
+    pub fn beat(&self) -> f64 {
+        self.beat_at(self.now())
+    }
+
+    /// Returns the fraction of the current beat which has elapsed,
+    /// from `0.0` (on the beat) up to `1.0`.
+    pub fn beat_phase(&self) -> f32 {
+        self.beat().fract() as f32
+    }
+
+    /// Changes the tempo from the first beat which
+    /// hasn't been queued yet onward, cancelling any ramp.
+    pub fn set_tempo(&mut self, tempo_bpm: f32) {
+        self.ramp_tempo(tempo_bpm, 0.0..0.0);
+    }
+
+    /// Changes the tempo linearly to `tempo_bpm` across a window
+    /// of `beats`, replacing any ramp.
+    ///
+    /// The ramp starts from the tempo at the start of the window, and
+    /// doesn't start before the first beat which hasn't been queued yet,
+    /// so triggers which have already been handed out stay on time.
+    pub fn ramp_tempo(&mut self, tempo_bpm: f32, beats: Range<f64>) {
+        self.reanchor();
+
+        // The schedule is at beat 0 until it's first advanced.
+        let beat = self.anchor.map_or(0.0, |_| self.beat());
+        let start_beat = beats.start.max(self.scheduled_beat).max(beat);
+        let end_beat = beats.end.max(start_beat);
+
+        // Change tempo straight away if nothing's queued ahead.
+        if end_beat <= start_beat && start_beat <= beat {
+            self.tempo_bpm = tempo_bpm as f64;
+            self.ramp = None;
+            return;
+        }
+
+        self.ramp = Some(TempoRamp {
+            from_bpm: self.tempo_bpm,
+            to_bpm: tempo_bpm as f64,
+            start_beat,
+            end_beat,
+        });
+    }
+
+    /// Moves the anchor to the current beat, at the current tempo,
+    /// clearing the ramp if it's already started.
+    fn reanchor(&mut self) {
+        if self.anchor.is_none() {
+            return;
+        }
+
+        let now = self.now();
+        let beat = self.beat_at(now);
+        self.tempo_bpm = self.tempo_at(beat);
+        self.anchor = Some((now, beat));
+        self.ramp = self
+            .ramp
+            .filter(|ramp| beat < ramp.start_beat)
+            .map(|ramp| TempoRamp {
+                from_bpm: self.tempo_bpm,
+                ..ramp
+            });
+    }
+
+    /// Advances the schedule to the clock's current time,
+    /// returning the window of beats which need triggers queued.
+    ///
+    /// Returns `None` if every beat within the lookahead is already scheduled.
+    pub fn advance(&mut self) -> Option<Range<f64>> {
+        let now = self.now();
+
+        match self.anchor {
+            None => self.anchor = Some((now, 0.0)),
+            Some((anchor_time, anchor_beat)) => {
+                // Shift the schedule past stalls, rather than catching up.
+                let stall = now - self.last_update;
+                if stall > MAX_STALL {
+                    self.anchor = Some((anchor_time + stall, anchor_beat));
+                }
+            }
+        }
+        self.last_update = now;
+
+        // Settle the ramp once it's finished.
+        if let Some(ramp) = self.ramp
+            && self.beat_at(now) >= ramp.end_beat
+        {
+            self.anchor = Some((self.time_at(ramp.end_beat), ramp.end_beat));
+            self.tempo_bpm = ramp.to_bpm;
+            self.ramp = None;
+        }
+
+        let start = self.scheduled_beat;
+        let end = self.beat_at(now + self.lookahead);
+        if end <= start {
+            return None;
+        }
+
+        self.scheduled_beat = end;
+        Some(start..end)
+    }
+
+    /// Queues a trigger for `step` of `track` (or of its `fill`)
+    /// at `beat`, playing at `velocity` and `pitch`.
+    pub fn push(
+        &mut self,
+        beat: f64,
+        track: usize,
+        step: usize,
+        fill: bool,
+        velocity: u8,
+        pitch: i8,
+    ) {
+        self.queue.push(Trigger {
+            time: self.time_at(beat),
+            beat,
+            track,
+            step,
+            fill,
+            velocity,
+            pitch,
+        });
+    }
+
+    /// Removes and returns every queued trigger, in the order they're
+    /// due, so they can be handed to a backend ahead of time.
+    ///
+    /// Only the latest [MAX_CATCH_UP] triggers of each track which are
+    /// more than [MAX_LATENESS] overdue are returned; the others are dropped.
+    pub fn drain_queued(&mut self) -> Vec<Trigger> {
+        let now = self.now();
+
+        let mut queued = std::mem::take(&mut self.queue);
+        queued.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+        // Catch up on the latest of each track's overdue triggers.
+        let mut caught_up: BTreeMap<usize, usize> = BTreeMap::new();
+        let mut kept = Vec::with_capacity(queued.len());
+        for trigger in queued.into_iter().rev() {
+            if now - trigger.time > MAX_LATENESS {
+                let count = caught_up.entry(trigger.track).or_default();
+                if *count >= MAX_CATCH_UP {
+                    continue;
+                }
+                *count += 1;
+            }
+            kept.push(trigger);
+        }
+        kept.reverse();
+
+        kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tempo at which each beat lasts exactly half a second.
+    const TEMPO_BPM: f32 = 120.0;
+
+    /// Beats between each test trigger.
+    const TRIGGER_BEATS: f64 = 0.25;
+
+    /// Advances `scheduler` to `time`, queueing a trigger every
+    /// [TRIGGER_BEATS] beats, and returns the triggers handed out.
+    fn update(scheduler: &mut Scheduler, clock: &FakeClock, time: f64) -> Vec<Trigger> {
+        clock.set(time);
+        if let Some(window) = scheduler.advance() {
+            let first = (window.start / TRIGGER_BEATS).ceil() as u64;
+            let end = (window.end / TRIGGER_BEATS).ceil() as u64;
+            for i in first..end {
+                scheduler.push(i as f64 * TRIGGER_BEATS, 0, i as usize, false, 100, 0);
+            }
+        }
+        scheduler.drain_queued()
+    }
+
+    /// Updates `scheduler` at 60 frames per second from `start` to `end`
+    /// seconds, returning the time of each trigger with the time it was
+    /// handed out.
+    fn run(scheduler: &mut Scheduler, clock: &FakeClock, start: f64, end: f64) -> Vec<(f64, f64)> {
+        let mut played = vec![];
+        let first_frame = (start * 60.0).round() as u64;
+        let end_frame = (end * 60.0).round() as u64;
+        for frame in first_frame..end_frame {
+            let now = frame as f64 / 60.0;
+            for trigger in update(scheduler, clock, now) {
+                played.push((trigger.time, now));
+            }
+        }
+        played
+    }
+
+    /// Returns the times of `played` triggers.
+    fn times(played: &[(f64, f64)]) -> Vec<f64> {
+        played.iter().map(|(time, _)| *time).collect()
+    }
+
+    #[test]
+    fn triggers_keep_exact_times_between_frames() {
+        let clock = FakeClock::new();
+        let mut scheduler = Scheduler::new(clock.clone(), TEMPO_BPM);
+
+        let played = run(&mut scheduler, &clock, 0.0, 1.0);
+
+        // Triggers fall every 0.125 seconds, between 60 fps frames.
+        assert_eq!(
+            times(&played),
+            vec![0.0, 0.125, 0.25, 0.375, 0.5, 0.625, 0.75, 0.875, 1.0]
+        );
+
+        // Each is handed out ahead of time, within the lookahead.
+        for (time, handed_out) in played {
+            assert!(handed_out <= time && time - handed_out <= LOOKAHEAD);
+        }
+    }
+
+    #[test]
+    fn short_stall_catches_up_on_latest_overdue_trigger() {
+        let clock = FakeClock::new();
+        let mut scheduler = Scheduler::new(clock.clone(), TEMPO_BPM);
+        let before = run(&mut scheduler, &clock, 0.0, 1.0);
+        assert_eq!(times(&before).last(), Some(&1.0));
+
+        // Stall for 0.95 seconds, less than MAX_STALL.
+        let stalled = update(&mut scheduler, &clock, 1.95);
+        let stalled: Vec<f64> = stalled.iter().map(|t| t.time).collect();
+
+        // Of the triggers more than MAX_LATENESS overdue (1.125 to 1.75),
+        // only the latest plays, followed by the one just overdue and
+        // the one within the lookahead.
+        assert_eq!(stalled, vec![1.75, 1.875, 2.0]);
+
+        // Playback carries on at the same tempo, without shifting.
+        let after = run(&mut scheduler, &clock, 2.0, 2.5);
+        assert_eq!(times(&after), vec![2.125, 2.25, 2.375, 2.5]);
+    }
+
+    #[test]
+    fn long_stall_shifts_the_schedule() {
+        let clock = FakeClock::new();
+        let mut scheduler = Scheduler::new(clock.clone(), TEMPO_BPM).with_lookahead(0.2);
+        let before = run(&mut scheduler, &clock, 0.0, 1.0);
+        assert_eq!(times(&before).last(), Some(&1.125));
+        assert_eq!(update(&mut scheduler, &clock, 1.0), vec![]);
+
+        // Stall for 2 seconds, longer than MAX_STALL.
+        let stalled = run(&mut scheduler, &clock, 3.0, 3.5);
+
+        // The schedule resumes where it left off, two seconds later,
+        // without repeating the triggers handed out before the stall.
+        assert_eq!(scheduler.beat_at(3.0), 2.0);
+        assert_eq!(times(&stalled), vec![3.25, 3.375, 3.5, 3.625]);
+    }
+
+    #[test]
+    fn beats_follow_tempo_ramps() {
+        let clock = FakeClock::new();
+        let mut scheduler = Scheduler::new(clock.clone(), TEMPO_BPM);
+        scheduler.advance();
+
+        // Double the tempo across the second bar.
+        scheduler.ramp_tempo(TEMPO_BPM * 2.0, 4.0..8.0);
+        let close = |a: f64, b: f64| assert!((a - b).abs() < 1e-9, "{a} != {b}");
+
+        // Before the ramp, each beat lasts half a second.
+        close(scheduler.time_at(4.0), 2.0);
+
+        // During it, time grows with the logarithm of the tempo,
+        // which rises by 30 BPM per beat.
+        let ramp_secs = |tempo: f64| 60.0 / 30.0 * (tempo / 120.0).ln();
+        close(scheduler.tempo_at(6.0), 180.0);
+        close(scheduler.time_at(6.0), 2.0 + ramp_secs(180.0));
+        close(scheduler.time_at(8.0), 2.0 + ramp_secs(240.0));
+
+        // After it, each beat lasts a quarter of a second.
+        close(scheduler.time_at(10.0), 2.5 + ramp_secs(240.0));
+
+        // Beats and times map back onto each other.
+        for beat in [1.0, 4.5, 6.0, 7.9, 12.0] {
+            close(scheduler.beat_at(scheduler.time_at(beat)), beat);
+        }
+    }
+
+    #[test]
+    fn tempo_changes_leave_handed_out_triggers_on_time() {
+        let clock = FakeClock::new();
+        let mut scheduler = Scheduler::new(clock.clone(), TEMPO_BPM);
+        let before = run(&mut scheduler, &clock, 0.0, 0.5);
+        assert_eq!(times(&before).last(), Some(&0.5));
+
+        // Double the tempo while beat 1 (at 0.5 seconds) is queued ahead.
+        clock.set(0.45);
+        scheduler.set_tempo(TEMPO_BPM * 2.0);
+        assert_eq!(scheduler.time_at(1.0), 0.5);
+        assert_eq!(scheduler.tempo_at(2.0), TEMPO_BPM as f64 * 2.0);
+
+        // Beats after it follow the new tempo, twice as often.
+        let after = times(&run(&mut scheduler, &clock, 0.45, 0.75));
+        assert_eq!(after.len(), 4);
+        assert!(after[0] > 0.5);
+        for pair in after.windows(2) {
+            assert!((pair[1] - pair[0] - 0.0625).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,402 @@
+//! Positional audio for sounds placed on a [TileMap].
+//!
+//! An [Emitter] sits at a grid position, and is heard relative to a
+//! listener (usually the player) as the map is drawn: panned by how
+//! far left or right of the listener it appears in the dimetric view,
+//! and quieter the further away it appears. Walls between the two
+//! muffle the sound.
+//!
+//! Backends can only change a sound's volume, so a [SpatialSound] is
+//! loaded as four copies (left and right, clear and muffled), and
+//! placing it mixes between them.
+
+use std::rc::Rc;
+
+use glam::Vec2;
+
+use crate::engine::tile::{TileMap, TileTexture};
+use crate::game::map::FOREGROUND_LAYER;
+
+use super::backend::{AudioBackend, AudioError, PlayParams, SoundId};
+use super::scheduler::{AudioClock, GameClock};
+use super::wav::Wav;
+
+/// Distance at which emitters fall silent by default, in tile widths.
+pub const DEFAULT_RADIUS: f32 = 24.0;
+
+/// Distance within which emitters aren't panned, in tile widths.
+const PAN_DISTANCE: f32 = 2.0;
+
+/// Cutoff of the filter applied to muffled sounds, in hertz.
+const MUFFLE_CUTOFF: f32 = 400.0;
+
+/// Number of wall tiles between an emitter and the listener
+/// at which the emitter is completely muffled.
+const MUFFLE_WALLS: f32 = 4.0;
+
+/// Volume of a completely muffled emitter, relative to an unmuffled one.
+const MUFFLED_VOLUME: f32 = 0.5;
+
+/// A sound loaded so that it can be placed anywhere in stereo.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpatialSound {
+    left: SoundId,
+    right: SoundId,
+    muffled_left: SoundId,
+    muffled_right: SoundId,
+}
+
+impl SpatialSound {
+    /// Loads a sound from WAV `bytes` into `backend`.
+    pub async fn load(backend: &dyn AudioBackend, bytes: &[u8]) -> Result<Self, AudioError> {
+        let wav = Wav::decode(bytes).map_err(|e| AudioError::Decode(e.to_string()))?;
+        let muffled = wav.lowpassed(MUFFLE_CUTOFF);
+
+        Ok(Self {
+            left: backend.load(&wav.panned(1.0, 0.0).encode()).await?,
+            right: backend.load(&wav.panned(0.0, 1.0).encode()).await?,
+            muffled_left: backend.load(&muffled.panned(1.0, 0.0).encode()).await?,
+            muffled_right: backend.load(&muffled.panned(0.0, 1.0).encode()).await?,
+        })
+    }
+
+    /// Returns each copy of the sound with its volume at `placement`.
+    fn mix(&self, placement: Placement) -> [(SoundId, f32); 4] {
+        // Pan with equal power, so sounds don't dip in the middle.
+        let angle = (placement.pan + 1.0) * std::f32::consts::FRAC_PI_4;
+        let (left, right) = (angle.cos(), angle.sin());
+
+        let clear = placement.gain * (1.0 - placement.muffle);
+        let muffled = placement.gain * placement.muffle * MUFFLED_VOLUME;
+        [
+            (self.left, left * clear),
+            (self.right, right * clear),
+            (self.muffled_left, left * muffled),
+            (self.muffled_right, right * muffled),
+        ]
+    }
+}
+
+/// Where a sound is heard, relative to the listener.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Placement {
+    /// Stereo position, from `-1.0` (left) to `1.0` (right).
+    pub pan: f32,
+
+    /// Volume after distance attenuation, from `0.0` to `1.0`.
+    pub gain: f32,
+
+    /// How muffled the sound is by walls, from `0.0` to `1.0`.
+    pub muffle: f32,
+}
+
+impl Placement {
+    /// Returns the placement of a sound at grid position `source`, heard
+    /// from `listener` on `map`, falling silent `radius` tile widths away.
+    ///
+    /// Tiles with `wall_texture` between the two muffle the sound.
+    pub fn between(
+        map: &TileMap,
+        listener: Vec2,
+        source: Vec2,
+        radius: f32,
+        wall_texture: Option<&TileTexture>,
+    ) -> Self {
+        // Measure in the dimetric view, in tile widths.
+        let offset = map.grid_offset_to_view(source - listener);
+        let distance = offset.length();
+
+        let gain = (1.0 - distance / radius).clamp(0.0, 1.0).powi(2);
+        let pan = (offset.x / distance.max(PAN_DISTANCE)).clamp(-1.0, 1.0);
+
+        // Count the walls along the line between the
+        // two, skipping the tiles at either end.
+        let mut muffle = 0.0;
+        if let Some(wall_texture) = wall_texture
+            && gain > 0.0
+        {
+            let line = map.tiles_on_line_between(listener.x, listener.y, source.x, source.y);
+            let walls = line
+                .iter()
+                .take(line.len() - 1)
+                .skip(1)
+                .filter(|(x, y)| {
+                    map.tile_state(*x, *y, FOREGROUND_LAYER)
+                        .is_some_and(|tile| tile.texture.as_ref() == Some(wall_texture))
+                })
+                .count();
+            muffle = (walls as f32 / MUFFLE_WALLS).min(1.0);
+        }
+
+        Self { pan, gain, muffle }
+    }
+}
+
+/// A sound playing from a position on a [TileMap].
+pub struct Emitter {
+    /// Backend the emitter plays through.
+    backend: Rc<dyn AudioBackend>,
+
+    /// Clock used to time the emitter's sounds.
+    clock: Box<dyn AudioClock>,
+
+    /// The emitter's sound.
+    ///
+    /// Looping emitters need a sound of their own, since
+    /// volume changes apply to every instance of a sound.
+    sound: SpatialSound,
+
+    /// Grid position of the emitter.
+    pub position: Vec2,
+
+    /// Volume of the emitter before it's placed, from `0.0` to `1.0`.
+    pub volume: f32,
+
+    /// Distance at which the emitter falls silent, in tile widths.
+    pub radius: f32,
+
+    /// Whether walls muffle the emitter.
+    pub muffled_by_walls: bool,
+
+    /// The placement the emitter's loop was last mixed at,
+    /// or `None` if it isn't looping.
+    looping: Option<Placement>,
+}
+
+impl Emitter {
+    /// Returns a new emitter of `sound` at grid `position`.
+    pub fn new(backend: Rc<dyn AudioBackend>, sound: SpatialSound, position: Vec2) -> Self {
+        Self {
+            backend,
+            clock: Box::new(GameClock),
+            sound,
+            position,
+            volume: 1.0,
+            radius: DEFAULT_RADIUS,
+            muffled_by_walls: true,
+            looping: None,
+        }
+    }
+
+    /// Measures time with `clock`, rather than the game's clock.
+    pub fn with_clock(mut self, clock: impl AudioClock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Sets the volume of the emitter before it's placed.
+    pub fn with_volume(mut self, volume: f32) -> Self {
+        self.volume = volume.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets the distance at which the emitter falls silent, in tile widths.
+    pub fn with_radius(mut self, radius: f32) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    /// Sets whether walls muffle the emitter.
+    pub fn with_muffling(mut self, muffled_by_walls: bool) -> Self {
+        self.muffled_by_walls = muffled_by_walls;
+        self
+    }
+
+    /// Returns where the emitter is heard from `listener` on `map`.
+    pub fn placement(
+        &self,
+        map: &TileMap,
+        listener: Vec2,
+        wall_texture: &TileTexture,
+    ) -> Placement {
+        let wall_texture = self.muffled_by_walls.then_some(wall_texture);
+        Placement::between(map, listener, self.position, self.radius, wall_texture)
+    }
+
+    /// Plays the emitter's sound once, as heard from `listener` on `map`.
+    pub fn play(&self, map: &TileMap, listener: Vec2, wall_texture: &TileTexture) {
+        let placement = self.placement(map, listener, wall_texture);
+        self.play_at(placement, false);
+    }
+
+    /// Starts looping the emitter's sound, as heard from `listener` on `map`.
+    ///
+    /// The loop follows the listener when the emitter is [updated](Self::update).
+    pub fn start(&mut self, map: &TileMap, listener: Vec2, wall_texture: &TileTexture) {
+        self.stop();
+
+        let placement = self.placement(map, listener, wall_texture);
+        self.play_at(placement, true);
+        self.looping = Some(placement);
+    }
+
+    /// Stops the emitter's loop, if it's playing.
+    ///
+    /// Loops are also stopped when their emitter is dropped.
+    pub fn stop(&mut self) {
+        if self.looping.take().is_some() {
+            for (sound, _) in self.sound.mix(Placement::default()) {
+                self.backend.stop(sound);
+            }
+        }
+    }
+
+    /// Returns true if the emitter's loop is playing.
+    pub fn is_looping(&self) -> bool {
+        self.looping.is_some()
+    }
+
+    /// Updates the emitter's loop, if it's playing, to
+    /// be heard from `listener` on `map`.
+    pub fn update(&mut self, map: &TileMap, listener: Vec2, wall_texture: &TileTexture) {
+        let Some(last) = self.looping else {
+            return;
+        };
+
+        let placement = self.placement(map, listener, wall_texture);
+        if placement != last {
+            for (sound, volume) in self.sound.mix(placement) {
+                self.backend.set_volume(sound, volume * self.volume);
+            }
+            self.looping = Some(placement);
+        }
+    }
+
+    /// Plays each copy of the emitter's sound, mixed for `placement`.
+    fn play_at(&self, placement: Placement, looped: bool) {
+        let time = self.clock.now();
+        for (sound, volume) in self.sound.mix(placement) {
+            self.backend.play(
+                sound,
+                PlayParams {
+                    time,
+                    volume: volume * self.volume,
+                    looped,
+                },
+            );
+        }
+    }
+}
+
+impl Drop for Emitter {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::tile::Tile;
+    use crate::game::map::BACKGROUND;
+
+    use super::super::backend::{AudioEvent, RecordingBackend};
+    use super::super::mixdown::block_on;
+    use super::super::scheduler::FakeClock;
+    use super::super::wav::{self, SAMPLE_RATE};
+    use super::*;
+
+    /// Returns an empty 16 by 16 map, and the texture of its walls.
+    fn map() -> (TileMap, TileTexture) {
+        let map = TileMap::new(16, 16, BACKGROUND, BACKGROUND);
+        (map, TileTexture::placeholder(1))
+    }
+
+    /// Fills the tiles at `positions` on `map` with `texture`.
+    fn fill(map: &mut TileMap, texture: &TileTexture, positions: &[(usize, usize)]) {
+        for &(x, y) in positions {
+            let tile = Tile::Filled {
+                texture: texture.clone(),
+                height_offset: None,
+                blend_color: None,
+            };
+            map.set_tile(x, y, FOREGROUND_LAYER, tile);
+        }
+    }
+
+    #[test]
+    fn sounds_pan_towards_their_side_of_the_view() {
+        let (map, _) = map();
+        let listener = Vec2::new(8.0, 8.0);
+        let pan = |offset: Vec2| {
+            Placement::between(&map, listener, listener + offset, DEFAULT_RADIUS, None).pan
+        };
+
+        // Grid diagonals run straight across or down the dimetric view.
+        assert_eq!(pan(Vec2::new(3.0, -3.0)), 1.0);
+        assert_eq!(pan(Vec2::new(-3.0, 3.0)), -1.0);
+        assert_eq!(pan(Vec2::new(3.0, 3.0)), 0.0);
+
+        // Nearby sounds are panned less.
+        assert_eq!(pan(Vec2::new(0.5, -0.5)), 0.25);
+        assert_eq!(pan(Vec2::ZERO), 0.0);
+    }
+
+    #[test]
+    fn sounds_fade_out_towards_their_radius() {
+        let (map, _) = map();
+        let listener = Vec2::new(4.0, 4.0);
+        let gain =
+            |offset: Vec2| Placement::between(&map, listener, listener + offset, 4.0, None).gain;
+
+        // Two grid steps to the right is two tile widths, halfway to the radius.
+        assert_eq!(gain(Vec2::ZERO), 1.0);
+        assert_eq!(gain(Vec2::new(2.0, -2.0)), 0.25);
+        assert_eq!(gain(Vec2::new(4.0, -4.0)), 0.0);
+        assert_eq!(gain(Vec2::new(8.0, -8.0)), 0.0);
+    }
+
+    #[test]
+    fn walls_between_muffle_sounds() {
+        let (mut map, wall) = map();
+        let (listener, source) = (Vec2::new(1.0, 1.0), Vec2::new(7.0, 1.0));
+        let muffle = |map: &TileMap, wall_texture| {
+            Placement::between(map, listener, source, DEFAULT_RADIUS, wall_texture).muffle
+        };
+
+        // Tiles under the listener and the source don't count.
+        fill(&mut map, &wall, &[(1, 1), (7, 1)]);
+        assert_eq!(muffle(&map, Some(&wall)), 0.0);
+
+        fill(&mut map, &wall, &[(3, 1), (4, 1)]);
+        assert_eq!(muffle(&map, Some(&wall)), 0.5);
+        assert_eq!(muffle(&map, None), 0.0);
+
+        // Other textures aren't walls, and enough walls muffle completely.
+        fill(&mut map, &TileTexture::placeholder(2), &[(5, 1)]);
+        assert_eq!(muffle(&map, Some(&wall)), 0.5);
+        fill(&mut map, &wall, &[(2, 1), (5, 1), (6, 1)]);
+        assert_eq!(muffle(&map, Some(&wall)), 1.0);
+    }
+
+    #[test]
+    fn emitters_play_at_their_clocks_time() {
+        let clock = FakeClock::new();
+        let backend = RecordingBackend::new(clock.clone());
+        let bytes = wav::encode(&[0.0; 16], 1, SAMPLE_RATE);
+        let sound = block_on(SpatialSound::load(&backend, &bytes)).unwrap();
+
+        let (map, wall) = map();
+        let position = Vec2::new(2.0, 2.0);
+        let emitter = Emitter::new(Rc::new(backend.clone()), sound, position)
+            .with_volume(0.5)
+            .with_clock(clock.clone());
+        clock.set(3.0);
+        emitter.play(&map, position, &wall);
+
+        // Every copy plays at once, with the muffled copies silent.
+        let centre = 0.5 * std::f32::consts::FRAC_1_SQRT_2;
+        let plays: Vec<(f64, f32)> = backend
+            .take_events()
+            .into_iter()
+            .filter_map(|(time, event)| match event {
+                AudioEvent::Play { params, .. } => Some((time, params.volume)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(plays.len(), 4);
+        assert!(plays.iter().all(|&(time, _)| time == 3.0));
+        for ((_, volume), expected) in plays.iter().zip([centre, centre, 0.0, 0.0]) {
+            assert!((volume - expected).abs() < 1e-6);
+        }
+    }
+}
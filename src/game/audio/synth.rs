@@ -0,0 +1,404 @@
+//! Procedural sound synthesis, in the spirit of bfxr.
+//!
+//! A [Synth] renders a one-shot sound from a handful of parameters:
+//! an oscillator with a pitch sweep, mixed with noise, shaped by an
+//! ADSR envelope and passed through simple low- and high-pass filters.
+//! Rendered sounds are encoded as WAV, so they can be loaded by any
+//! [AudioBackend](super::backend::AudioBackend) like an embedded sample.
+//!
+//! Of the game's sound effects, only [objective_sound](super::objective_sound)
+//! and [objective_hum](super::objective_hum) are synthesised. Compositions
+//! can also declare synths for their tracks (see [pattern](super::pattern)).
+
+use std::f32::consts::TAU;
+use std::fmt;
+
+use crate::engine::rng::Rng;
+
+use super::wav::{self, SAMPLE_RATE};
+
+/// Shape of a [Synth]'s oscillator.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Waveform {
+    #[default]
+    Sine,
+    Square,
+    Saw,
+    Triangle,
+    Noise,
+}
+
+impl Waveform {
+    /// Returns the waveform with the name used in compositions, like `square`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sine" => Some(Waveform::Sine),
+            "square" => Some(Waveform::Square),
+            "saw" => Some(Waveform::Saw),
+            "triangle" => Some(Waveform::Triangle),
+            "noise" => Some(Waveform::Noise),
+            _ => None,
+        }
+    }
+}
+
+/// Parameters for a procedurally generated sound.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Synth {
+    /// Shape of the oscillator.
+    pub waveform: Waveform,
+
+    /// Starting frequency of the oscillator, in hertz.
+    pub frequency: f32,
+
+    /// Change in frequency over time, in octaves per second.
+    pub sweep: f32,
+
+    /// Fraction of each cycle a square wave spends high.
+    pub duty: f32,
+
+    /// Amount of white noise mixed into the oscillator, from `0.0` to `1.0`.
+    pub noise: f32,
+
+    /// Time taken to rise to full volume, in seconds.
+    pub attack: f32,
+
+    /// Time taken to fall from full volume to the sustain level, in seconds.
+    pub decay: f32,
+
+    /// Volume held after the decay, from `0.0` to `1.0`.
+    pub sustain: f32,
+
+    /// Time the sustain level is held, in seconds.
+    pub hold: f32,
+
+    /// Time taken to fall from the sustain level to silence, in seconds.
+    pub release: f32,
+
+    /// Cutoff of the low-pass filter, in hertz, if there is one.
+    pub lowpass: Option<f32>,
+
+    /// Cutoff of the high-pass filter, in hertz, if there is one.
+    pub highpass: Option<f32>,
+
+    /// Overall volume of the sound, from `0.0` to `1.0`.
+    pub volume: f32,
+
+    /// Seed for the sound's noise.
+    pub seed: u64,
+}
+
+impl Default for Synth {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::Sine,
+            frequency: 440.0,
+            sweep: 0.0,
+            duty: 0.5,
+            noise: 0.0,
+            attack: 0.005,
+            decay: 0.1,
+            sustain: 0.5,
+            hold: 0.1,
+            release: 0.2,
+            lowpass: None,
+            highpass: None,
+            volume: 0.8,
+            seed: 0,
+        }
+    }
+}
+
+impl Synth {
+    /// Returns a new synth with a `waveform` oscillator at `frequency` hertz.
+    pub fn new(waveform: Waveform, frequency: f32) -> Self {
+        Self {
+            waveform,
+            frequency,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the change in frequency over time, in octaves per second.
+    pub fn with_sweep(mut self, sweep: f32) -> Self {
+        self.sweep = sweep;
+        self
+    }
+
+    /// Sets the amount of white noise mixed into the oscillator.
+    pub fn with_noise(mut self, noise: f32) -> Self {
+        self.noise = noise.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets the envelope's attack, decay, sustain level, hold and release.
+    pub fn with_envelope(
+        mut self,
+        attack: f32,
+        decay: f32,
+        sustain: f32,
+        hold: f32,
+        release: f32,
+    ) -> Self {
+        self.attack = attack.max(0.0);
+        self.decay = decay.max(0.0);
+        self.sustain = sustain.clamp(0.0, 1.0);
+        self.hold = hold.max(0.0);
+        self.release = release.max(0.0);
+        self
+    }
+
+    /// Sets the cutoff of the low-pass filter, in hertz.
+    pub fn with_lowpass(mut self, cutoff: f32) -> Self {
+        self.lowpass = Some(cutoff);
+        self
+    }
+
+    /// Sets the cutoff of the high-pass filter, in hertz.
+    pub fn with_highpass(mut self, cutoff: f32) -> Self {
+        self.highpass = Some(cutoff);
+        self
+    }
+
+    /// Sets the overall volume of the sound.
+    pub fn with_volume(mut self, volume: f32) -> Self {
+        self.volume = volume.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Parses a synth from `key=value` parameters, like `wave=square freq=220`.
+    ///
+    /// Keys are `wave`, `freq`, `sweep`, `duty`, `noise`, `attack`, `decay`,
+    /// `sustain`, `hold`, `release`, `lowpass`, `highpass`, `volume` and `seed`.
+    pub fn parse<'a>(params: impl IntoIterator<Item = &'a str>) -> Result<Self, SynthError> {
+        let mut synth = Synth::default();
+
+        for param in params {
+            let invalid = || SynthError::InvalidParam(param.to_string());
+            let (key, value) = param.split_once('=').ok_or_else(invalid)?;
+
+            if key == "wave" {
+                synth.waveform = Waveform::from_name(value).ok_or_else(invalid)?;
+                continue;
+            }
+            if key == "seed" {
+                synth.seed = value.parse().map_err(|_| invalid())?;
+                continue;
+            }
+
+            let value: f32 = value.parse().map_err(|_| invalid())?;
+            match key {
+                "freq" => synth.frequency = value,
+                "sweep" => synth.sweep = value,
+                "duty" => synth.duty = value.clamp(0.0, 1.0),
+                "noise" => synth.noise = value.clamp(0.0, 1.0),
+                "attack" => synth.attack = value.max(0.0),
+                "decay" => synth.decay = value.max(0.0),
+                "sustain" => synth.sustain = value.clamp(0.0, 1.0),
+                "hold" => synth.hold = value.max(0.0),
+                "release" => synth.release = value.max(0.0),
+                "lowpass" => synth.lowpass = Some(value),
+                "highpass" => synth.highpass = Some(value),
+                "volume" => synth.volume = value.clamp(0.0, 1.0),
+                _ => return Err(SynthError::UnknownParam(key.to_string())),
+            }
+        }
+
+        Ok(synth)
+    }
+
+    /// Returns the duration of the sound, in seconds.
+    pub fn duration(&self) -> f32 {
+        self.attack + self.decay + self.hold + self.release
+    }
+
+    /// Renders the sound as mono samples at `sample_rate`.
+    pub fn render(&self, sample_rate: u32) -> Vec<f32> {
+        let dt = 1.0 / sample_rate as f32;
+        let sample_count = (self.duration() * sample_rate as f32).ceil() as usize;
+
+        let mut rng = Rng::new(self.seed);
+        let mut phase = 0.0;
+        let mut lowpass = 0.0;
+        let mut highpass = (0.0, 0.0);
+
+        let mut samples = Vec::with_capacity(sample_count);
+        for i in 0..sample_count {
+            let t = i as f32 * dt;
+
+            // Advance the oscillator, following the sweep.
+            let frequency = (self.frequency * (self.sweep * t).exp2()).clamp(1.0, 20_000.0);
+            phase = (phase + frequency * dt).fract();
+
+            let noise = rng.next_f32() * 2.0 - 1.0;
+            let oscillator = match self.waveform {
+                Waveform::Sine => (phase * TAU).sin(),
+                Waveform::Square if phase < self.duty => 1.0,
+                Waveform::Square => -1.0,
+                Waveform::Saw => phase * 2.0 - 1.0,
+                Waveform::Triangle => 1.0 - (phase * 4.0 - 2.0).abs(),
+                Waveform::Noise => noise,
+            };
+            let mut sample = oscillator * (1.0 - self.noise) + noise * self.noise;
+
+            // One-pole filters.
+            if let Some(cutoff) = self.lowpass {
+                let alpha = one_pole_alpha(cutoff, dt);
+                lowpass += alpha * (sample - lowpass);
+                sample = lowpass;
+            }
+            if let Some(cutoff) = self.highpass {
+                let alpha = 1.0 - one_pole_alpha(cutoff, dt);
+                let (last_input, last_output) = highpass;
+                let output = alpha * (last_output + sample - last_input);
+                highpass = (sample, output);
+                sample = output;
+            }
+
+            samples.push(sample * self.envelope(t) * self.volume);
+        }
+
+        samples
+    }
+
+    /// Renders the sound as a mono WAV file.
+    pub fn to_wav(&self) -> Vec<u8> {
+        wav::encode(&self.render(SAMPLE_RATE), 1, SAMPLE_RATE)
+    }
+
+    /// Returns the envelope's volume `t` seconds into the sound.
+    fn envelope(&self, t: f32) -> f32 {
+        let mut t = t;
+        if t < self.attack {
+            return t / self.attack;
+        }
+        t -= self.attack;
+
+        if t < self.decay {
+            return 1.0 - (1.0 - self.sustain) * t / self.decay;
+        }
+        t -= self.decay;
+
+        if t < self.hold {
+            return self.sustain;
+        }
+        t -= self.hold;
+
+        if t < self.release {
+            return self.sustain * (1.0 - t / self.release);
+        }
+
+        0.0
+    }
+}
+
+/// Returns the smoothing factor of a one-pole low-pass
+/// filter at `cutoff` hertz, with a timestep of `dt` seconds.
+pub(super) fn one_pole_alpha(cutoff: f32, dt: f32) -> f32 {
+    let rc = 1.0 / (TAU * cutoff.max(1.0));
+    dt / (rc + dt)
+}
+
+/// Errors encountered while parsing a [Synth].
+#[derive(Clone, Debug, PartialEq)]
+pub enum SynthError {
+    /// A parameter has an unknown key.
+    UnknownParam(String),
+
+    /// A parameter isn't `key=value`, or its value is invalid.
+    InvalidParam(String),
+}
+
+impl fmt::Display for SynthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SynthError::UnknownParam(key) => write!(f, "unknown synth parameter `{key}`"),
+            SynthError::InvalidParam(param) => write!(f, "invalid synth parameter `{param}`"),
+        }
+    }
+}
+
+impl std::error::Error for SynthError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sample rate at which test sounds are rendered, for round numbers of samples.
+    const RATE: u32 = 1000;
+
+    #[test]
+    fn renders_are_deterministic() {
+        let synth = Synth::new(Waveform::Square, 110.0)
+            .with_sweep(-2.0)
+            .with_noise(0.5)
+            .with_lowpass(300.0)
+            .with_highpass(50.0);
+        assert_eq!(synth.render(RATE), synth.render(RATE));
+
+        // Only the seed changes the noise.
+        let reseeded = Synth {
+            seed: 1,
+            ..synth.clone()
+        };
+        assert_ne!(synth.render(RATE), reseeded.render(RATE));
+    }
+
+    #[test]
+    fn envelope_shapes_the_sound() {
+        // A constant oscillator, so the samples trace the envelope.
+        let synth = Synth {
+            duty: 1.0,
+            volume: 1.0,
+            ..Synth::new(Waveform::Square, 1.0).with_envelope(0.125, 0.25, 0.5, 0.125, 0.5)
+        };
+        assert_eq!(synth.duration(), 1.0);
+
+        let samples = synth.render(RATE);
+        assert_eq!(samples.len(), 1000);
+        let close = |index: usize, expected: f32| {
+            let sample = samples[index];
+            assert!(
+                (sample - expected).abs() < 1e-4,
+                "sample {index} is {sample}"
+            );
+        };
+
+        // Rise through the attack, and fall through the decay.
+        close(0, 0.0);
+        close(25, 0.2);
+        close(125, 1.0);
+        close(250, 0.75);
+
+        // Hold the sustain level, then release to silence.
+        close(375, 0.5);
+        close(499, 0.5);
+        close(750, 0.25);
+        close(999, 0.001);
+    }
+
+    #[test]
+    fn parses_params() {
+        let synth = Synth::parse(["wave=saw", "freq=220", "sustain=2", "lowpass=800", "seed=3"]);
+        assert_eq!(
+            synth,
+            Ok(Synth {
+                waveform: Waveform::Saw,
+                frequency: 220.0,
+                sustain: 1.0,
+                lowpass: Some(800.0),
+                seed: 3,
+                ..Synth::default()
+            })
+        );
+
+        let error = |param| Synth::parse([param]).unwrap_err();
+        assert_eq!(
+            error("pitch=2"),
+            SynthError::UnknownParam("pitch".to_string())
+        );
+        for param in ["freq", "freq=high", "wave=wobble", "seed=-1"] {
+            assert_eq!(error(param), SynthError::InvalidParam(param.to_string()));
+        }
+    }
+}
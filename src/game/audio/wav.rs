@@ -0,0 +1,263 @@
+//! Minimal WAV (RIFF PCM) encoding and decoding.
+
+use std::fmt;
+
+use super::synth::one_pole_alpha;
+
+/// Sample rate of audio rendered by the game, in samples per second.
+pub const SAMPLE_RATE: u32 = 44_100;
+
+/// Encodes interleaved `samples` in `[-1.0, 1.0]` as a 16-bit PCM WAV file.
+pub fn encode(samples: &[f32], channels: u16, sample_rate: u32) -> Vec<u8> {
+    const BITS_PER_SAMPLE: u16 = 16;
+    let block_align = channels * BITS_PER_SAMPLE / 8;
+    let data_len = (samples.len() * 2) as u32;
+
+    let mut bytes = Vec::with_capacity(44 + data_len as usize);
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+    bytes.extend_from_slice(b"WAVE");
+
+    // Format chunk.
+    bytes.extend_from_slice(b"fmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&channels.to_le_bytes());
+    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    bytes.extend_from_slice(&block_align.to_le_bytes());
+    bytes.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+
+    // Data chunk.
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        bytes.extend_from_slice(&sample.to_le_bytes());
+    }
+
+    bytes
+}
+
+/// Decoded PCM audio.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Wav {
+    /// Number of interleaved channels.
+    pub channels: u16,
+
+    /// Sample rate, in samples per second.
+    pub sample_rate: u32,
+
+    /// Interleaved samples, in `[-1.0, 1.0]`.
+    pub samples: Vec<f32>,
+}
+
+impl Wav {
+    /// Decodes a WAV file of 8, 16, 24 or 32-bit integer, or 32-bit float, samples.
+    pub fn decode(bytes: &[u8]) -> Result<Self, WavError> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(WavError::InvalidHeader);
+        }
+
+        let mut format = None;
+        let mut data = None;
+        let mut chunks = &bytes[12..];
+        while chunks.len() >= 8 {
+            let id = &chunks[0..4];
+            let len = u32::from_le_bytes(chunks[4..8].try_into().unwrap()) as usize;
+            let body = chunks.get(8..8 + len).ok_or(WavError::UnexpectedEof)?;
+            match id {
+                b"fmt " if len >= 16 => format = Some(body),
+                b"data" => data = Some(body),
+                _ => {}
+            }
+
+            // Chunks are padded to an even length.
+            chunks = chunks.get(8 + len + (len & 1)..).unwrap_or_default();
+        }
+
+        let (Some(format), Some(data)) = (format, data) else {
+            return Err(WavError::MissingChunk);
+        };
+        let mut tag = u16::from_le_bytes([format[0], format[1]]);
+        let channels = u16::from_le_bytes([format[2], format[3]]);
+        let sample_rate = u32::from_le_bytes(format[4..8].try_into().unwrap());
+        let bits = u16::from_le_bytes([format[14], format[15]]);
+
+        // Extensible formats keep their real tag in the sub-format.
+        if tag == 0xfffe && format.len() >= 26 {
+            tag = u16::from_le_bytes([format[24], format[25]]);
+        }
+        if channels == 0 {
+            return Err(WavError::UnsupportedFormat { tag, bits });
+        }
+
+        let samples = match (tag, bits) {
+            (1, 8) => data.iter().map(|&b| (b as f32 - 128.0) / 128.0).collect(),
+            (1, 16) => data
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32_768.0)
+                .collect(),
+            (1, 24) => data
+                .chunks_exact(3)
+                .map(|b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2_147_483_648.0)
+                .collect(),
+            (1, 32) => data
+                .chunks_exact(4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0)
+                .collect(),
+            (3, 32) => data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            _ => return Err(WavError::UnsupportedFormat { tag, bits }),
+        };
+
+        Ok(Self {
+            channels,
+            sample_rate,
+            samples,
+        })
+    }
+
+    /// Encodes the audio as a 16-bit PCM WAV file.
+    pub fn encode(&self) -> Vec<u8> {
+        encode(&self.samples, self.channels, self.sample_rate)
+    }
+
+    /// Returns the number of sample frames (samples per channel).
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+
+    /// Returns the audio pitched by `semitones`, by resampling it
+    /// with linear interpolation. Pitching up shortens the audio.
+    pub fn pitched(&self, semitones: f32) -> Self {
+        self.resampled_by((semitones / 12.0).exp2() as f64)
+    }
+
+    /// Returns the audio at `sample_rate`, without changing its pitch.
+    pub fn resampled(&self, sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            ..self.resampled_by(self.sample_rate as f64 / sample_rate as f64)
+        }
+    }
+
+    /// Returns the audio in stereo, duplicating mono audio into both
+    /// channels and keeping only the first two of any others.
+    pub fn to_stereo(&self) -> Self {
+        let channels = self.channels as usize;
+        let mut samples = Vec::with_capacity(self.frames() * 2);
+        for frame in self.samples.chunks_exact(channels) {
+            samples.push(frame[0]);
+            samples.push(frame[1.min(channels - 1)]);
+        }
+
+        Self {
+            channels: 2,
+            sample_rate: self.sample_rate,
+            samples,
+        }
+    }
+
+    /// Returns the audio with each frame read `ratio` frames
+    /// apart, interpolating linearly between frames.
+    fn resampled_by(&self, ratio: f64) -> Self {
+        let channels = self.channels as usize;
+        let frames = self.frames();
+        let pitched_frames = (frames as f64 / ratio).floor() as usize;
+
+        let mut samples = Vec::with_capacity(pitched_frames * channels);
+        for frame in 0..pitched_frames {
+            let position = frame as f64 * ratio;
+            let index = position as usize;
+            let t = (position - index as f64) as f32;
+            for channel in 0..channels {
+                let a = self.samples[index * channels + channel];
+                let b = if index + 1 < frames {
+                    self.samples[(index + 1) * channels + channel]
+                } else {
+                    0.0
+                };
+                samples.push(a + (b - a) * t);
+            }
+        }
+
+        Self {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            samples,
+        }
+    }
+
+    /// Returns the audio mixed down to mono, then placed in stereo
+    /// with `left` and `right` as the volume of each channel.
+    pub fn panned(&self, left: f32, right: f32) -> Self {
+        let channels = self.channels as usize;
+        let mut samples = Vec::with_capacity(self.frames() * 2);
+        for frame in self.samples.chunks_exact(channels) {
+            let mono = frame.iter().sum::<f32>() / channels as f32;
+            samples.push(mono * left);
+            samples.push(mono * right);
+        }
+
+        Self {
+            channels: 2,
+            sample_rate: self.sample_rate,
+            samples,
+        }
+    }
+
+    /// Returns the audio passed through a one-pole low-pass filter at `cutoff` hertz.
+    pub fn lowpassed(&self, cutoff: f32) -> Self {
+        let alpha = one_pole_alpha(cutoff, 1.0 / self.sample_rate as f32);
+        let channels = self.channels as usize;
+
+        let mut samples = self.samples.clone();
+        let mut last = vec![0.0; channels];
+        for frame in samples.chunks_exact_mut(channels) {
+            for (sample, last) in frame.iter_mut().zip(&mut last) {
+                *last += alpha * (*sample - *last);
+                *sample = *last;
+            }
+        }
+
+        Self {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            samples,
+        }
+    }
+}
+
+/// Errors encountered while decoding a [Wav].
+#[derive(Clone, Debug, PartialEq)]
+pub enum WavError {
+    /// The file doesn't start with a RIFF WAVE header.
+    InvalidHeader,
+
+    /// The file ended in the middle of a chunk.
+    UnexpectedEof,
+
+    /// The file has no format or data chunk.
+    MissingChunk,
+
+    /// The samples aren't in a supported format.
+    UnsupportedFormat { tag: u16, bits: u16 },
+}
+
+impl fmt::Display for WavError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WavError::InvalidHeader => write!(f, "not a WAV file"),
+            WavError::UnexpectedEof => write!(f, "unexpected end of file"),
+            WavError::MissingChunk => write!(f, "missing format or data chunk"),
+            WavError::UnsupportedFormat { tag, bits } => {
+                write!(f, "unsupported sample format {tag} ({bits}-bit)")
+            }
+        }
+    }
+}
+
+impl std::error::Error for WavError {}
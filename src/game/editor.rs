@@ -0,0 +1,469 @@
+//! In-game level editor.
+//!
+//! The editor paints directly onto a level's image, using the colors
+//! of the map [`Palette`](crate::engine::tile::Palette) as brushes, and
+//! reloads each painted pixel into the [`GameMap`] as it changes. Since
+//! the image is the source of truth, exported levels always use the
+//! exact colors the level loader reads.
+//!
+//! Levels are edited at the resolution they were authored at, with each
+//! pixel painting a square of tiles, so exports match the level assets.
+
+use std::collections::{BTreeSet, VecDeque};
+
+use glam::Vec2;
+use image::{DynamicImage, Rgba, RgbaImage};
+use macroquad::prelude::{
+    GRAY, KeyCode, MouseButton, is_key_down, is_key_pressed, is_mouse_button_down,
+    is_mouse_button_pressed, is_mouse_button_released, mouse_position,
+};
+
+use super::map::{FOREGROUND_LAYER, GameMap, Level, LevelIssue};
+use crate::engine::tile::{PaletteColorMapper, PaletteMatch, Tile};
+
+/// Camera panning velocity in grid units per second.
+const CAMERA_VELOCITY: f32 = 20.0;
+
+/// Largest supported brush size, in level pixels.
+const MAX_BRUSH_SIZE: u32 = 9;
+
+/// Painting tools available in the editor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tool {
+    /// Paints a square of tiles under the cursor while the mouse is held.
+    Brush,
+
+    /// Paints a rectangle of tiles between where the mouse
+    /// is pressed and where it's released.
+    Rectangle,
+
+    /// Paints all tiles connected to the clicked tile which
+    /// share its color.
+    Fill,
+}
+
+/// A single pixel changed by an edit.
+#[derive(Clone, Copy)]
+struct PixelEdit {
+    x: u32,
+    y: u32,
+    before: Rgba<u8>,
+    after: Rgba<u8>,
+}
+
+/// Level editor state.
+pub struct Editor {
+    /// The level image being edited, in grid orientation.
+    image: RgbaImage,
+
+    /// Number of tiles along each side of a pixel in the image.
+    tiles_per_pixel: u32,
+
+    /// Mapper for reloading painted pixels into the map.
+    color_mapper: PaletteColorMapper,
+
+    /// Index of the palette entry used as the brush color.
+    brush: usize,
+    brush_size: u32,
+    tool: Tool,
+
+    /// Pixel edits made by the in-progress brush stroke.
+    stroke: Vec<PixelEdit>,
+
+    /// Grid point where the in-progress rectangle started.
+    rectangle_anchor: Option<(u32, u32)>,
+
+    /// Completed edits which can be undone, most recent last.
+    undo_stack: Vec<Vec<PixelEdit>>,
+
+    /// Undone edits which can be redone, most recent last.
+    redo_stack: Vec<Vec<PixelEdit>>,
+
+    /// Grid point the viewport is centered on.
+    pub camera: Vec2,
+
+    /// Issues found the last time the level was validated.
+    pub issues: Vec<LevelIssue>,
+}
+
+impl Editor {
+    /// Opens the editor on a `level`, reloading
+    /// `map` to reflect the level as authored.
+    pub fn new(level: &Level, map: &mut GameMap, camera: Vec2) -> Self {
+        let spawn_point = map.load_map(level);
+        let issues = map.validate(spawn_point);
+
+        Self {
+            image: level.image.to_rgba8(),
+            tiles_per_pixel: level.tiles_per_pixel,
+            color_mapper: map.color_mapper(),
+            brush: 0,
+            brush_size: 1,
+            tool: Tool::Brush,
+            stroke: vec![],
+            rectangle_anchor: None,
+            undo_stack: vec![],
+            redo_stack: vec![],
+            camera,
+            issues,
+        }
+    }
+
+    /// Returns the edited level, at the resolution it was authored at.
+    pub fn level(&self) -> Level {
+        Level {
+            image: DynamicImage::ImageRgba8(self.image.clone()),
+            tiles_per_pixel: self.tiles_per_pixel,
+        }
+    }
+
+    /// Updates the editor from user input, painting onto `map`.
+    pub fn update(&mut self, frame_time: f32, map: &mut GameMap) {
+        let brush_count = map.palette.entries.len();
+
+        // Select brushes by number.
+        const BRUSH_KEYS: [KeyCode; 9] = [
+            KeyCode::Key1,
+            KeyCode::Key2,
+            KeyCode::Key3,
+            KeyCode::Key4,
+            KeyCode::Key5,
+            KeyCode::Key6,
+            KeyCode::Key7,
+            KeyCode::Key8,
+            KeyCode::Key9,
+        ];
+        for (i, key) in BRUSH_KEYS.iter().enumerate().take(brush_count) {
+            if is_key_pressed(*key) {
+                self.brush = i;
+            }
+        }
+
+        // Select tools.
+        if is_key_pressed(KeyCode::B) {
+            self.tool = Tool::Brush;
+        } else if is_key_pressed(KeyCode::R) {
+            self.tool = Tool::Rectangle;
+        } else if is_key_pressed(KeyCode::F) {
+            self.tool = Tool::Fill;
+        }
+
+        // Resize brushes.
+        if is_key_pressed(KeyCode::LeftBracket) {
+            self.brush_size = self.brush_size.saturating_sub(1).max(1);
+        } else if is_key_pressed(KeyCode::RightBracket) {
+            self.brush_size = (self.brush_size + 1).min(MAX_BRUSH_SIZE);
+        }
+
+        // Undo and redo.
+        if is_key_pressed(KeyCode::Z) {
+            self.undo(map);
+        } else if is_key_pressed(KeyCode::Y) {
+            self.redo(map);
+        }
+
+        // Pan the camera with WASD, relative to screen-space.
+        let velocity = CAMERA_VELOCITY * frame_time;
+        if is_key_down(KeyCode::W) {
+            self.camera -= Vec2::new(velocity, velocity);
+        } else if is_key_down(KeyCode::S) {
+            self.camera += Vec2::new(velocity, velocity);
+        }
+        if is_key_down(KeyCode::A) {
+            self.camera += Vec2::new(-velocity, velocity);
+        } else if is_key_down(KeyCode::D) {
+            self.camera += Vec2::new(velocity, -velocity);
+        }
+
+        // Paint with the current tool.
+        let cursor = self.cursor(map);
+        match self.tool {
+            Tool::Brush => {
+                if is_mouse_button_down(MouseButton::Left)
+                    && let Some((x, y)) = cursor
+                {
+                    let color = self.brush_color(map);
+                    let radius = (self.brush_size - 1) / 2;
+                    let min_x = x.saturating_sub(radius);
+                    let min_y = y.saturating_sub(radius);
+                    self.paint_rectangle(
+                        map,
+                        (min_x, min_y),
+                        (min_x + self.brush_size - 1, min_y + self.brush_size - 1),
+                        color,
+                    );
+                }
+
+                if is_mouse_button_released(MouseButton::Left) {
+                    self.commit_stroke(map);
+                }
+            }
+
+            Tool::Rectangle => {
+                if is_mouse_button_pressed(MouseButton::Left) {
+                    self.rectangle_anchor = cursor;
+                }
+
+                if is_mouse_button_released(MouseButton::Left) {
+                    if let (Some(anchor), Some(cursor)) = (self.rectangle_anchor.take(), cursor) {
+                        let color = self.brush_color(map);
+                        self.paint_rectangle(map, anchor, cursor, color);
+                    }
+                    self.commit_stroke(map);
+                }
+            }
+
+            Tool::Fill => {
+                if is_mouse_button_pressed(MouseButton::Left)
+                    && let Some((x, y)) = cursor
+                {
+                    let color = self.brush_color(map);
+                    self.paint_fill(map, x, y, color);
+                    self.commit_stroke(map);
+                }
+            }
+        }
+    }
+
+    /// Draws the editor's controls and status.
+    pub fn draw(&self, map: &GameMap) {
+        let entry = &map.palette.entries[self.brush];
+        let [r, g, b, a] = entry.color.into();
+        let mut lines = vec![
+            format!(
+                "[editor] {:?} tool, {} brush (#{r:02x}{g:02x}{b:02x}{a:02x}), size {}",
+                self.tool, entry.template, self.brush_size
+            ),
+            "[1-9]: brush  [b r f]: tool  [ [ ] ]: size".to_string(),
+            "[z]: undo  [y]: redo  [p]: export  [tab]: play".to_string(),
+        ];
+
+        if let Some((x, y)) = self.cursor(map) {
+            lines.push(format!("Pixel [{x}, {y}]"));
+        }
+
+        if let Some((x, y)) = self.rectangle_anchor {
+            lines.push(format!("Rectangle from [{x}, {y}]"));
+        }
+
+        for issue in &self.issues {
+            lines.push(format!("Issue: {issue}"));
+        }
+
+        for (i, line) in lines.iter().enumerate() {
+            macroquad::prelude::draw_text(line, 10., 20. + 20. * i as f32, 20., GRAY);
+        }
+    }
+
+    /// Returns the level pixel under the mouse cursor,
+    /// if it's inside the level.
+    fn cursor(&self, map: &GameMap) -> Option<(u32, u32)> {
+        let (mouse_x, mouse_y) = mouse_position();
+        let tile = map
+            .map
+            .view_to_grid(mouse_x, mouse_y, FOREGROUND_LAYER)
+            .round();
+        let point = (tile / self.tiles_per_pixel as f32).floor();
+
+        if point.x < 0.0
+            || point.y < 0.0
+            || point.x >= self.image.width() as f32
+            || point.y >= self.image.height() as f32
+        {
+            return None;
+        }
+
+        Some((point.x as u32, point.y as u32))
+    }
+
+    /// Returns the color of the current brush.
+    fn brush_color(&self, map: &GameMap) -> Rgba<u8> {
+        Rgba(map.palette.entries[self.brush].color.into())
+    }
+
+    /// Paints all pixels in the rectangle between the corners `a` and `b`.
+    fn paint_rectangle(
+        &mut self,
+        map: &mut GameMap,
+        a: (u32, u32),
+        b: (u32, u32),
+        color: Rgba<u8>,
+    ) {
+        let max_x = a.0.max(b.0).min(self.image.width() - 1);
+        let max_y = a.1.max(b.1).min(self.image.height() - 1);
+        for x in a.0.min(b.0)..=max_x {
+            for y in a.1.min(b.1)..=max_y {
+                self.paint_pixel(map, x, y, color);
+            }
+        }
+    }
+
+    /// Paints all pixels orthogonally connected to `x, y` that share its color.
+    fn paint_fill(&mut self, map: &mut GameMap, x: u32, y: u32, color: Rgba<u8>) {
+        let target = *self.image.get_pixel(x, y);
+        if target == color {
+            return;
+        }
+
+        let (width, height) = self.image.dimensions();
+        let mut visited = BTreeSet::from([(x, y)]);
+        let mut queue = VecDeque::from([(x, y)]);
+        while let Some((x, y)) = queue.pop_front() {
+            self.paint_pixel(map, x, y, color);
+
+            let neighbors = [
+                x.checked_sub(1).map(|x| (x, y)),
+                (x + 1 < width).then_some((x + 1, y)),
+                y.checked_sub(1).map(|y| (x, y)),
+                (y + 1 < height).then_some((x, y + 1)),
+            ];
+            for (nx, ny) in neighbors.into_iter().flatten() {
+                if *self.image.get_pixel(nx, ny) == target && visited.insert((nx, ny)) {
+                    queue.push_back((nx, ny));
+                }
+            }
+        }
+    }
+
+    /// Paints a single pixel, recording the edit in the in-progress stroke.
+    ///
+    /// Painting a spawn point clears any other spawn points in the level.
+    fn paint_pixel(&mut self, map: &mut GameMap, x: u32, y: u32, color: Rgba<u8>) {
+        if *self.image.get_pixel(x, y) == color {
+            return;
+        }
+
+        if is_spawn_color(map, color) {
+            self.clear_spawn_points(map);
+        }
+
+        let before = *self.image.get_pixel(x, y);
+        self.set_pixel(map, x, y, color);
+        self.stroke.push(PixelEdit {
+            x,
+            y,
+            before,
+            after: color,
+        });
+    }
+
+    /// Replaces every spawn point in the level with floor.
+    fn clear_spawn_points(&mut self, map: &mut GameMap) {
+        // Spawn points are replaced with the first
+        // palette entry for the fallback template.
+        let Some(floor) = map
+            .palette
+            .entries
+            .iter()
+            .find(|e| !e.spawn && Some(&e.template) == map.palette.fallback.as_ref())
+            .map(|e| Rgba(e.color.into()))
+        else {
+            return;
+        };
+
+        let spawn_pixels: Vec<(u32, u32)> = self
+            .image
+            .enumerate_pixels()
+            .filter(|(.., color)| is_spawn_color(map, **color))
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        for (x, y) in spawn_pixels {
+            let before = *self.image.get_pixel(x, y);
+            self.set_pixel(map, x, y, floor);
+            self.stroke.push(PixelEdit {
+                x,
+                y,
+                before,
+                after: floor,
+            });
+        }
+    }
+
+    /// Sets a pixel in the level image, reloading its tiles into `map`.
+    fn set_pixel(&mut self, map: &mut GameMap, x: u32, y: u32, color: Rgba<u8>) {
+        self.image.put_pixel(x, y, color);
+
+        let scale = self.tiles_per_pixel;
+        for tile_x in x * scale..(x + 1) * scale {
+            for tile_y in y * scale..(y + 1) * scale {
+                // Clear the old tile first, as skipped colors leave it in place.
+                map.map.set_tile(
+                    tile_x as usize,
+                    tile_y as usize,
+                    FOREGROUND_LAYER,
+                    Tile::Empty,
+                );
+                map.map.load_pixel(
+                    tile_x,
+                    tile_y,
+                    FOREGROUND_LAYER,
+                    color,
+                    &mut self.color_mapper,
+                );
+            }
+        }
+    }
+
+    /// Re-validates the level after an edit.
+    fn validate(&mut self, map: &GameMap) {
+        let spawn_point = self
+            .image
+            .enumerate_pixels()
+            .find(|(.., color)| is_spawn_color(map, **color))
+            .map(|(x, y, _)| Vec2::new(x as f32, y as f32) * self.tiles_per_pixel as f32);
+
+        self.issues = map.validate(spawn_point);
+    }
+
+    /// Finishes the in-progress stroke, making it undoable.
+    fn commit_stroke(&mut self, map: &GameMap) {
+        if self.stroke.is_empty() {
+            return;
+        }
+
+        self.undo_stack.push(std::mem::take(&mut self.stroke));
+        self.redo_stack.clear();
+        self.validate(map);
+    }
+
+    /// Reverts the most recent edit.
+    fn undo(&mut self, map: &mut GameMap) {
+        let Some(edits) = self.undo_stack.pop() else {
+            return;
+        };
+
+        for edit in edits.iter().rev() {
+            self.set_pixel(map, edit.x, edit.y, edit.before);
+        }
+
+        self.redo_stack.push(edits);
+        self.validate(map);
+    }
+
+    /// Reapplies the most recently undone edit.
+    fn redo(&mut self, map: &mut GameMap) {
+        let Some(edits) = self.redo_stack.pop() else {
+            return;
+        };
+
+        for edit in &edits {
+            self.set_pixel(map, edit.x, edit.y, edit.after);
+        }
+
+        self.undo_stack.push(edits);
+        self.validate(map);
+    }
+}
+
+/// Returns true if `color` marks a spawn point in `map`'s palette.
+///
+/// Colors are matched within the palette's tolerance, the same
+/// way they're matched when the level is loaded.
+fn is_spawn_color(map: &GameMap, color: Rgba<u8>) -> bool {
+    match map.palette.match_color(color) {
+        PaletteMatch::Entry(index) | PaletteMatch::Ambiguous { nearest: index, .. } => {
+            map.palette.entries[index].spawn
+        }
+        PaletteMatch::Unknown => false,
+    }
+}
@@ -91,10 +91,10 @@ impl Player {
             self.sprite_flipped = self.position.x < target_pos.x;
 
             // Only permit moves which keep the player on the map.
-            if self.position.x < 0.0 || self.position.x > (map::WIDTH - 1) as f32 {
+            if self.position.x < 0.0 || self.position.x > (map.width() - 1) as f32 {
                 self.position.x = last_pos.x;
             }
-            if self.position.y < 0.0 || self.position.y > (map::HEIGHT - 1) as f32 {
+            if self.position.y < 0.0 || self.position.y > (map.height() - 1) as f32 {
                 self.position.y = last_pos.y;
             }
 
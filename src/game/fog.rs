@@ -4,10 +4,17 @@ use glam::Vec2;
 
 use crate::engine::tile::{TileMap, TileTexture};
 
-pub const TINY_MAX_PULSE_RADIUS: isize = (super::map::HEIGHT as f32 * 0.05) as isize;
-pub const SMALL_MAX_PULSE_RADIUS: isize = (super::map::HEIGHT as f32 * 0.1) as isize;
-pub const MEDIUM_MAX_PULSE_RADIUS: isize = (super::map::HEIGHT as f32 * 0.2) as isize;
-pub const LARGE_MAX_PULSE_RADIUS: isize = (super::map::HEIGHT as f32 * 0.3) as isize;
+// Maximum pulse radii, relative to the size of the map.
+pub const TINY_PULSE: f32 = 0.05;
+pub const SMALL_PULSE: f32 = 0.1;
+pub const MEDIUM_PULSE: f32 = 0.2;
+pub const LARGE_PULSE: f32 = 0.3;
+
+/// Returns the maximum radius, in tiles, of a pulse
+/// spanning `fraction` of the smaller side of `map`.
+pub fn max_pulse_radius(map: &TileMap, fraction: f32) -> isize {
+    (map.width().min(map.height()) as f32 * fraction) as isize
+}
 
 pub struct Pulse {
     pub origin: Vec2,
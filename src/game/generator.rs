@@ -0,0 +1,542 @@
+//! Seeded procedural level generation.
+//!
+//! Generated levels are images using the same colors as the hand-made
+//! [`map::TILEMAPS`], so they load through the same pipeline. Every
+//! generated level has a spawn point and at least one objective cluster,
+//! and every floor tile (including every objective) is reachable from it.
+
+use std::collections::{BTreeSet, VecDeque};
+
+use glam::Vec2;
+use image::{DynamicImage, Rgba, RgbaImage};
+
+use crate::engine::rng::Rng;
+use crate::game::map::{self, GameMap, Level};
+
+/// Transparent color used for floor tiles, matching the level assets.
+const FLOOR: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+/// Smallest side length of a room generated by [`Method::Rooms`].
+const MIN_ROOM_SIZE: usize = 3;
+
+/// Smallest side length of a generated level: a room and its walls.
+pub const MIN_LEVEL_SIZE: usize = MIN_ROOM_SIZE + 2;
+
+/// Algorithm used to carve a level's walls.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    /// Organic caves grown with a cellular automaton.
+    Caves,
+
+    /// Rectangular rooms joined by corridors, laid out
+    /// with binary space partitioning.
+    Rooms,
+
+    /// Winding tunnels dug by a random walk.
+    DrunkardsWalk,
+}
+
+/// Configuration for generating a level.
+#[derive(Clone, Debug)]
+pub struct LevelGenerator {
+    /// Seed for the random number generator; the same
+    /// configuration and seed always yield the same level.
+    pub seed: u64,
+
+    /// Algorithm used to carve the level.
+    pub method: Method,
+
+    /// Level width, in pixels.
+    ///
+    /// Levels are at least [`MIN_LEVEL_SIZE`] wide.
+    pub width: usize,
+
+    /// Level height, in pixels.
+    ///
+    /// Levels are at least [`MIN_LEVEL_SIZE`] high.
+    pub height: usize,
+
+    /// Approximate fraction of the level which is wall,
+    /// from `0.0` (open) to `1.0` (solid).
+    pub wall_density: f32,
+
+    /// Number of separate objective clusters to place.
+    ///
+    /// At least one cluster is always placed, and fewer are
+    /// placed if the level has no room left for them.
+    pub objective_clusters: usize,
+
+    /// Number of objective tiles in each cluster.
+    pub cluster_size: usize,
+
+    /// Number of tiles along each side of a pixel when
+    /// the level is loaded into a map.
+    pub tiles_per_pixel: u32,
+}
+
+impl LevelGenerator {
+    /// Returns a new generator for `method` with default settings.
+    pub fn new(seed: u64, method: Method) -> Self {
+        Self {
+            seed,
+            method,
+            width: 32,
+            height: 32,
+            wall_density: 0.45,
+            objective_clusters: 4,
+            cluster_size: 4,
+            tiles_per_pixel: 4,
+        }
+    }
+
+    /// Generates a level image in grid orientation, with one pixel per
+    /// tile in the level's layout.
+    ///
+    /// Use [`Self::generate_level`] for a level that's ready to
+    /// be loaded with [`GameMap::load_map`].
+    pub fn generate_image(&self) -> DynamicImage {
+        // Grow levels which are too small to fit a room.
+        if self.width < MIN_LEVEL_SIZE || self.height < MIN_LEVEL_SIZE {
+            let generator = Self {
+                width: self.width.max(MIN_LEVEL_SIZE),
+                height: self.height.max(MIN_LEVEL_SIZE),
+                ..self.clone()
+            };
+            return generator.generate_image();
+        }
+
+        let mut rng = Rng::new(self.seed);
+
+        // Carve walls.
+        let mut walls = match self.method {
+            Method::Caves => self.carve_caves(&mut rng),
+            Method::Rooms => self.carve_rooms(&mut rng),
+            Method::DrunkardsWalk => self.carve_drunkards_walk(&mut rng),
+        };
+
+        // Wall off everything but the largest open region,
+        // so that every floor tile is reachable.
+        let mut region = self.largest_region(&walls);
+
+        // Carve a room in the middle if there's no room for
+        // both the spawn point and an objective.
+        if region.len() < 2 {
+            walls.fill(true);
+            let room_x = (self.width - MIN_ROOM_SIZE) / 2;
+            let room_y = (self.height - MIN_ROOM_SIZE) / 2;
+            for x in room_x..room_x + MIN_ROOM_SIZE {
+                for y in room_y..room_y + MIN_ROOM_SIZE {
+                    walls[self.index(x, y)] = false;
+                }
+            }
+            region = self.largest_region(&walls);
+        }
+
+        for x in 0..self.width {
+            for y in 0..self.height {
+                if !region.contains(&(x, y)) {
+                    walls[self.index(x, y)] = true;
+                }
+            }
+        }
+
+        let mut image = RgbaImage::from_pixel(self.width as u32, self.height as u32, FLOOR);
+        for x in 0..self.width {
+            for y in 0..self.height {
+                if walls[self.index(x, y)] {
+                    image.put_pixel(x as u32, y as u32, Rgba(map::BACKGROUND.into()));
+                }
+            }
+        }
+
+        // Place the spawn point and objectives on the open region.
+        let open: Vec<(usize, usize)> = region.into_iter().collect();
+        let spawn = open[rng.range(0..open.len())];
+        image.put_pixel(spawn.0 as u32, spawn.1 as u32, Rgba(map::ACCENT_3.into()));
+
+        for (x, y) in self.place_objectives(&mut rng, &walls, &open, spawn) {
+            image.put_pixel(x as u32, y as u32, Rgba(map::ACCENT_1.into()));
+        }
+
+        DynamicImage::ImageRgba8(image)
+    }
+
+    /// Generates a level played at [`Self::tiles_per_pixel`],
+    /// ready to be loaded with [`GameMap::load_map`].
+    pub fn generate_level(&self) -> Level {
+        Level {
+            image: self.generate_image(),
+            tiles_per_pixel: self.tiles_per_pixel,
+        }
+    }
+
+    /// Generates a level directly into `map`'s tile map.
+    ///
+    /// Returns the player spawn position.
+    pub fn generate_map(&self, map: &mut GameMap) -> Option<Vec2> {
+        map.load_map(&self.generate_level())
+    }
+
+    /// Carves caves by smoothing random noise with a cellular automaton.
+    fn carve_caves(&self, rng: &mut Rng) -> Vec<bool> {
+        let mut walls: Vec<bool> = (0..self.width * self.height)
+            .map(|_| rng.chance(self.wall_density))
+            .collect();
+
+        // Each tile becomes a wall when most of its neighbors are walls.
+        for _ in 0..5 {
+            let mut next = walls.clone();
+            for x in 0..self.width {
+                for y in 0..self.height {
+                    let neighbors = self.count_wall_neighbors(&walls, x, y);
+                    next[self.index(x, y)] =
+                        neighbors >= 5 || (neighbors == 4 && walls[self.index(x, y)]);
+                }
+            }
+            walls = next;
+        }
+
+        walls
+    }
+
+    /// Carves rooms in the leaves of a binary space partition,
+    /// joining sibling partitions with corridors.
+    fn carve_rooms(&self, rng: &mut Rng) -> Vec<bool> {
+        let mut walls = vec![true; self.width * self.height];
+
+        // Denser levels have smaller rooms within each partition.
+        let room_fill = (1.0 - self.wall_density).clamp(0.3, 1.0);
+
+        self.partition(rng, &mut walls, (0, 0, self.width, self.height), room_fill);
+
+        walls
+    }
+
+    /// Recursively partitions `area`, carving a room in each leaf.
+    ///
+    /// Returns the center of one room in the partition.
+    fn partition(
+        &self,
+        rng: &mut Rng,
+        walls: &mut [bool],
+        area: (usize, usize, usize, usize),
+        room_fill: f32,
+    ) -> (usize, usize) {
+        let (x, y, width, height) = area;
+
+        // Leaves must fit a room and its surrounding walls.
+        let min_half = MIN_ROOM_SIZE + 2;
+        let min_leaf = min_half * 2;
+
+        // Split along the longer axis while the area is large enough.
+        let split_x = width >= height && width >= min_leaf;
+        let split_y = !split_x && height >= min_leaf;
+
+        if split_x || split_y {
+            let length = if split_x { width } else { height };
+            let split = rng.range(min_half..length - min_half + 1);
+            let (a, b) = if split_x {
+                ((x, y, split, height), (x + split, y, width - split, height))
+            } else {
+                ((x, y, width, split), (x, y + split, width, height - split))
+            };
+
+            let a = self.partition(rng, walls, a, room_fill);
+            let b = self.partition(rng, walls, b, room_fill);
+
+            // Join the partitions with an L-shaped corridor.
+            for cx in a.0.min(b.0)..=a.0.max(b.0) {
+                walls[self.index(cx, a.1)] = false;
+            }
+            for cy in a.1.min(b.1)..=a.1.max(b.1) {
+                walls[self.index(b.0, cy)] = false;
+            }
+
+            return if rng.chance(0.5) { a } else { b };
+        }
+
+        // Carve a room inside the leaf, leaving a wall margin.
+        let max_width = width.saturating_sub(2).max(1);
+        let max_height = height.saturating_sub(2).max(1);
+        let room_width = ((max_width as f32 * room_fill) as usize)
+            .max(MIN_ROOM_SIZE)
+            .min(max_width);
+        let room_height = ((max_height as f32 * room_fill) as usize)
+            .max(MIN_ROOM_SIZE)
+            .min(max_height);
+        let room_x = x + 1 + rng.range(0..max_width - room_width + 1);
+        let room_y = y + 1 + rng.range(0..max_height - room_height + 1);
+
+        for rx in room_x..(room_x + room_width).min(self.width) {
+            for ry in room_y..(room_y + room_height).min(self.height) {
+                walls[self.index(rx, ry)] = false;
+            }
+        }
+
+        (
+            (room_x + room_width / 2).min(self.width - 1),
+            (room_y + room_height / 2).min(self.height - 1),
+        )
+    }
+
+    /// Carves tunnels with a random walk until enough of the level is open.
+    fn carve_drunkards_walk(&self, rng: &mut Rng) -> Vec<bool> {
+        let mut walls = vec![true; self.width * self.height];
+
+        let target_open =
+            ((self.width * self.height) as f32 * (1.0 - self.wall_density)).max(1.0) as usize;
+        let mut open = 0;
+        let (mut x, mut y) = (self.width / 2, self.height / 2);
+
+        // Cap the walk so that degenerate densities always terminate.
+        let max_steps = self.width * self.height * 64;
+        for _ in 0..max_steps {
+            if open >= target_open {
+                break;
+            }
+
+            if walls[self.index(x, y)] {
+                walls[self.index(x, y)] = false;
+                open += 1;
+            }
+
+            match rng.range(0..4) {
+                0 => x = x.saturating_sub(1),
+                1 => x = (x + 1).min(self.width - 1),
+                2 => y = y.saturating_sub(1),
+                _ => y = (y + 1).min(self.height - 1),
+            }
+        }
+
+        walls
+    }
+
+    /// Places objective clusters on the `open` tiles, preferring
+    /// tiles far away from `spawn`.
+    fn place_objectives(
+        &self,
+        rng: &mut Rng,
+        walls: &[bool],
+        open: &[(usize, usize)],
+        spawn: (usize, usize),
+    ) -> BTreeSet<(usize, usize)> {
+        let mut objectives = BTreeSet::new();
+
+        for _ in 0..self.objective_clusters.max(1) {
+            // Clusters must be apart from each other and the spawn.
+            let is_free = |candidate: (usize, usize), objectives: &BTreeSet<_>| {
+                candidate != spawn
+                    && !self
+                        .neighbors(candidate.0, candidate.1)
+                        .chain(std::iter::once(candidate))
+                        .any(|p| objectives.contains(&p))
+            };
+            let distance = |candidate: (usize, usize), objectives: &BTreeSet<(usize, usize)>| {
+                std::iter::once(&spawn)
+                    .chain(objectives.iter())
+                    .map(|p| p.0.abs_diff(candidate.0) + p.1.abs_diff(candidate.1))
+                    .min()
+                    .unwrap_or(0)
+            };
+
+            // Pick the farthest of a few random candidates.
+            let mut best = None;
+            let mut best_distance = 0;
+            for _ in 0..16 {
+                let candidate = open[rng.range(0..open.len())];
+                if !is_free(candidate, &objectives) {
+                    continue;
+                }
+
+                let distance = distance(candidate, &objectives);
+                if best.is_none() || distance > best_distance {
+                    best = Some(candidate);
+                    best_distance = distance;
+                }
+            }
+
+            // If every candidate was taken, fall back to the
+            // farthest free tile, so clusters are only skipped
+            // when there's no room left for them.
+            let best = best.or_else(|| {
+                open.iter()
+                    .copied()
+                    .filter(|&candidate| is_free(candidate, &objectives))
+                    .max_by_key(|&candidate| distance(candidate, &objectives))
+            });
+
+            let Some(seed) = best else {
+                break;
+            };
+
+            // Grow the cluster outwards over open tiles. Clusters must
+            // not touch each other, or they'd be cleared together.
+            let mut cluster = BTreeSet::from([seed]);
+            let mut queue = VecDeque::from([seed]);
+            while let Some((x, y)) = queue.pop_front() {
+                if cluster.len() >= self.cluster_size {
+                    break;
+                }
+
+                for (nx, ny) in self.neighbors(x, y) {
+                    if cluster.len() < self.cluster_size
+                        && !walls[self.index(nx, ny)]
+                        && (nx, ny) != spawn
+                        && !cluster.contains(&(nx, ny))
+                        && !self
+                            .neighbors(nx, ny)
+                            .chain(std::iter::once((nx, ny)))
+                            .any(|p| objectives.contains(&p))
+                    {
+                        cluster.insert((nx, ny));
+                        queue.push_back((nx, ny));
+                    }
+                }
+            }
+
+            objectives.extend(cluster);
+        }
+
+        objectives
+    }
+
+    /// Returns the largest orthogonally-connected set of open tiles.
+    fn largest_region(&self, walls: &[bool]) -> BTreeSet<(usize, usize)> {
+        let mut largest = BTreeSet::new();
+        let mut visited = BTreeSet::new();
+
+        for x in 0..self.width {
+            for y in 0..self.height {
+                if walls[self.index(x, y)] || visited.contains(&(x, y)) {
+                    continue;
+                }
+
+                let mut region = BTreeSet::from([(x, y)]);
+                let mut queue = VecDeque::from([(x, y)]);
+                while let Some((x, y)) = queue.pop_front() {
+                    for (nx, ny) in self.neighbors(x, y) {
+                        if !walls[self.index(nx, ny)] && region.insert((nx, ny)) {
+                            queue.push_back((nx, ny));
+                        }
+                    }
+                }
+
+                visited.extend(region.iter().copied());
+                if region.len() > largest.len() {
+                    largest = region;
+                }
+            }
+        }
+
+        largest
+    }
+
+    /// Counts the walls among the eight tiles surrounding `x, y`,
+    /// treating tiles outside the level as walls.
+    fn count_wall_neighbors(&self, walls: &[bool], x: usize, y: usize) -> usize {
+        let mut count = 0;
+        for dx in -1isize..=1 {
+            for dy in -1isize..=1 {
+                if dx == 0 && dy == 0 {
+                    continue;
+                }
+
+                let nx = x as isize + dx;
+                let ny = y as isize + dy;
+                if nx < 0
+                    || ny < 0
+                    || nx >= self.width as isize
+                    || ny >= self.height as isize
+                    || walls[self.index(nx as usize, ny as usize)]
+                {
+                    count += 1;
+                }
+            }
+        }
+
+        count
+    }
+
+    /// Returns the orthogonal neighbors of `x, y` inside the level.
+    fn neighbors(&self, x: usize, y: usize) -> impl Iterator<Item = (usize, usize)> {
+        let (width, height) = (self.width, self.height);
+        [
+            x.checked_sub(1).map(|x| (x, y)),
+            (x + 1 < width).then_some((x + 1, y)),
+            y.checked_sub(1).map(|y| (x, y)),
+            (y + 1 < height).then_some((x, y + 1)),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Converts `x, y` into an index into a dense list of tiles.
+    fn index(&self, x: usize, y: usize) -> usize {
+        y + self.height * x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::tile::TileTexture;
+    use crate::game::map::LevelIssue;
+
+    /// Every generation method.
+    const METHODS: [Method; 3] = [Method::Caves, Method::Rooms, Method::DrunkardsWalk];
+
+    /// Loads the level generated by `generator` and
+    /// returns the issues found with it.
+    fn validate(generator: &LevelGenerator) -> Vec<LevelIssue> {
+        let mut map = GameMap::new(TileTexture::placeholder(1), TileTexture::placeholder(2));
+        let spawn_point = generator.generate_map(&mut map);
+        map.validate(spawn_point)
+    }
+
+    #[test]
+    fn same_seed_generates_same_level() {
+        for method in METHODS {
+            let a = LevelGenerator::new(7, method).generate_image();
+            let b = LevelGenerator::new(7, method).generate_image();
+            let c = LevelGenerator::new(8, method).generate_image();
+
+            assert_eq!(a, b, "{method:?}");
+            assert_ne!(a, c, "{method:?}");
+        }
+    }
+
+    #[test]
+    fn generated_levels_are_playable() {
+        for method in METHODS {
+            for seed in 0..16 {
+                let mut generator = LevelGenerator::new(seed, method);
+                generator.tiles_per_pixel = 1;
+
+                assert_eq!(validate(&generator), vec![], "{method:?} seed {seed}");
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_settings_generate_playable_levels() {
+        for method in METHODS {
+            for (width, height, wall_density) in [(0, 0, 0.45), (1, 32, 0.45), (8, 8, 1.0)] {
+                let mut generator = LevelGenerator::new(3, method);
+                generator.width = width;
+                generator.height = height;
+                generator.wall_density = wall_density;
+                generator.objective_clusters = 0;
+                generator.tiles_per_pixel = 1;
+
+                let image = generator.generate_image();
+                assert!(image.width() as usize >= MIN_LEVEL_SIZE);
+                assert!(image.height() as usize >= MIN_LEVEL_SIZE);
+                assert_eq!(
+                    validate(&generator),
+                    vec![],
+                    "{method:?} {width}x{height} at {wall_density}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,486 @@
+//! Collected records, and the jukebox for replaying them.
+//!
+//! Each completed level is kept as a [Record] of its music, with as
+//! many of the piece's tracks as were unlocked by clearing the level.
+//! The [Jukebox] lists every collected record, and replays any of them
+//! with a visualiser of the steps played. Records keep their piece's
+//! source, so they play as they were collected even if a level's music
+//! changes later.
+
+use std::fmt;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use macroquad::prelude::{
+    Color as MqColor, GRAY, KeyCode, WHITE, draw_circle, draw_circle_lines, draw_line,
+    draw_rectangle, draw_text, is_key_pressed, screen_height, screen_width,
+};
+use serde::{Deserialize, Serialize};
+
+use super::audio::Piece;
+use super::audio::arrangement::{Arranger, MusicEvent};
+use super::audio::backend::AudioBackend;
+use super::audio::pattern::Composition;
+use super::map;
+use crate::engine::tile::as_macroquad_color;
+
+/// Name of the file the record collection is saved to, in
+/// the game's directory of the per-user data directory.
+#[cfg(not(target_arch = "wasm32"))]
+pub const RECORDS_FILE: &str = "records.ron";
+
+/// Height of each line of the record list, in pixels.
+const LIST_LINE_HEIGHT: f32 = 24.;
+
+/// Space kept below the record list for the liner notes, in pixels.
+const NOTES_HEIGHT: f32 = 160.;
+
+/// Rate at which the visualiser's bars fall, as a fraction per second.
+const VISUALISER_DECAY: f32 = 6.0;
+
+/// A level's music, as collected by completing the level.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Index of the level the record was collected from.
+    pub level: usize,
+
+    /// Title of the level's piece.
+    pub title: String,
+
+    /// Liner notes for the record, one entry per line.
+    pub notes: Vec<String>,
+
+    /// Source of the level's piece, as a [Composition].
+    pub source: String,
+
+    /// Number of the piece's unlock groups which were unlocked.
+    pub unlocked: usize,
+}
+
+/// Every [Record] collected so far, ordered by level.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecordCollection {
+    pub records: Vec<Record>,
+}
+
+impl RecordCollection {
+    /// Adds `record` to the collection, replacing any
+    /// record already collected from the same level.
+    pub fn collect(&mut self, record: Record) {
+        match self
+            .records
+            .binary_search_by_key(&record.level, |r| r.level)
+        {
+            Ok(i) => self.records[i] = record,
+            Err(i) => self.records.insert(i, record),
+        }
+    }
+
+    /// Encodes the collection as RON.
+    pub fn to_ron(&self) -> Result<String, ron::Error> {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+    }
+
+    /// Decodes a collection from RON.
+    pub fn from_ron(source: &str) -> Result<Self, ron::error::SpannedError> {
+        ron::from_str(source)
+    }
+
+    /// Loads the collection from [records_path], or
+    /// returns an empty collection if there isn't one.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load() -> Result<Self, RecordsError> {
+        let path = records_path().ok_or(RecordsError::NoDataDir)?;
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(RecordsError::Load(format!("{}: {e}", path.display()))),
+        };
+
+        Self::from_ron(&source).map_err(|e| RecordsError::Load(format!("{}: {e}", path.display())))
+    }
+
+    /// Saves the collection to [records_path], creating its directory if needed.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save(&self) -> Result<(), RecordsError> {
+        let path = records_path().ok_or(RecordsError::NoDataDir)?;
+        let ron = self
+            .to_ron()
+            .map_err(|e| RecordsError::Save(e.to_string()))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| RecordsError::Save(format!("{}: {e}", dir.display())))?;
+        }
+        std::fs::write(&path, ron)
+            .map_err(|e| RecordsError::Save(format!("{}: {e}", path.display())))
+    }
+}
+
+/// Returns the path the record collection is saved to, in the platform's
+/// per-user data directory, or `None` if there isn't one.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn records_path() -> Option<PathBuf> {
+    let var = |name: &str| {
+        std::env::var_os(name)
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+    };
+    let data_dir = if cfg!(target_os = "windows") {
+        var("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        var("HOME").map(|home| home.join("Library/Application Support"))
+    } else {
+        var("XDG_DATA_HOME").or_else(|| var("HOME").map(|home| home.join(".local/share")))
+    };
+
+    Some(data_dir?.join(env!("CARGO_PKG_NAME")).join(RECORDS_FILE))
+}
+
+/// Errors encountered while loading or saving the record collection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordsError {
+    /// There's no per-user data directory to keep the records in.
+    NoDataDir,
+
+    /// The saved records couldn't be read or decoded.
+    Load(String),
+
+    /// The records couldn't be encoded or written.
+    Save(String),
+}
+
+impl fmt::Display for RecordsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordsError::NoDataDir => write!(f, "no data directory to keep records in"),
+            RecordsError::Load(e) => write!(f, "failed to load records from {e}"),
+            RecordsError::Save(e) => write!(f, "failed to save records to {e}"),
+        }
+    }
+}
+
+impl std::error::Error for RecordsError {}
+
+/// Jukebox screen state.
+pub struct Jukebox {
+    /// Index of the highlighted record.
+    selected: usize,
+
+    /// Index of the first record shown in the list.
+    scroll: usize,
+
+    /// Index in the game's music of the piece playing, with
+    /// the arranger following it, if a record is playing.
+    playing: Option<(usize, Arranger)>,
+
+    /// Height of each of the playing piece's visualiser bars, from `0.0` to `1.0`.
+    levels: Vec<f32>,
+
+    /// Why the last chosen record couldn't be played, if it couldn't.
+    error: Option<String>,
+}
+
+impl Default for Jukebox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Jukebox {
+    /// Opens the jukebox, with nothing playing.
+    pub fn new() -> Self {
+        Self {
+            selected: 0,
+            scroll: 0,
+            playing: None,
+            levels: vec![],
+            error: None,
+        }
+    }
+
+    /// Updates the jukebox, choosing records with the keyboard and
+    /// updating the piece playing from `music`.
+    ///
+    /// Returns the record chosen to play this frame, if one was,
+    /// which should be started with [Jukebox::play].
+    pub fn update<'a>(
+        &mut self,
+        frame_time: f32,
+        records: &'a RecordCollection,
+        music: &mut [(Piece, Arranger)],
+    ) -> Option<&'a Record> {
+        let mut chosen = None;
+        let count = records.records.len();
+        if count > 0 {
+            if is_key_pressed(KeyCode::Up) || is_key_pressed(KeyCode::W) {
+                self.selected = (self.selected + count - 1) % count;
+            } else if is_key_pressed(KeyCode::Down) || is_key_pressed(KeyCode::S) {
+                self.selected = (self.selected + 1) % count;
+            }
+            self.scroll = self.first_row(list_rows(screen_height()));
+
+            if is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::Space) {
+                chosen = Some(&records.records[self.selected]);
+            }
+        }
+
+        // Fade the visualiser, and raise it for every step played.
+        for level in &mut self.levels {
+            *level *= (-VISUALISER_DECAY * frame_time).exp();
+        }
+        if let Some((index, arranger)) = &mut self.playing {
+            let piece = &mut music[*index].0;
+            arranger.update(piece);
+            for event in piece.update() {
+                if let Some(level) = self.levels.get_mut(event.track) {
+                    *level = level.max(event.volume);
+                }
+            }
+        }
+
+        chosen
+    }
+
+    /// Starts playing `record`, with as many tracks unlocked as when
+    /// it was collected.
+    ///
+    /// The record plays through the piece in `music` composed from the
+    /// same source, as listed in `sources`, if there is one. Otherwise
+    /// its piece is composed and loaded through `backend`, and added to
+    /// both so it's only loaded once.
+    pub async fn play(
+        &mut self,
+        record: &Record,
+        music: &mut Vec<(Piece, Arranger)>,
+        sources: &mut Vec<String>,
+        backend: Rc<dyn AudioBackend>,
+    ) {
+        self.stop(music);
+        self.error = None;
+
+        let index = match sources.iter().position(|source| *source == record.source) {
+            Some(index) => index,
+            None => {
+                let composition = match Composition::parse(&record.source) {
+                    Ok(composition) => composition,
+                    Err(e) => {
+                        self.error = Some(format!("Can't play {}: {e}", record.title));
+                        return;
+                    }
+                };
+                let piece = match composition.into_piece(backend).await {
+                    Ok(piece) => piece,
+                    Err(e) => {
+                        self.error = Some(format!("Can't play {}: {e}", record.title));
+                        return;
+                    }
+                };
+                music.push((piece, Arranger::new(composition.arrangement)));
+                sources.push(record.source.clone());
+                music.len() - 1
+            }
+        };
+
+        let (piece, level_arranger) = &mut music[index];
+        let mut arranger = Arranger::new(level_arranger.arrangement().clone());
+        arranger.handle(MusicEvent::Start, piece);
+        for _ in 0..record.unlocked {
+            arranger.handle(MusicEvent::Objective, piece);
+        }
+
+        self.levels = vec![0.0; piece.track_count()];
+        self.playing = Some((index, arranger));
+    }
+
+    /// Returns the index of the first record to list, so that the selected
+    /// record is shown among `rows` rows, scrolling as little as possible.
+    fn first_row(&self, rows: usize) -> usize {
+        self.scroll
+            .min(self.selected)
+            .max((self.selected + 1).saturating_sub(rows))
+    }
+
+    /// Silences the playing record, if there is one.
+    pub fn stop(&mut self, music: &mut [(Piece, Arranger)]) {
+        if let Some((index, _)) = self.playing.take() {
+            music[index].0.silence();
+        }
+    }
+
+    /// Draws the record list, the selected record's liner notes and
+    /// the visualiser, along with any error loading or saving `records`.
+    pub fn draw(
+        &self,
+        records: &RecordCollection,
+        records_error: Option<&RecordsError>,
+        music: &[(Piece, Arranger)],
+    ) {
+        let (width, height) = (screen_width(), screen_height());
+        let accent = as_macroquad_color(map::ACCENT_1);
+
+        draw_text("[jukebox]", 10., 20., 20., GRAY);
+        draw_text(
+            "[w s]: choose  [enter]: play  [j]: close",
+            10.,
+            40.,
+            20.,
+            GRAY,
+        );
+
+        // Show why records can't be kept or played, if they can't.
+        let errors = records_error
+            .map(|e| format!("Records won't be kept: {e}"))
+            .into_iter()
+            .chain(self.error.clone());
+        for (i, error) in errors.enumerate() {
+            draw_text(&error, 10., height - 40. - 20. * i as f32, 20., accent);
+        }
+
+        if records.records.is_empty() {
+            draw_text(
+                "No records collected yet. Clear a level to collect its record.",
+                10.,
+                80.,
+                20.,
+                WHITE,
+            );
+            return;
+        }
+
+        // List the records which fit, highlighting the selected one.
+        let rows = list_rows(height);
+        let first = self.first_row(rows);
+        let shown = records.records.iter().enumerate().skip(first).take(rows);
+        for (row, (i, record)) in shown.enumerate() {
+            let color = if i == self.selected { accent } else { WHITE };
+            let line = format!("Level {}: {}", record.level + 1, record.title);
+            let y = 80. + LIST_LINE_HEIGHT * row as f32;
+            draw_text(&line, 10., y, LIST_LINE_HEIGHT, color);
+        }
+
+        // Note how many records are scrolled out of the list.
+        let below = records.records.len().saturating_sub(first + rows);
+        let mut list_bottom = 80. + LIST_LINE_HEIGHT * rows.min(records.records.len()) as f32;
+        if first > 0 || below > 0 {
+            let line = format!("({first} above, {below} below)");
+            draw_text(&line, 10., list_bottom, 20., GRAY);
+            list_bottom += 20.;
+        }
+
+        // Liner notes for the selected record.
+        let record = &records.records[self.selected];
+        let notes_top = list_bottom + 20.;
+        let mut lines = record.notes.clone();
+        lines.push(format!("{} parts recovered.", record.unlocked));
+        for (i, line) in lines.iter().enumerate() {
+            draw_text(line, 10., notes_top + 20. * i as f32, 20., GRAY);
+        }
+
+        let Some((index, _)) = &self.playing else {
+            return;
+        };
+        let piece = &music[*index].0;
+
+        // Spin a record once per bar.
+        let center = (width * 0.75, height * 0.35);
+        let radius = width.min(height) * 0.2;
+        let angle = piece.bar_phase() * std::f32::consts::TAU;
+        draw_circle(center.0, center.1, radius, MqColor::new(0.1, 0.1, 0.1, 1.0));
+        draw_circle_lines(center.0, center.1, radius * 0.6, 1.0, GRAY);
+        draw_circle(center.0, center.1, radius * 0.25, accent);
+        draw_line(
+            center.0,
+            center.1,
+            center.0 + angle.cos() * radius,
+            center.1 + angle.sin() * radius,
+            2.0,
+            WHITE,
+        );
+
+        // Draw a bar for each track, rising as it plays.
+        let bar_width = width * 0.5 / self.levels.len().max(1) as f32;
+        let bar_height = height * 0.3;
+        let bottom = height - 40.;
+        for (track, level) in self.levels.iter().enumerate() {
+            let x = width * 0.45 + bar_width * track as f32;
+            let h = bar_height * level;
+            draw_rectangle(x, bottom - h, bar_width * 0.8, h, accent);
+
+            let name = piece.track_name(track).unwrap_or_default();
+            draw_text(name, x, bottom + 16., 16., GRAY);
+        }
+    }
+}
+
+/// Returns how many records fit in the list on a screen `height` pixels tall.
+fn list_rows(height: f32) -> usize {
+    ((height - 80. - NOTES_HEIGHT) / LIST_LINE_HEIGHT).max(1.) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a record collected from `level`, with all `unlocked` parts.
+    fn record(level: usize, unlocked: usize) -> Record {
+        Record {
+            level,
+            title: format!("Piece {level}"),
+            notes: vec!["Found in the fog.".to_string()],
+            source: "tempo 120\nsynth tone\ntrack tone x...".to_string(),
+            unlocked,
+        }
+    }
+
+    #[test]
+    fn records_are_kept_in_level_order_once_each() {
+        let mut records = RecordCollection::default();
+        records.collect(record(2, 1));
+        records.collect(record(0, 1));
+        records.collect(record(2, 3));
+
+        let collected: Vec<_> = records
+            .records
+            .iter()
+            .map(|r| (r.level, r.unlocked))
+            .collect();
+        assert_eq!(collected, [(0, 1), (2, 3)]);
+    }
+
+    #[test]
+    fn records_survive_ron_with_their_source() {
+        let mut records = RecordCollection::default();
+        records.collect(record(1, 2));
+
+        let decoded = RecordCollection::from_ron(&records.to_ron().unwrap()).unwrap();
+        assert_eq!(decoded, records);
+        assert!(Composition::parse(&decoded.records[0].source).is_ok());
+    }
+
+    #[test]
+    fn list_scrolls_to_keep_the_selection_shown() {
+        let mut jukebox = Jukebox::new();
+        let scroll_to = |jukebox: &mut Jukebox, selected| {
+            jukebox.selected = selected;
+            jukebox.scroll = jukebox.first_row(4);
+            jukebox.scroll
+        };
+
+        // Moving within the rows shown doesn't scroll.
+        assert_eq!(scroll_to(&mut jukebox, 3), 0);
+        assert_eq!(scroll_to(&mut jukebox, 5), 2);
+        assert_eq!(scroll_to(&mut jukebox, 3), 2);
+        assert_eq!(scroll_to(&mut jukebox, 1), 1);
+
+        // Wrapping around from the last record scrolls back to the top.
+        assert_eq!(scroll_to(&mut jukebox, 9), 6);
+        assert_eq!(scroll_to(&mut jukebox, 0), 0);
+    }
+
+    #[test]
+    fn list_shows_at_least_one_record() {
+        assert_eq!(list_rows(0.), 1);
+        assert_eq!(list_rows(80. + NOTES_HEIGHT + LIST_LINE_HEIGHT * 5.5), 5);
+    }
+}
@@ -1,13 +1,14 @@
 //! Level loading utilities for the game.
 
-use glam::Vec2;
-use image::{DynamicImage, Rgba};
+use std::collections::{BTreeMap, BTreeSet};
 
-use crate::engine::tile::{Color, ColorMapper, Tile, TileLoadResult, TileTexture};
+use glam::Vec2;
+use image::{DynamicImage, imageops::FilterType};
+use palette::WithAlpha;
 
-// Map size in grid units.
-pub const WIDTH: usize = 128;
-pub const HEIGHT: usize = 128;
+use crate::engine::tile::{
+    Color, Palette, PaletteColorMapper, PaletteIssue, TileState, TileTemplate, TileTexture,
+};
 
 // Map draw layers.
 pub const FOREGROUND_LAYER: i8 = 0;
@@ -31,71 +32,228 @@ pub const FOG_OF_WAR: Color = Color::new(0, 0, 0, 156);
 /// Background.
 pub const BACKGROUND: Color = Color::new(19, 21, 16, 255);
 
+/// A hand-made level image, and the scale it's played at.
+pub struct LevelAsset {
+    /// PNG-encoded level image, as authored.
+    pub bytes: &'static [u8],
+
+    /// Number of tiles along each side of a pixel in the image.
+    pub tiles_per_pixel: u32,
+}
+
 /// Tile map images.
-pub const TILEMAPS: &[&[u8]] = &[
-    include_bytes!("../../assets/map-0.png"),
-    include_bytes!("../../assets/map-1.png"),
-    include_bytes!("../../assets/map-2.png"),
-    include_bytes!("../../assets/map-3.png"),
-    include_bytes!("../../assets/map-4.png"),
+pub const TILEMAPS: &[LevelAsset] = &[
+    LevelAsset {
+        bytes: include_bytes!("../../assets/map-0.png"),
+        tiles_per_pixel: 8,
+    },
+    LevelAsset {
+        bytes: include_bytes!("../../assets/map-1.png"),
+        tiles_per_pixel: 8,
+    },
+    LevelAsset {
+        bytes: include_bytes!("../../assets/map-2.png"),
+        tiles_per_pixel: 8,
+    },
+    LevelAsset {
+        bytes: include_bytes!("../../assets/map-3.png"),
+        tiles_per_pixel: 8,
+    },
+    LevelAsset {
+        bytes: include_bytes!("../../assets/map-4.png"),
+        tiles_per_pixel: 8,
+    },
 ];
 
+/// Palette mapping tile map image colors to tile templates.
+///
+/// Native builds load the palette from [`PALETTE_PATH`] instead, when
+/// it can be read, so it can be tweaked without rebuilding the game.
+pub const PALETTE: &str = include_str!("../../assets/palette.txt");
+
+/// Path the level palette is loaded from on native builds.
+#[cfg(not(target_arch = "wasm32"))]
+pub const PALETTE_PATH: &str = "assets/palette.txt";
+
 // Tile assets
 pub const TILE_BACKGROUND: &[u8] = include_bytes!("../../assets/tile-background.png");
 pub const TILE_FLOOR: &[u8] = include_bytes!("../../assets/tile-floor.png");
 pub const TILE_WALL: &[u8] = include_bytes!("../../assets/tile-wall.png");
 
+/// A level image at the resolution it was authored at.
+#[derive(Clone)]
+pub struct Level {
+    /// Level image, in grid orientation.
+    pub image: DynamicImage,
+
+    /// Number of tiles along each side of a pixel in [`Self::image`].
+    pub tiles_per_pixel: u32,
+}
+
+impl Level {
+    /// Decodes a hand-made level from [`TILEMAPS`],
+    /// rotating its image into grid orientation.
+    pub fn decode(asset: &LevelAsset) -> Self {
+        Self {
+            image: image::load_from_memory(asset.bytes).unwrap().rotate270(),
+            tiles_per_pixel: asset.tiles_per_pixel,
+        }
+    }
+
+    /// Returns the level's image scaled up to one pixel per tile.
+    pub fn tiles(&self) -> DynamicImage {
+        scale_level(&self.image, self.tiles_per_pixel)
+    }
+
+    /// Encodes the level image as a PNG, in the same
+    /// orientation and resolution as the level assets.
+    pub fn to_png(&self) -> Vec<u8> {
+        // Levels are rotated into grid orientation on load,
+        // so rotate them back on export.
+        let mut bytes = std::io::Cursor::new(vec![]);
+        self.image
+            .rotate90()
+            .write_to(&mut bytes, image::ImageFormat::Png)
+            .unwrap();
+        bytes.into_inner()
+    }
+}
+
+/// Scales each of a level image's pixels up to `tiles_per_pixel`
+/// tiles on each side, preserving the level's aspect ratio.
+pub fn scale_level(image: &DynamicImage, tiles_per_pixel: u32) -> DynamicImage {
+    image.resize_exact(
+        image.width() * tiles_per_pixel,
+        image.height() * tiles_per_pixel,
+        FilterType::Nearest,
+    )
+}
+
 /// Game map state.
 pub struct GameMap {
     pub wall_texture: TileTexture,
     pub floor_texture: TileTexture,
+    pub palette: Palette,
     pub map: crate::engine::tile::TileMap,
     pub objectives_remaining: usize,
+
+    /// Pixels which didn't cleanly match the palette
+    /// during the most recent map load.
+    pub palette_issues: Vec<PaletteIssue>,
 }
 
 impl GameMap {
     /// Create a new, empty game map.
     pub fn new(wall_texture: TileTexture, floor_texture: TileTexture) -> Self {
-        let mut map = crate::engine::tile::TileMap::new(WIDTH, HEIGHT, BACKGROUND, DEFAULT);
+        // The map is sized by each level as it's loaded.
+        let mut map = crate::engine::tile::TileMap::new(1, 1, BACKGROUND, DEFAULT);
         map.draw_debug_info = false;
         map.viewport_scale = 6.0;
 
-        Self {
+        let game_map = Self {
             wall_texture,
             floor_texture,
+            palette: Palette::parse(PALETTE).unwrap(),
             map,
             objectives_remaining: 0,
+            palette_issues: vec![],
+        };
+
+        #[cfg(not(target_arch = "wasm32"))]
+        let game_map = game_map.with_palette_file();
+
+        game_map
+    }
+
+    /// Replaces the embedded [`PALETTE`] with the one at [`PALETTE_PATH`],
+    /// keeping the embedded palette if the file can't be read or used.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn with_palette_file(mut self) -> Self {
+        let Ok(source) = std::fs::read_to_string(PALETTE_PATH) else {
+            return self;
+        };
+
+        let palette = Palette::parse(&source)
+            .and_then(|palette| PaletteColorMapper::new(palette, self.tile_templates()))
+            .map(|mapper| mapper.palette().clone());
+        match palette {
+            Ok(palette) => self.palette = palette,
+            Err(e) => eprintln!("Failed to read palette from {PALETTE_PATH}: {e}"),
         }
+        self
     }
 
-    /// Load the game map from the specified tilemap index.
+    /// Returns the tile textures used by game maps, by name.
     ///
-    /// Returns the player spawn position.
-    pub fn load_map(&mut self, bitmap: &DynamicImage) -> Vec2 {
+    /// These names identify textures in serialized maps.
+    pub fn textures(&self) -> BTreeMap<String, TileTexture> {
+        BTreeMap::from([
+            ("wall".to_string(), self.wall_texture.clone()),
+            ("floor".to_string(), self.floor_texture.clone()),
+        ])
+    }
+
+    /// Returns the tile templates referenced by [`PALETTE`].
+    pub fn tile_templates(&self) -> BTreeMap<String, TileTemplate> {
+        let floor = TileTemplate::filled(self.floor_texture.clone());
+        let mut floor_color = DEFAULT;
+        floor_color.alpha = (0.75 * 255.) as u8;
+
+        BTreeMap::from([
+            (
+                "wall".to_string(),
+                TileTemplate::filled(self.wall_texture.clone()),
+            ),
+            (
+                "floor".to_string(),
+                floor.clone().with_blend_color(floor_color),
+            ),
+            (
+                "objective".to_string(),
+                floor.clone().with_blend_color(ACCENT_1),
+            ),
+            (
+                "cleared".to_string(),
+                floor.clone().with_blend_color(ACCENT_2),
+            ),
+            ("spawn".to_string(), floor.with_blend_color(ACCENT_3)),
+        ])
+    }
+
+    /// Returns a color mapper for loading tiles from map images.
+    pub fn color_mapper(&self) -> PaletteColorMapper {
+        PaletteColorMapper::new(self.palette.clone(), self.tile_templates()).unwrap()
+    }
+
+    /// Load the game map from a level, with one tile for
+    /// each of the level's pixels once it's scaled up.
+    ///
+    /// Returns the player spawn position, if the map has one.
+    pub fn load_map(&mut self, level: &Level) -> Option<Vec2> {
+        let bitmap = &level.tiles();
+
         // FIXME: This is a bit hacky, but it works for now.
         // We recreate the tile map from scratch to clear out any old state.
-        self.map = crate::engine::tile::TileMap::new(WIDTH, HEIGHT, BACKGROUND, DEFAULT);
+        self.map = crate::engine::tile::TileMap::new(
+            bitmap.width() as usize,
+            bitmap.height() as usize,
+            BACKGROUND,
+            DEFAULT,
+        );
         self.map.draw_debug_info = false;
         self.map.viewport_scale = 6.0;
 
+        let mut color_mapper = self.color_mapper();
         let spawn_point = self
             .map
-            .load_from_bitmap(
-                bitmap,
-                FOREGROUND_LAYER,
-                LayeredColorMapper {
-                    wall_texture: self.wall_texture.clone(),
-                    floor_texture: self.floor_texture.clone(),
-                    floor_opacity: 0.75,
-                },
-            )
-            .unwrap();
+            .load_from_bitmap(bitmap, FOREGROUND_LAYER, &mut color_mapper);
+        self.palette_issues = color_mapper.issues;
 
         // Set all tiles' heights to be very low so that they rise up on game load.
         // Also count the total number of objective (ACCENT_1) tiles that are present.
         self.objectives_remaining = 0;
-        for x in 0..WIDTH {
-            for y in 0..HEIGHT {
+        for x in 0..self.map.width() {
+            for y in 0..self.map.height() {
                 if let Some(tile_state) = self.map.get_tile_state(x, y, FOREGROUND_LAYER) {
                     tile_state.height_offset = -100.0;
                     tile_state.target_height_offset = 0.0;
@@ -107,7 +265,111 @@ impl GameMap {
             }
         }
 
-        spawn_point.into()
+        spawn_point.map(Vec2::from)
+    }
+
+    /// Checks that the currently loaded map, spawning
+    /// the player at `spawn_point`, is playable.
+    ///
+    /// Returns all issues found with the map.
+    pub fn validate(&self, spawn_point: Option<Vec2>) -> Vec<LevelIssue> {
+        let mut issues = vec![];
+
+        // Players can walk on any tile that isn't a wall.
+        let walkable = |tile_state: &TileState| {
+            tile_state.texture.is_some() && tile_state.texture.as_ref() != Some(&self.wall_texture)
+        };
+
+        let objective_clusters = self.objective_clusters();
+        if objective_clusters.is_empty() {
+            issues.push(LevelIssue::NoObjectives);
+        }
+
+        let Some(spawn_point) = spawn_point else {
+            issues.push(LevelIssue::MissingSpawn);
+            return issues;
+        };
+
+        // Find everywhere the player can walk to from the spawn point.
+        let reachable = self.map.connected_tiles(
+            spawn_point.x as usize,
+            spawn_point.y as usize,
+            FOREGROUND_LAYER,
+            walkable,
+        );
+
+        for cluster in objective_clusters {
+            if cluster.is_disjoint(&reachable) {
+                issues.push(LevelIssue::UnreachableObjective {
+                    tiles: cluster.into_iter().collect(),
+                });
+            }
+        }
+
+        for region in self.map.connected_regions(FOREGROUND_LAYER, walkable) {
+            if region.is_disjoint(&reachable) {
+                issues.push(LevelIssue::DisconnectedRegion {
+                    tiles: region.into_iter().collect(),
+                });
+            }
+        }
+
+        issues
+    }
+
+    /// Returns every cluster of unreached objective
+    /// tiles, as they'd be cleared by a flood fill.
+    pub fn objective_clusters(&self) -> Vec<BTreeSet<(usize, usize)>> {
+        self.map.connected_regions(FOREGROUND_LAYER, |tile_state| {
+            tile_state.original_blend_color.without_alpha() == ACCENT_1.without_alpha()
+        })
+    }
+
+    /// Returns the grid position of each cluster of unreached
+    /// objective tiles: the cluster's tile nearest its middle.
+    pub fn objective_positions(&self) -> Vec<Vec2> {
+        self.objective_clusters()
+            .iter()
+            .map(|cluster| {
+                let tiles = cluster.iter().map(|&(x, y)| Vec2::new(x as f32, y as f32));
+                let middle = tiles.clone().sum::<Vec2>() / cluster.len() as f32;
+                tiles
+                    .min_by(|a, b| a.distance(middle).total_cmp(&b.distance(middle)))
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    /// Reveals every tile at its original color, ignoring fog of war.
+    pub fn reveal(&mut self) {
+        for x in 0..self.map.width() {
+            for y in 0..self.map.height() {
+                if let Some(tile_state) = self.map.get_tile_state(x, y, FOREGROUND_LAYER) {
+                    tile_state.target_blend_color = tile_state.original_blend_color;
+                    tile_state.target_height_offset = 0.0;
+                }
+            }
+        }
+    }
+
+    /// Centers the map viewport on the grid point `position`.
+    pub fn center_viewport_on(&mut self, position: Vec2) {
+        let view_position = self
+            .map
+            .grid_to_view(position.x, position.y, FOREGROUND_LAYER);
+        // Subtract viewport offset from the view position, since the view position
+        // includes the viewport offset.
+        let view_position = view_position - self.map.viewport_offset;
+        self.map.viewport_offset.x = -view_position.x;
+        self.map.viewport_offset.y = -view_position.y;
+        // Shift the viewport offset to be centered on the position.
+        let view_size = self.map.calculate_view_size();
+        self.map.viewport_offset.x += view_size.x / 2.0;
+        self.map.viewport_offset.y += view_size.y / 2.0;
+        // Shift the viewport offset to adjust for the sprite width.
+        let tile_size = self.map.calculate_tile_size();
+        self.map.viewport_offset.x -= tile_size.x / 2.0;
+        self.map.viewport_offset.y -= tile_size.y / 2.0;
     }
 
     pub fn update(&mut self, delta_time: f32) {
@@ -115,60 +377,180 @@ impl GameMap {
     }
 }
 
-/// Game-specific color mapper for loading levels from bitmaps.
-///
-/// This defines the color semantics for the Layered game:
-/// - BACKGROUND color = walls
-/// - ACCENT_1 = objectives (orange)
-/// - ACCENT_2 = dangers/threats (teal)
-/// - ACCENT_3 = avatar spawn point (pink)
-/// - Any other color = floor
-pub struct LayeredColorMapper {
-    pub wall_texture: TileTexture,
-    pub floor_texture: TileTexture,
-    pub floor_opacity: f32,
+/// An issue found while validating a level with [`GameMap::validate`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum LevelIssue {
+    /// The level has no player spawn point.
+    MissingSpawn,
+
+    /// The level has no objectives, and can't be completed.
+    NoObjectives,
+
+    /// A cluster of objective tiles can't be walked to from the spawn point.
+    UnreachableObjective { tiles: Vec<(usize, usize)> },
+
+    /// A region of walkable tiles can't be walked to from the spawn point.
+    DisconnectedRegion { tiles: Vec<(usize, usize)> },
 }
 
-impl ColorMapper for LayeredColorMapper {
-    fn map_pixel(&mut self, x: u32, y: u32, color: Rgba<u8>) -> TileLoadResult {
-        let wall_color: [u8; 4] = BACKGROUND.into();
-        let objective_color: [u8; 4] = ACCENT_1.into();
-        let danger_color: [u8; 4] = ACCENT_2.into();
-        let avatar_color: [u8; 4] = ACCENT_3.into();
-
-        // Check if this is a wall tile
-        if color.0 == wall_color {
-            return TileLoadResult::Tile(Tile::Filled {
-                texture: self.wall_texture.clone(),
-                height_offset: None,
-                blend_color: None,
-            });
-        }
+impl LevelIssue {
+    /// Returns true if the issue makes the level unplayable.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, LevelIssue::DisconnectedRegion { .. })
+    }
+}
 
-        // Determine the blend color for floor tiles
-        let blend_color = if color.0 == objective_color {
-            Some(ACCENT_1)
-        } else if color.0 == danger_color {
-            Some(ACCENT_2)
-        } else if color.0 == avatar_color {
-            Some(ACCENT_3)
-        } else {
-            let mut color = DEFAULT;
-            color.alpha = (self.floor_opacity * 255.) as u8;
-            Some(color)
+impl std::fmt::Display for LevelIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Summarizes a region of tiles by its size and bounds.
+        let region = |tiles: &[(usize, usize)]| {
+            let min_x = tiles.iter().map(|t| t.0).min().unwrap_or_default();
+            let min_y = tiles.iter().map(|t| t.1).min().unwrap_or_default();
+            let max_x = tiles.iter().map(|t| t.0).max().unwrap_or_default();
+            let max_y = tiles.iter().map(|t| t.1).max().unwrap_or_default();
+            format!(
+                "{} tiles from [{min_x}, {min_y}] to [{max_x}, {max_y}]",
+                tiles.len()
+            )
         };
 
-        let tile = Tile::Filled {
-            texture: self.floor_texture.clone(),
-            height_offset: None,
-            blend_color,
-        };
+        match self {
+            LevelIssue::MissingSpawn => write!(f, "no spawn point"),
+            LevelIssue::NoObjectives => write!(f, "no objectives"),
+            LevelIssue::UnreachableObjective { tiles } => {
+                write!(f, "unreachable objective: {}", region(tiles))
+            }
+            LevelIssue::DisconnectedRegion { tiles } => {
+                write!(f, "disconnected region: {}", region(tiles))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
 
-        // Check if this is an avatar spawn point
-        if color.0 == avatar_color {
-            TileLoadResult::TileWithSpawn(tile, x as f32, y as f32)
-        } else {
-            TileLoadResult::Tile(tile)
+    /// Returns a level with one tile per character in `rows`:
+    /// `#` for walls, `.` for floor, `o` for objectives
+    /// and `s` for the spawn point.
+    fn level(rows: &[&str]) -> Level {
+        let mut image = RgbaImage::new(rows[0].len() as u32, rows.len() as u32);
+        for (y, row) in rows.iter().enumerate() {
+            for (x, tile) in row.chars().enumerate() {
+                let color = match tile {
+                    '#' => BACKGROUND,
+                    '.' => Color::new(0, 0, 0, 0),
+                    'o' => ACCENT_1,
+                    's' => ACCENT_3,
+                    _ => panic!("unknown tile `{tile}`"),
+                };
+                let (r, g, b, a) = color.into_components();
+                image.put_pixel(x as u32, y as u32, Rgba([r, g, b, a]));
+            }
+        }
+
+        Level {
+            image: image.into(),
+            tiles_per_pixel: 1,
         }
     }
+
+    /// Loads `rows` into a new map and returns the issues found with it.
+    fn validate(rows: &[&str]) -> Vec<LevelIssue> {
+        let mut map = GameMap::new(TileTexture::placeholder(1), TileTexture::placeholder(2));
+        let spawn_point = map.load_map(&level(rows));
+        map.validate(spawn_point)
+    }
+
+    #[test]
+    fn playable_level_has_no_issues() {
+        let issues = validate(&[
+            "#####", //
+            "#s.o#", //
+            "#.#o#", //
+            "#####", //
+        ]);
+
+        assert_eq!(issues, vec![]);
+    }
+
+    #[test]
+    fn reports_missing_spawn() {
+        let issues = validate(&[
+            "####", //
+            "#.o#", //
+            "####", //
+        ]);
+
+        assert_eq!(issues, vec![LevelIssue::MissingSpawn]);
+        assert!(issues[0].is_fatal());
+    }
+
+    #[test]
+    fn reports_missing_objectives() {
+        let issues = validate(&[
+            "####", //
+            "#s.#", //
+            "####", //
+        ]);
+
+        assert_eq!(issues, vec![LevelIssue::NoObjectives]);
+        assert!(issues[0].is_fatal());
+    }
+
+    #[test]
+    fn reports_objectives_behind_walls() {
+        let issues = validate(&[
+            "######", //
+            "#s.#o#", //
+            "######", //
+        ]);
+
+        assert_eq!(
+            issues,
+            vec![
+                LevelIssue::UnreachableObjective {
+                    tiles: vec![(4, 1)],
+                },
+                LevelIssue::DisconnectedRegion {
+                    tiles: vec![(4, 1)],
+                },
+            ]
+        );
+        assert!(issues[0].is_fatal());
+    }
+
+    #[test]
+    fn disconnected_floor_is_not_fatal() {
+        let issues = validate(&[
+            "#######", //
+            "#so#..#", //
+            "#######", //
+        ]);
+
+        assert_eq!(
+            issues,
+            vec![LevelIssue::DisconnectedRegion {
+                tiles: vec![(4, 1), (5, 1)],
+            }]
+        );
+        assert!(!issues[0].is_fatal());
+    }
+
+    #[test]
+    fn objectives_touching_diagonally_are_separate_clusters() {
+        let mut map = GameMap::new(TileTexture::placeholder(1), TileTexture::placeholder(2));
+        map.load_map(&level(&[
+            "#####", //
+            "#o.s#", //
+            "#.o.#", //
+            "#####", //
+        ]));
+
+        assert_eq!(map.objective_clusters().len(), 2);
+        assert_eq!(map.objectives_remaining, 2);
+    }
 }
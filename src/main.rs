@@ -2,6 +2,19 @@ pub mod engine;
 pub mod game;
 
 fn main() {
+    // Render a level's music instead of playing, if asked to.
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(result) = game::audio::mixdown::run(std::env::args()) {
+        match result {
+            Ok(path) => eprintln!("Rendered mixdown to {path}"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     macroquad::Window::from_config(
         macroquad::prelude::Conf {
             window_title: "LDJam58".to_string(),